@@ -0,0 +1,462 @@
+// SPDX-License-Identifier: BSD-3-Clause
+
+use core::
+{
+	cell::UnsafeCell, marker::PhantomData, mem::MaybeUninit, ops::{Deref, DerefMut}, ptr::{self, NonNull},
+	sync::atomic::{AtomicBool, AtomicUsize, Ordering, fence},
+};
+
+pub use crate::ref_counted::{BorrowError, BorrowMutError};
+
+// Shared borrows count up from UNUSED, and a mutable borrow is marked by WRITING
+const UNUSED: usize = 0;
+const WRITING: usize = usize::MAX;
+
+/// This represents a pool capable of holding N T's that can be shared between executors running at different
+/// interrupt priorities. Unlike RcPool this can live in a plain static
+pub struct ArcPool<T: Sized, const N: usize>
+{
+	pool: [ArcInner<T>; N],
+}
+
+// Slots are only ever claimed through their atomic in-use flag, so the pool can be shared as long as what it
+// holds can be
+unsafe impl<T: Send + Sync, const N: usize> Sync for ArcPool<T, N> {}
+
+impl<T, const N: usize> ArcPool<T, N>
+{
+	/// Create a new Arc pool
+	pub const fn new() -> Self
+	{
+		Self
+		{
+			pool: [const { ArcInner::new() }; N],
+		}
+	}
+}
+
+impl<T, const N: usize> Default for ArcPool<T, N>
+{
+	fn default() -> Self
+	{
+		Self::new()
+	}
+}
+
+impl<T: Sized, const N: usize> ArcPool<T, N>
+{
+	/// Allocate a free slot from the pool for value. Slots are handed back when the last Arc to them is
+	/// dropped, so this can be called from any priority level at any time
+	pub fn alloc(&'static self, value: T) -> Option<Arc<T>>
+	{
+		let inner = self.pool.iter().find(|slot| slot.claim())?;
+		// Nothing else can see this slot until we hand out the Arc, so it's ours to fill in
+		unsafe { (*inner.value.get()).write(value) };
+		inner.borrowState.store(UNUSED, Ordering::Relaxed);
+		inner.refCount.store(1, Ordering::Release);
+		Some(Arc { ptr: NonNull::from(inner), marker: PhantomData })
+	}
+}
+
+/// Container for the control block and data of a given atomically reference counted type
+struct ArcInner<T: Sized>
+{
+	inUse: AtomicBool,
+	refCount: AtomicUsize,
+	borrowState: AtomicUsize,
+	value: UnsafeCell<MaybeUninit<T>>,
+}
+
+impl<T> ArcInner<T>
+{
+	const fn new() -> Self
+	{
+		Self
+		{
+			inUse: AtomicBool::new(false),
+			refCount: AtomicUsize::new(0),
+			borrowState: AtomicUsize::new(UNUSED),
+			value: UnsafeCell::new(MaybeUninit::uninit()),
+		}
+	}
+
+	/// Try to take this slot for a new allocation
+	fn claim(&self) -> bool
+	{
+		self.inUse
+			.compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+			.is_ok()
+	}
+}
+
+/// An atomically reference counted pointer to some data, allocated from a pool. Access to the data is borrowed
+/// like a RefCell's, but the borrows never block: whoever finds the data already borrowed incompatibly gets an
+/// error, as waiting on code that an interrupt executor preempted would never end
+pub struct Arc<T: Sized>
+{
+	ptr: NonNull<ArcInner<T>>,
+	marker: PhantomData<ArcInner<T>>,
+}
+
+unsafe impl<T: Send + Sync> Send for Arc<T> {}
+unsafe impl<T: Send + Sync> Sync for Arc<T> {}
+
+impl<T: Sized> Arc<T>
+{
+	#[inline(always)]
+	fn inner(&self) -> &ArcInner<T>
+	{
+		unsafe { self.ptr.as_ref() }
+	}
+
+	/// How many Arc's currently point at this data
+	pub fn count(this: &Self) -> usize
+	{
+		this.inner().refCount.load(Ordering::Relaxed)
+	}
+
+	#[inline]
+	pub fn borrow(&self) -> Ref<'_, T>
+	{
+		match self.tryBorrow()
+		{
+			Ok(sharedRef) => sharedRef,
+			Err(_) => panic!("Arc already mutably borrowed"),
+		}
+	}
+
+	pub fn tryBorrow(&self) -> Result<Ref<'_, T>, BorrowError>
+	{
+		match BorrowRef::new(&self.inner().borrowState)
+		{
+			Some(borrow) =>
+			{
+				let value = unsafe { NonNull::new_unchecked((*self.inner().value.get()).as_mut_ptr()) };
+				Ok(Ref { value, _borrow: borrow, marker: PhantomData })
+			},
+			None => Err(BorrowError),
+		}
+	}
+
+	#[inline]
+	pub fn borrowMut(&self) -> RefMut<'_, T>
+	{
+		match self.tryBorrowMut()
+		{
+			Ok(mutRef) => mutRef,
+			Err(_) => panic!("Arc already borrowed"),
+		}
+	}
+
+	pub fn tryBorrowMut(&self) -> Result<RefMut<'_, T>, BorrowMutError>
+	{
+		match BorrowRefMut::new(&self.inner().borrowState)
+		{
+			Some(borrow) =>
+			{
+				let value = unsafe { NonNull::new_unchecked((*self.inner().value.get()).as_mut_ptr()) };
+				Ok(RefMut { value, _borrow: borrow, marker: PhantomData })
+			},
+			None => Err(BorrowMutError),
+		}
+	}
+}
+
+impl<T: Sized> Clone for Arc<T>
+{
+	#[inline]
+	fn clone(&self) -> Self
+	{
+		// Making a new reference from an existing one needs no ordering, only the final drop does
+		let count = self.inner().refCount.fetch_add(1, Ordering::Relaxed);
+		// If the count is anywhere near overflowing then something has leaked Arc's in a loop
+		if count > isize::MAX as usize
+		{
+			panic!("Reference count overflowed for Arc at {:?}", self.ptr);
+		}
+		Self { ptr: self.ptr, marker: PhantomData }
+	}
+}
+
+impl<T: Sized> Drop for Arc<T>
+{
+	#[inline]
+	fn drop(&mut self)
+	{
+		if self.inner().refCount.fetch_sub(1, Ordering::Release) != 1
+		{
+			return;
+		}
+		// We were the last reference, so make sure every other holder's uses of the value happened before we
+		// destroy it, then hand the slot back to the pool
+		fence(Ordering::Acquire);
+		unsafe
+		{
+			ptr::drop_in_place((*self.inner().value.get()).as_mut_ptr());
+		}
+		self.inner().inUse.store(false, Ordering::Release);
+	}
+}
+
+pub struct Ref<'b, T: ?Sized + 'b>
+{
+	value: NonNull<T>,
+	_borrow: BorrowRef<'b>,
+	marker: PhantomData<&'b T>,
+}
+
+impl<T: ?Sized> Deref for Ref<'_, T>
+{
+	type Target = T;
+
+	#[inline]
+	fn deref(&self) -> &T
+	{
+		unsafe { self.value.as_ref() }
+	}
+}
+
+pub struct RefMut<'b, T: ?Sized + 'b>
+{
+	value: NonNull<T>,
+	_borrow: BorrowRefMut<'b>,
+	marker: PhantomData<&'b T>,
+}
+
+impl<T: ?Sized> Deref for RefMut<'_, T>
+{
+	type Target = T;
+
+	#[inline]
+	fn deref(&self) -> &T
+	{
+		unsafe { self.value.as_ref() }
+	}
+}
+
+impl<T: ?Sized> DerefMut for RefMut<'_, T>
+{
+	fn deref_mut(&mut self) -> &mut T
+	{
+		unsafe { self.value.as_mut() }
+	}
+}
+
+struct BorrowRef<'b>
+{
+	borrow: &'b AtomicUsize,
+}
+
+impl<'b> BorrowRef<'b>
+{
+	#[inline]
+	fn new(borrow: &'b AtomicUsize) -> Option<Self>
+	{
+		// Another shared borrow can only join in while there's no mutable one, and there's room to count it
+		let mut count = borrow.load(Ordering::Relaxed);
+		loop
+		{
+			if count >= WRITING - 1
+			{
+				return None;
+			}
+			match borrow.compare_exchange_weak(count, count + 1, Ordering::Acquire, Ordering::Relaxed)
+			{
+				Ok(_) => return Some(BorrowRef { borrow }),
+				Err(current) => count = current,
+			}
+		}
+	}
+}
+
+impl Drop for BorrowRef<'_>
+{
+	#[inline]
+	fn drop(&mut self)
+	{
+		let borrow = self.borrow.fetch_sub(1, Ordering::Release);
+		debug_assert!(borrow > UNUSED && borrow != WRITING);
+	}
+}
+
+struct BorrowRefMut<'b>
+{
+	borrow: &'b AtomicUsize,
+}
+
+impl<'b> BorrowRefMut<'b>
+{
+	#[inline]
+	fn new(borrow: &'b AtomicUsize) -> Option<Self>
+	{
+		borrow
+			.compare_exchange(UNUSED, WRITING, Ordering::Acquire, Ordering::Relaxed)
+			.ok()
+			.map(|_| BorrowRefMut { borrow })
+	}
+}
+
+impl Drop for BorrowRefMut<'_>
+{
+	#[inline]
+	fn drop(&mut self)
+	{
+		let borrow = self.borrow.swap(UNUSED, Ordering::Release);
+		debug_assert!(borrow == WRITING);
+	}
+}
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+	use core::cell::Cell;
+
+	// Counts how many times values holding the counter have been dropped
+	struct Tracked(std::rc::Rc<Cell<usize>>);
+
+	impl Drop for Tracked
+	{
+		fn drop(&mut self)
+		{
+			self.0.set(self.0.get() + 1);
+		}
+	}
+
+	// Run a test against a pool that lives for 'static as far as the Arc's know, but gets cleaned up after so
+	// nothing shows up as leaked under Miri. The test must have dropped everything it took from the pool
+	fn withPool<T: 'static, const N: usize>(test: impl FnOnce(&'static ArcPool<T, N>))
+	{
+		let pool: *mut ArcPool<T, N> = Box::into_raw(Box::new(ArcPool::new()));
+		test(unsafe { &*pool });
+		drop(unsafe { Box::from_raw(pool) });
+	}
+
+	fn assertSendSync<T: Send + Sync>() {}
+
+	#[test]
+	fn sharedBetweenThreads()
+	{
+		assertSendSync::<Arc<u32>>();
+		assertSendSync::<ArcPool<u32, 1>>();
+
+		withPool::<u32, 1>(|pool|
+		{
+			let arc = pool.alloc(0).unwrap();
+			std::thread::scope(|scope|
+			{
+				for _ in 0..4
+				{
+					let arc = arc.clone();
+					scope.spawn(move ||
+					{
+						let mut increments = 0;
+						while increments < 1000
+						{
+							// Borrows never wait, so whoever finds the value busy tries again
+							if let Ok(mut value) = arc.tryBorrowMut()
+							{
+								*value += 1;
+								increments += 1;
+							}
+						}
+					});
+				}
+			});
+			assert_eq!(*arc.borrow(), 4000);
+			assert_eq!(Arc::count(&arc), 1);
+		});
+	}
+
+	#[test]
+	fn borrowsSeeValue()
+	{
+		withPool::<u32, 1>(|pool|
+		{
+			let arc = pool.alloc(5).unwrap();
+			assert_eq!(*arc.borrow(), 5);
+			*arc.borrowMut() += 1;
+			let clone = arc.clone();
+			assert_eq!(Arc::count(&arc), 2);
+			assert_eq!(*clone.borrow(), 6);
+		});
+	}
+
+	#[test]
+	fn borrowsExcludeMutableBorrows()
+	{
+		withPool::<u32, 1>(|pool|
+		{
+			let arc = pool.alloc(0).unwrap();
+			{
+				let first = arc.borrow();
+				let second = arc.tryBorrow();
+				assert!(second.is_ok());
+				assert!(matches!(arc.tryBorrowMut(), Err(BorrowMutError)));
+				drop(first);
+				assert!(matches!(arc.tryBorrowMut(), Err(BorrowMutError)));
+			}
+			{
+				let mutRef = arc.borrowMut();
+				assert!(matches!(arc.tryBorrow(), Err(BorrowError)));
+				assert!(matches!(arc.clone().tryBorrowMut(), Err(BorrowMutError)));
+				drop(mutRef);
+			}
+			assert!(arc.tryBorrow().is_ok());
+			assert!(arc.tryBorrowMut().is_ok());
+		});
+	}
+
+	#[test]
+	fn valueDropsWithLastReference()
+	{
+		let drops = std::rc::Rc::new(Cell::new(0));
+		withPool::<Tracked, 1>(|pool|
+		{
+			let arc = pool.alloc(Tracked(drops.clone())).unwrap();
+			let clone = arc.clone();
+			drop(arc);
+			assert_eq!(drops.get(), 0);
+			drop(clone);
+			assert_eq!(drops.get(), 1);
+		});
+	}
+
+	#[test]
+	fn poolExhausts()
+	{
+		withPool::<u32, 2>(|pool|
+		{
+			let first = pool.alloc(1).unwrap();
+			let second = pool.alloc(2).unwrap();
+			assert!(pool.alloc(3).is_none());
+			drop(first);
+			let third = pool.alloc(3).unwrap();
+			assert!(pool.alloc(4).is_none());
+			assert_eq!((*second.borrow(), *third.borrow()), (2, 3));
+		});
+	}
+
+	#[test]
+	fn freedSlotsAreReused()
+	{
+		withPool::<u32, 2>(|pool|
+		{
+			let first = pool.alloc(1).unwrap();
+			let second = pool.alloc(2).unwrap();
+			let (firstSlot, secondSlot) = (first.ptr, second.ptr);
+			assert!(firstSlot != secondSlot);
+
+			// The slot only goes back once every reference to it has gone
+			let clone = first.clone();
+			drop(first);
+			assert!(pool.alloc(3).is_none());
+			drop(clone);
+			let reused = pool.alloc(3).unwrap();
+			assert!(reused.ptr == firstSlot);
+			assert_eq!(Arc::count(&reused), 1);
+			assert!(reused.tryBorrowMut().is_ok());
+			assert_eq!((*reused.borrow(), *second.borrow()), (3, 2));
+		});
+	}
+}
@@ -0,0 +1,202 @@
+// SPDX-License-Identifier: BSD-3-Clause
+
+use core::cell::{Cell, RefCell};
+use defmt::error;
+use embassy_futures::join::join3;
+use embassy_futures::select::{Either3, select3};
+use embassy_stm32::mode::Async;
+use embassy_stm32::usart::{Uart, UartRx, UartTx};
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::channel::Receiver;
+use embassy_time::{Duration, Ticker};
+
+use crate::prbs::{PrbsChecker, PrbsGenerator, PrbsPattern, PrbsStatistics};
+use crate::serial::SerialEvent;
+use crate::types::{ReceiveRequest, SerialCommand, SerialEncoding};
+use crate::watchdog::{self, Watched};
+
+// Large receive chunks keep the gaps between DMA transfers, where characters can be dropped, rare
+const RECEIVE_CHUNK_SIZE: usize = 256;
+const TRANSMIT_CHUNK_SIZE: usize = 64;
+
+static BERT_STATISTICS: Mutex<CriticalSectionRawMutex, Cell<BertStatistics>> =
+	Mutex::new(Cell::new(BertStatistics::new()));
+
+#[derive(Clone, Copy)]
+pub struct BertStatistics
+{
+	running: bool,
+	pattern: u8,
+	locked: bool,
+	prbs: PrbsStatistics,
+	lineErrors: u32,
+	seconds: u32,
+	errorFreeSeconds: u32,
+}
+
+impl BertStatistics
+{
+	const fn new() -> Self
+	{
+		Self
+		{
+			running: false,
+			pattern: 0,
+			locked: false,
+			prbs: PrbsStatistics { bitsChecked: 0, bitErrors: 0, characterErrors: 0, lockLosses: 0 },
+			lineErrors: 0,
+			seconds: 0,
+			errorFreeSeconds: 0,
+		}
+	}
+
+	pub fn toData(&self, data: &mut [u8]) -> Option<usize>
+	{
+		// There need to be at least 44 bytes to format out the BERT statistics
+		if data.len() < 44
+		{
+			return None;
+		}
+
+		data[0] = self.running as u8;
+		data[1] = self.pattern;
+		data[2] = self.locked as u8;
+		data[3] = 0;
+		data[4..12].copy_from_slice(&self.prbs.bitsChecked.to_le_bytes());
+		data[12..20].copy_from_slice(&self.prbs.bitErrors.to_le_bytes());
+		data[20..28].copy_from_slice(&self.prbs.characterErrors.to_le_bytes());
+		data[28..32].copy_from_slice(&self.prbs.lockLosses.to_le_bytes());
+		data[32..36].copy_from_slice(&self.lineErrors.to_le_bytes());
+		data[36..40].copy_from_slice(&self.seconds.to_le_bytes());
+		data[40..44].copy_from_slice(&self.errorFreeSeconds.to_le_bytes());
+		Some(44)
+	}
+}
+
+/// Get the statistics for the current (or most recent) BERT run
+pub fn statistics() -> BertStatistics
+{
+	BERT_STATISTICS.lock(Cell::get)
+}
+
+/// Mark the BERT as no longer running, leaving the final statistics readable
+pub fn stopped()
+{
+	BERT_STATISTICS.lock(|statistics| statistics.set(BertStatistics { running: false, ..statistics.get() }));
+}
+
+/// Run the bit error rate tester until a request or command comes in that needs the serial task's attention.
+///
+/// The PRBS is transmitted continuously while the receiver synchronises to the same pattern, so the target
+/// side of the link needs to loop TX back to RX. Statistics restart from scratch each time this is entered,
+/// which includes after every line coding change.
+pub async fn run(
+	serialPort: &mut Uart<'static, Async>,
+	encoding: &SerialEncoding,
+	pattern: PrbsPattern,
+	receiveChannel: &Receiver<'static, CriticalSectionRawMutex, ReceiveRequest, 1>,
+	serialCommands: &Receiver<'static, CriticalSectionRawMutex, SerialCommand, 2>,
+) -> SerialEvent
+{
+	let bits = encoding.characterBits();
+	let checker = RefCell::new(PrbsChecker::new(pattern));
+	let lineErrors = Cell::new(0);
+	BERT_STATISTICS.lock(|statistics| statistics.set(BertStatistics
+	{
+		running: true,
+		pattern: pattern as u8,
+		..BertStatistics::new()
+	}));
+
+	let (transmitter, receiver) = serialPort.split_ref();
+	let bertFuture = join3
+	(
+		generate(transmitter, pattern, bits),
+		check(receiver, &checker, &lineErrors, bits),
+		account(&checker, &lineErrors, pattern),
+	);
+
+	match select3(bertFuture, receiveChannel.receive(), serialCommands.receive()).await
+	{
+		Either3::First(_) => unreachable!("BERT futures never complete"),
+		Either3::Second(request) => SerialEvent::Request(request),
+		Either3::Third(command) => SerialEvent::Command(command),
+	}
+}
+
+async fn generate(transmitter: &mut UartTx<'static, Async>, pattern: PrbsPattern, bits: u8)
+{
+	let mut generator = PrbsGenerator::new(pattern);
+	let mut buffer = [0u8; TRANSMIT_CHUNK_SIZE];
+
+	loop
+	{
+		watchdog::heartbeat(Watched::Serial);
+		generator.fill(&mut buffer, bits);
+		if let Err(error) = transmitter.write(&buffer).await
+		{
+			error!("BERT transmit failed, {}", error)
+		}
+	}
+}
+
+async fn check(
+	receiver: &mut UartRx<'static, Async>,
+	checker: &RefCell<PrbsChecker>,
+	lineErrors: &Cell<u32>,
+	bits: u8,
+)
+{
+	let mut buffer = [0u8; RECEIVE_CHUNK_SIZE];
+
+	loop
+	{
+		watchdog::heartbeat(Watched::Serial);
+		// Nothing comes back until the target side loops TX back to RX, which may never happen
+		match watchdog::excused(Watched::Serial, receiver.read_until_idle(&mut buffer)).await
+		{
+			Ok(byteCount) => checker.borrow_mut().check(&buffer[0..byteCount], bits),
+			// Framing, parity, noise and overrun errors can't be attributed to specific bits so count them separately
+			Err(_) => lineErrors.set(lineErrors.get() + 1),
+		}
+	}
+}
+
+async fn account(checker: &RefCell<PrbsChecker>, lineErrors: &Cell<u32>, pattern: PrbsPattern)
+{
+	let mut ticker = Ticker::every(Duration::from_secs(1));
+	let mut seconds = 0;
+	let mut errorFreeSeconds = 0;
+	let mut lastErrors = (0, 0, 0);
+
+	loop
+	{
+		ticker.next().await;
+		let (locked, prbs) =
+		{
+			let checker = checker.borrow();
+			(checker.locked(), checker.statistics())
+		};
+
+		// A second is only error-free if we were locked throughout and saw no errors of any kind
+		seconds += 1;
+		let errors = (prbs.bitErrors, prbs.lockLosses, lineErrors.get());
+		if locked && errors == lastErrors
+		{
+			errorFreeSeconds += 1;
+		}
+		lastErrors = errors;
+
+		BERT_STATISTICS.lock(|statistics| statistics.set(BertStatistics
+		{
+			running: true,
+			pattern: pattern as u8,
+			locked,
+			prbs,
+			lineErrors: errors.2,
+			seconds,
+			errorFreeSeconds,
+		}));
+	}
+}
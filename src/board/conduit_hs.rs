@@ -0,0 +1,182 @@
+// SPDX-License-Identifier: BSD-3-Clause
+
+// The high speed conduit board, built around an STM32U5A5ZJ using the OTG_HS core and its embedded PHY, with a
+// 16MHz crystal to clock the PHY and the target UART on USART2
+
+use assign_resources::assign_resources;
+use embassy_stm32::
+{
+    bind_interrupts, interrupt, pac, peripherals, usart, usb, Peri,
+};
+use embassy_stm32::gpio::Level;
+
+#[cfg(not(feature = "usb-hs"))]
+compile_error!("The conduit HS board's USB runs at high speed, so must be built with usb-hs");
+
+use crate::clocks::{ClockPlan, ClockSource, ClockTree, UsartClock, UsbClock};
+#[cfg(feature = "target-power")]
+use crate::power::PowerSense;
+
+assign_resources!
+{
+	usb: UsbResources
+	{
+		peripheral: USB_OTG_HS = UsbPeripheral,
+		dm: PA11,
+		dp: PA12,
+	}
+	uart: DmaUartResources
+	{
+		peripheral: USART2 = UartPeripheral,
+		tx: PA2,
+		rx: PA3,
+		tx_dma: GPDMA1_CH0,
+		rx_dma: GPDMA1_CH1,
+	}
+	sniffer: SnifferUartResources
+	{
+		peripheral: USART1 = SnifferUartPeripheral,
+		rx: PA10,
+		rx_dma: GPDMA1_CH2,
+	}
+	flash: FlashResources
+	{
+		peripheral: FLASH = FlashPeripheral,
+	}
+	watchdog: WatchdogResources
+	{
+		peripheral: IWDG = WatchdogPeripheral,
+	}
+	clocks: ClockResources
+	{
+		peripheral: RCC = ClockPeripheral,
+	}
+	control: ControlResources
+	{
+		reset: PB0,
+		boot: PB1,
+	}
+	leds: LedResources
+	{
+		status: PB14,
+		activity: PB15,
+	}
+	modem: ModemResources
+	{
+		dcd: PA8,
+		dcd_exti: EXTI8,
+		dsr: PB4,
+		dsr_exti: EXTI4,
+		ri: PB5,
+		ri_exti: EXTI5,
+	}
+	power: PowerResources
+	{
+		enable: PB2,
+		adc: ADC1,
+		voltage: PA0,
+		current: PA1,
+	}
+}
+
+bind_interrupts!
+(
+	pub struct UartIrqs
+	{
+    	USART2 => usart::InterruptHandler<peripherals::USART2>;
+    	USART1 => usart::InterruptHandler<peripherals::USART1>;
+	}
+);
+
+bind_interrupts!
+(
+	pub struct UsbIrqs
+	{
+    	OTG_HS => usb::InterruptHandler<peripherals::USB_OTG_HS>;
+	}
+);
+
+/// Bring up the USB driver on the OTG_HS core and its embedded high speed PHY
+pub fn usbDriver(usb: UsbResources, outBuffer: &'static mut [u8], config: usb::Config)
+	-> usb::Driver<'static, UsbPeripheral>
+{
+	usb::Driver::new_hs(usb.peripheral, UsbIrqs, usb.dp, usb.dm, outBuffer, config)
+}
+
+/// Name the target UART's data interface is given when the user hasn't assigned their own
+pub const DEFAULT_DATA_INTERFACE_NAME: &str = "Target UART2";
+/// Whether VBus is brought to the USB core, so it can see when the host goes away
+pub const USB_VBUS_DETECTION: bool = true;
+/// The target's reset line is active low
+pub const TARGET_RESET_ACTIVE: Level = Level::Low;
+/// The target's boot mode select line is active high
+pub const TARGET_BOOT_ACTIVE: Level = Level::High;
+/// The LEDs light up when driven high
+pub const LED_ACTIVE: Level = Level::High;
+/// The target's DCD, DSR and RI outputs are active low
+#[cfg(feature = "modem-inputs")]
+pub const MODEM_INPUT_ACTIVE: Level = Level::Low;
+/// The target's load switch turns on when its enable line is driven high
+#[cfg(feature = "target-power")]
+pub const LOAD_SWITCH_ACTIVE: Level = Level::High;
+/// How the target's supply voltage and current are brought out to the ADC
+#[cfg(feature = "target-power")]
+pub const TARGET_POWER_SENSE: PowerSense = PowerSense
+{
+	referenceMillivolts: 3300,
+	voltageDivider: 2,
+	shuntMilliohms: 100,
+	senseGain: 50,
+	overcurrentLimit: 500,
+};
+
+/// Registers of the target UART, for the things the HAL has no API for such as half-duplex loopback
+pub const TARGET_UART_REGISTERS: pac::usart::Usart = pac::USART2;
+
+/// The USB core's interrupt
+pub const USB_INTERRUPT: interrupt::Interrupt = interrupt::OTG_HS;
+/// The target and sniffer UARTs' interrupts and those of their DMA channels
+pub const UART_INTERRUPTS: [interrupt::Interrupt; 5] =
+[
+	interrupt::USART2, interrupt::USART1, interrupt::GPDMA1_CHANNEL0, interrupt::GPDMA1_CHANNEL1,
+	interrupt::GPDMA1_CHANNEL2,
+];
+/// The EXTI lines the modem control inputs come in on
+pub const MODEM_INPUT_INTERRUPTS: [interrupt::Interrupt; 3] = [interrupt::EXTI8, interrupt::EXTI4, interrupt::EXTI5];
+
+usbExecutorInterrupt!(UART5);
+
+// Run from the crystal at the part's full 160MHz, which also directly clocks the high speed PHY
+const FULL_SPEED_CLOCK_TREE: ClockTree = ClockTree
+{
+	source: ClockSource::Hse(16_000_000),
+	sysclk: 160_000_000,
+	usb: UsbClock::HighSpeedPhy,
+	usart: UsartClock::BestFor(&BAUD_RATES),
+};
+/// The clocks to use for anything that needs throughput
+pub const FULL_SPEED_CLOCKS: ClockPlan = FULL_SPEED_CLOCK_TREE.plan();
+
+// When idling, drop the PLL down to 48MHz. The high speed PHY holds the core in voltage range 2, but this still
+// saves a good chunk of power and keeps the AHB well above the 30MHz the OTG_HS core needs
+const LOW_POWER_CLOCK_TREE: ClockTree = ClockTree
+{
+	source: ClockSource::Hse(16_000_000),
+	sysclk: 48_000_000,
+	usb: UsbClock::HighSpeedPhy,
+	usart: UsartClock::BestFor(&LOW_POWER_BAUD_RATES),
+};
+/// The clocks to drop to when the link is idle and running slowly enough
+pub const LOW_POWER_CLOCKS: ClockPlan = LOW_POWER_CLOCK_TREE.plan();
+/// Fastest baud rate the low power clocks get used at
+pub const LOW_POWER_MAX_BAUD: u32 = 115200;
+
+// The baud rates targets on this board are expected to be run at, up to the multi-megabaud links the high
+// speed USB is there to keep up with
+const BAUD_RATES: [u32; 14] =
+[
+	9600, 19200, 38400, 57600, 115200, 230400, 460800, 921600, 1000000, 2000000, 3000000, 4000000, 5000000,
+	10000000,
+];
+// And the ones of those that are slow enough for the low power clocks
+const LOW_POWER_BAUD_RATES: [u32; 5] = [9600, 19200, 38400, 57600, LOW_POWER_MAX_BAUD];
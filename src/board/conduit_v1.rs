@@ -0,0 +1,180 @@
+// SPDX-License-Identifier: BSD-3-Clause
+
+// The original conduit board, built around an STM32U585CI with the target UART on USART2
+
+use assign_resources::assign_resources;
+use embassy_stm32::
+{
+    bind_interrupts, interrupt, pac, peripherals, usart, usb, Peri,
+};
+use embassy_stm32::gpio::Level;
+
+#[cfg(feature = "usb-hs")]
+compile_error!("The conduit v1 board's STM32U585 has no OTG_HS core, so can't be built with usb-hs");
+
+use crate::clocks::{ClockPlan, ClockSource, ClockTree, MsisRange, UsartClock, UsbClock};
+#[cfg(feature = "target-power")]
+use crate::power::PowerSense;
+
+assign_resources!
+{
+	usb: UsbResources
+	{
+		peripheral: USB_OTG_FS = UsbPeripheral,
+		dm: PA11,
+		dp: PA12,
+	}
+	uart: DmaUartResources
+	{
+		peripheral: USART2 = UartPeripheral,
+		tx: PA2,
+		rx: PA3,
+		tx_dma: GPDMA1_CH0,
+		rx_dma: GPDMA1_CH1,
+	}
+	sniffer: SnifferUartResources
+	{
+		peripheral: USART1 = SnifferUartPeripheral,
+		rx: PA10,
+		rx_dma: GPDMA1_CH2,
+	}
+	flash: FlashResources
+	{
+		peripheral: FLASH = FlashPeripheral,
+	}
+	watchdog: WatchdogResources
+	{
+		peripheral: IWDG = WatchdogPeripheral,
+	}
+	clocks: ClockResources
+	{
+		peripheral: RCC = ClockPeripheral,
+	}
+	control: ControlResources
+	{
+		reset: PB0,
+		boot: PB1,
+	}
+	leds: LedResources
+	{
+		status: PB14,
+		activity: PB15,
+	}
+	modem: ModemResources
+	{
+		dcd: PA8,
+		dcd_exti: EXTI8,
+		dsr: PB4,
+		dsr_exti: EXTI4,
+		ri: PB5,
+		ri_exti: EXTI5,
+	}
+	power: PowerResources
+	{
+		enable: PB2,
+		adc: ADC1,
+		voltage: PA0,
+		current: PA1,
+	}
+}
+
+bind_interrupts!
+(
+	pub struct UartIrqs
+	{
+    	USART2 => usart::InterruptHandler<peripherals::USART2>;
+    	USART1 => usart::InterruptHandler<peripherals::USART1>;
+	}
+);
+
+bind_interrupts!
+(
+	pub struct UsbIrqs
+	{
+    	OTG_FS => usb::InterruptHandler<peripherals::USB_OTG_FS>;
+	}
+);
+
+/// Bring up the USB driver on the OTG_FS core and its internal full speed PHY
+pub fn usbDriver(usb: UsbResources, outBuffer: &'static mut [u8], config: usb::Config)
+	-> usb::Driver<'static, UsbPeripheral>
+{
+	usb::Driver::new_fs(usb.peripheral, UsbIrqs, usb.dp, usb.dm, outBuffer, config)
+}
+
+/// Name the target UART's data interface is given when the user hasn't assigned their own
+pub const DEFAULT_DATA_INTERFACE_NAME: &str = "Target UART2";
+/// VBus is hooked up on this board, so the USB core can see when the host goes away
+pub const USB_VBUS_DETECTION: bool = true;
+/// The target's reset line is active low (open drain style, through a transistor to nRST)
+pub const TARGET_RESET_ACTIVE: Level = Level::Low;
+/// The target's boot mode select line is active high
+pub const TARGET_BOOT_ACTIVE: Level = Level::High;
+/// The LEDs are wired from the pins to ground, so light up when driven high
+pub const LED_ACTIVE: Level = Level::High;
+/// The target's DCD, DSR and RI outputs are active low, as on an RS-232 level shifter
+#[cfg(feature = "modem-inputs")]
+pub const MODEM_INPUT_ACTIVE: Level = Level::Low;
+/// The target's load switch turns on when its enable line is driven high
+#[cfg(feature = "target-power")]
+pub const LOAD_SWITCH_ACTIVE: Level = Level::High;
+/// The target's supply is brought out through a 2:1 divider, and its current through a 100mΩ shunt and a gain
+/// of 50 current sense amplifier, which reads up to 660mA against the 3.3V reference
+#[cfg(feature = "target-power")]
+pub const TARGET_POWER_SENSE: PowerSense = PowerSense
+{
+	referenceMillivolts: 3300,
+	voltageDivider: 2,
+	shuntMilliohms: 100,
+	senseGain: 50,
+	overcurrentLimit: 500,
+};
+
+/// Registers of the target UART, for the things the HAL has no API for such as half-duplex loopback
+pub const TARGET_UART_REGISTERS: pac::usart::Usart = pac::USART2;
+
+/// The USB core's interrupt
+pub const USB_INTERRUPT: interrupt::Interrupt = interrupt::OTG_FS;
+/// The target and sniffer UARTs' interrupts and those of their DMA channels
+pub const UART_INTERRUPTS: [interrupt::Interrupt; 5] =
+[
+	interrupt::USART2, interrupt::USART1, interrupt::GPDMA1_CHANNEL0, interrupt::GPDMA1_CHANNEL1,
+	interrupt::GPDMA1_CHANNEL2,
+];
+/// The EXTI lines the modem control inputs come in on
+pub const MODEM_INPUT_INTERRUPTS: [interrupt::Interrupt; 3] = [interrupt::EXTI8, interrupt::EXTI4, interrupt::EXTI5];
+
+usbExecutorInterrupt!(UART5);
+
+// Run from MSIS at the part's full 160MHz, with HSI48 trimmed by the CRS for USB as there's no crystal
+const FULL_SPEED_CLOCK_TREE: ClockTree = ClockTree
+{
+	source: ClockSource::Msis(MsisRange::Range48MHz),
+	sysclk: 160_000_000,
+	usb: UsbClock::Hsi48,
+	usart: UsartClock::BestFor(&BAUD_RATES),
+};
+/// The clocks to use for anything that needs throughput
+pub const FULL_SPEED_CLOCKS: ClockPlan = FULL_SPEED_CLOCK_TREE.plan();
+
+// When idling, run straight from MSIS without the PLL which lets the core drop to voltage range 3. This is
+// kept at or above 32MHz so the USB core's turnaround time, set up for the full speed AHB clock, stays valid
+const LOW_POWER_CLOCK_TREE: ClockTree = ClockTree
+{
+	source: ClockSource::Msis(MsisRange::Range48MHz),
+	sysclk: 48_000_000,
+	usb: UsbClock::Hsi48,
+	usart: UsartClock::BestFor(&LOW_POWER_BAUD_RATES),
+};
+/// The clocks to drop to when the link is idle and running slowly enough
+pub const LOW_POWER_CLOCKS: ClockPlan = LOW_POWER_CLOCK_TREE.plan();
+/// Fastest baud rate the low power clocks get used at
+pub const LOW_POWER_MAX_BAUD: u32 = 115200;
+
+// The baud rates targets on this board are expected to be run at
+const BAUD_RATES: [u32; 11] =
+[
+	9600, 19200, 38400, 57600, 115200, 230400, 460800, 921600, 1000000, 2000000, 3000000,
+];
+// And the ones of those that are slow enough for the low power clocks
+const LOW_POWER_BAUD_RATES: [u32; 5] = [9600, 19200, 38400, 57600, LOW_POWER_MAX_BAUD];
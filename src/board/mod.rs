@@ -0,0 +1,121 @@
+// SPDX-License-Identifier: BSD-3-Clause
+
+// Everything that differs between the boards the firmware runs on. Each board is a module selected by a
+// `board-*` cargo feature that provides:
+//
+// * `assign_resources!` groups for the USB, target and sniffer UARTs (with their DMA channels), flash,
+//   watchdog, RCC, target control lines, LEDs, modem control inputs (with their EXTI channels) and the
+//   target's load switch and supply monitoring ADC
+// * `UartIrqs` and `UsbIrqs` interrupt bindings for those peripherals, and `usbDriver()` to bring up USB
+// * the interrupts of the USB core, the UARTs and their DMA channels, and the modem inputs, for setting
+//   their priorities, and the one to run the USB executor from through `usbExecutorInterrupt!`
+// * `FULL_SPEED_CLOCKS` and `LOW_POWER_CLOCKS` (planned through `clocks`) to scale between, and the fastest
+//   baud rate the low power clocks are used at
+// * the active levels of the control lines, LEDs, modem inputs and load switch, how the target's supply is
+//   measured, the target UART's registers and its default name
+//
+// Everything built from those that's the same for every board lives here
+
+#[cfg(not(any(feature = "board-conduit-v1", feature = "board-conduit-hs")))]
+compile_error!("A board must be selected by enabling one of the board-* features");
+#[cfg(all(feature = "board-conduit-v1", feature = "board-conduit-hs"))]
+compile_error!("Only one board can be selected at a time");
+
+// Make the given interrupt, which nothing else on the board can be using, the one that drives the USB executor
+macro_rules! usbExecutorInterrupt
+{
+	($interrupt:ident) =>
+	{
+		/// Interrupt that drives the USB executor
+		pub const USB_EXECUTOR_INTERRUPT: embassy_stm32::interrupt::Interrupt = embassy_stm32::interrupt::$interrupt;
+
+		#[embassy_stm32::interrupt]
+		unsafe fn $interrupt()
+		{
+			unsafe { crate::USB_EXECUTOR.on_interrupt() }
+		}
+	};
+}
+
+#[cfg(feature = "board-conduit-v1")]
+mod conduit_v1;
+#[cfg(feature = "board-conduit-v1")]
+pub use conduit_v1::*;
+#[cfg(feature = "board-conduit-hs")]
+mod conduit_hs;
+#[cfg(feature = "board-conduit-hs")]
+pub use conduit_hs::*;
+
+use embassy_stm32::{Config, Peripherals};
+use embassy_stm32::gpio::Level;
+use embassy_stm32::interrupt::{InterruptExt, Priority};
+
+use crate::clocks::{ClockPlan, UsartKernelClock};
+
+pub mod resources
+{
+	pub use super::
+	{
+		AssignedResources,
+		UsbResources,
+		DmaUartResources,
+		SnifferUartResources,
+		FlashResources,
+		WatchdogResources,
+		ClockResources,
+		ControlResources,
+		LedResources,
+		ModemResources,
+		PowerResources,
+	};
+}
+
+pub fn init() -> Peripherals
+{
+	embassy_stm32::init(clockConfig(&FULL_SPEED_CLOCKS))
+}
+
+/// Build the RCC configuration for a clock plan, including routing the USARTs to the clock it picked
+pub fn clockConfig(plan: &ClockPlan) -> Config
+{
+	use embassy_stm32::rcc::mux;
+
+	let mut config = plan.config();
+	// Route the target and sniffer USARTs to the same clock so they stay in step. Every board so far has the
+	// target on USART2 and the sniffer on USART1
+	let (usart1, usart2) = match plan.usartClock()
+	{
+		UsartKernelClock::Pclk => (mux::Usart1sel::PCLK2, mux::Usart2sel::PCLK1),
+		UsartKernelClock::Hsi16 => (mux::Usart1sel::HSI, mux::Usart2sel::HSI),
+	};
+	config.rcc.mux.usart1sel = usart1;
+	config.rcc.mux.usart2sel = usart2;
+	config
+}
+
+pub fn configureInterruptPriorities()
+{
+	// The USB peripheral's interrupt has to be able to preempt the executor it wakes, and that executor has to
+	// be able to preempt the UARTs and their DMA channels (lower numbers are higher priority)
+	USB_INTERRUPT.set_priority(Priority::P4);
+	USB_EXECUTOR_INTERRUPT.set_priority(Priority::P5);
+	for interrupt in UART_INTERRUPTS
+	{
+		interrupt.set_priority(Priority::P6);
+	}
+	// The modem control inputs only need to be noticed, not acted on in a hurry
+	for interrupt in MODEM_INPUT_INTERRUPTS
+	{
+		interrupt.set_priority(Priority::P7);
+	}
+}
+
+/// Get the level that leaves a line with the given active level inactive
+pub const fn inactive(active: Level) -> Level
+{
+	match active
+	{
+		Level::Low => Level::High,
+		Level::High => Level::Low,
+	}
+}
@@ -0,0 +1,184 @@
+// SPDX-License-Identifier: BSD-3-Clause
+
+// Traffic and events get recorded from all over, so recording is always there to call. Without the capture
+// feature though there's no interface to stream a capture out of, so it does nothing
+
+use core::fmt;
+
+use crate::pcapng::PacketDirection;
+#[cfg(feature = "capture")]
+pub use stream::{run, start, stop};
+
+/// Record data forwarded in the given direction
+pub fn data(direction: PacketDirection, data: &[u8])
+{
+	#[cfg(feature = "capture")]
+	stream::data(direction, data);
+	#[cfg(not(feature = "capture"))]
+	let _ = (direction, data);
+}
+
+/// Record a host-initiated event such as a line coding change, as an empty packet carrying a comment
+pub fn event(description: fmt::Arguments)
+{
+	#[cfg(feature = "capture")]
+	stream::event(description);
+	#[cfg(not(feature = "capture"))]
+	let _ = description;
+}
+
+#[cfg(feature = "capture")]
+mod stream
+{
+	use core::fmt::{self, Write};
+	use core::sync::atomic::{AtomicBool, Ordering};
+	use defmt::error;
+	use embassy_sync::blocking_mutex::Mutex;
+	use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+	use embassy_sync::pipe::Pipe;
+	use embassy_time::Instant;
+	use embassy_usb::driver::EndpointIn;
+	use embassy_usb_synopsys_otg::{Endpoint, In};
+
+	use crate::pcapng::{self, LINKTYPE_USER0, PacketDirection};
+	use crate::usb::BULK_PACKET_SIZE;
+
+	// How much captured data can be waiting to go to the host before we start dropping blocks
+	const CAPTURE_BUFFER_SIZE: usize = 2048;
+	// Largest packet we'll record in a single block, and the largest block that can result (with a full comment)
+	const MAX_PACKET_SIZE: usize = 64;
+	const MAX_COMMENT_SIZE: usize = 64;
+	const MAX_BLOCK_SIZE: usize = 192;
+
+	static CAPTURE_PIPE: Pipe<CriticalSectionRawMutex, CAPTURE_BUFFER_SIZE> = Pipe::new();
+	static CAPTURE_ENABLED: AtomicBool = AtomicBool::new(false);
+	// Serialises writers so that blocks only ever go into the pipe whole
+	static CAPTURE_LOCK: Mutex<CriticalSectionRawMutex, ()> = Mutex::new(());
+
+	/// Start a new capture, beginning the stream with the section header and interface description
+	pub fn start()
+	{
+		CAPTURE_LOCK.lock(|_|
+		{
+			CAPTURE_PIPE.clear();
+
+			let mut block = [0u8; MAX_BLOCK_SIZE];
+			if let Some(length) = pcapng::sectionHeaderBlock(&mut block, "usb-serial-conduit")
+			{
+				let _ = CAPTURE_PIPE.try_write(&block[0..length]);
+			}
+			if let Some(length) = pcapng::interfaceDescriptionBlock(&mut block, LINKTYPE_USER0, MAX_PACKET_SIZE as u32, "target")
+			{
+				let _ = CAPTURE_PIPE.try_write(&block[0..length]);
+			}
+			CAPTURE_ENABLED.store(true, Ordering::Relaxed);
+		});
+	}
+
+	pub fn stop()
+	{
+		CAPTURE_ENABLED.store(false, Ordering::Relaxed);
+	}
+
+	/// Record data forwarded in the given direction
+	pub fn data(direction: PacketDirection, data: &[u8])
+	{
+		if !CAPTURE_ENABLED.load(Ordering::Relaxed)
+		{
+			return;
+		}
+
+		let timestamp = Instant::now().as_micros();
+		for packet in data.chunks(MAX_PACKET_SIZE)
+		{
+			let mut block = [0u8; MAX_BLOCK_SIZE];
+			if let Some(length) = pcapng::enhancedPacketBlock(&mut block, timestamp, direction, packet, None)
+			{
+				push(&block[0..length]);
+			}
+		}
+	}
+
+	/// Record a host-initiated event such as a line coding change, as an empty packet carrying a comment
+	pub fn event(description: fmt::Arguments)
+	{
+		if !CAPTURE_ENABLED.load(Ordering::Relaxed)
+		{
+			return;
+		}
+
+		let mut comment = CommentWriter { buffer: [0u8; MAX_COMMENT_SIZE], length: 0 };
+		let _ = comment.write_fmt(description);
+
+		let mut block = [0u8; MAX_BLOCK_SIZE];
+		if let Some(length) = pcapng::enhancedPacketBlock
+		(
+			&mut block,
+			Instant::now().as_micros(),
+			PacketDirection::Outbound,
+			&[],
+			Some(comment.asStr()),
+		)
+		{
+			push(&block[0..length]);
+		}
+	}
+
+	fn push(block: &[u8])
+	{
+		// If the host isn't keeping up, drop the whole block rather than leave a partial one in the stream
+		CAPTURE_LOCK.lock(|_|
+		{
+			if CAPTURE_PIPE.free_capacity() >= block.len()
+			{
+				let _ = CAPTURE_PIPE.try_write(block);
+			}
+		});
+	}
+
+	/// Pump the capture stream out to the host over the capture endpoint
+	pub async fn run(endpoint: &mut Endpoint<'static, In>) -> !
+	{
+		let mut buffer = [0u8; BULK_PACKET_SIZE as usize];
+
+		loop
+		{
+			let byteCount = CAPTURE_PIPE.read(&mut buffer).await;
+			if let Err(error) = endpoint.write(&buffer[0..byteCount]).await
+			{
+				error!("USB capture interface write failed, {}", error)
+			}
+		}
+	}
+
+	/// Formats an event comment into a fixed buffer, truncating anything that doesn't fit
+	struct CommentWriter
+	{
+		buffer: [u8; MAX_COMMENT_SIZE],
+		length: usize,
+	}
+
+	impl CommentWriter
+	{
+		fn asStr(&self) -> &str
+		{
+			// Only whole str's that fit are ever copied in, so the contents are always valid UTF-8
+			unsafe { str::from_utf8_unchecked(&self.buffer[0..self.length]) }
+		}
+	}
+
+	impl Write for CommentWriter
+	{
+		fn write_str(&mut self, string: &str) -> fmt::Result
+		{
+			let remaining = MAX_COMMENT_SIZE - self.length;
+			if string.len() > remaining
+			{
+				return Err(fmt::Error);
+			}
+			self.buffer[self.length..self.length + string.len()].copy_from_slice(string.as_bytes());
+			self.length += string.len();
+			Ok(())
+		}
+	}
+}
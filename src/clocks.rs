@@ -0,0 +1,434 @@
+// SPDX-License-Identifier: BSD-3-Clause
+
+use embassy_stm32::Config;
+use embassy_stm32::rcc::
+{
+	AHBPrescaler, APBPrescaler, Hse, HseMode, Hsi48Config, MSIRange, Pll as PllConfig, PllDiv, PllMul, PllPreDiv,
+	PllSource, Sysclk, VoltageScale,
+};
+use embassy_stm32::time::Hertz;
+
+const MHZ: u32 = 1_000_000;
+// Highest the core and busses can be clocked at
+const MAX_SYSCLK: u32 = 160 * MHZ;
+// Range the PLL's input has to be in after the predivider
+const PLL_INPUT_MIN: u32 = 4 * MHZ;
+const PLL_INPUT_MAX: u32 = 16 * MHZ;
+// Range the PLL's VCO has to run in
+const PLL_VCO_MIN: u32 = 128 * MHZ;
+const PLL_VCO_MAX: u32 = 544 * MHZ;
+const PLL_MUL_MIN: u32 = 4;
+const PLL_MUL_MAX: u32 = 512;
+const PLL_DIV_MAX: u32 = 128;
+// Range an HSE crystal can be in
+const HSE_MIN: u32 = 4 * MHZ;
+const HSE_MAX: u32 = 50 * MHZ;
+// The USB core needs a 48MHz clock, and the AHB to be running at at least 14.2MHz to keep up with it
+const USB_CLOCK: u32 = 48 * MHZ;
+const USB_MIN_SYSCLK: u32 = 14_200_000;
+// The OTG_HS core needs the AHB to be running at at least 30MHz
+#[cfg(feature = "usb-hs")]
+const USB_HS_MIN_SYSCLK: u32 = 30 * MHZ;
+const HSI16_FREQUENCY: u32 = 16 * MHZ;
+// How far off the RC oscillators can be across temperature, in parts per million, vs a crystal
+const RC_TOLERANCE_PPM: u32 = 10_000;
+const CRYSTAL_TOLERANCE_PPM: u32 = 50;
+// Largest value the USART baud rate register can take
+const USART_DIV_MAX: u32 = 0xffff;
+// Smallest value the USART baud rate register can take
+const USART_DIV_MIN: u32 = 16;
+
+/// MSIS ranges that can be used as a clock source. Boards only pick the one that suits them
+#[derive(Clone, Copy)]
+#[allow(dead_code)]
+pub enum MsisRange
+{
+	Range4MHz,
+	Range12MHz,
+	Range16MHz,
+	Range24MHz,
+	Range48MHz,
+}
+
+impl MsisRange
+{
+	const fn frequency(self) -> u32
+	{
+		match self
+		{
+			Self::Range4MHz => 4 * MHZ,
+			Self::Range12MHz => 12 * MHZ,
+			Self::Range16MHz => 16 * MHZ,
+			Self::Range24MHz => 24 * MHZ,
+			Self::Range48MHz => 48 * MHZ,
+		}
+	}
+
+	fn range(self) -> MSIRange
+	{
+		match self
+		{
+			Self::Range4MHz => MSIRange::RANGE_4MHZ,
+			Self::Range12MHz => MSIRange::RANGE_12MHZ,
+			Self::Range16MHz => MSIRange::RANGE_16MHZ,
+			Self::Range24MHz => MSIRange::RANGE_24MHZ,
+			Self::Range48MHz => MSIRange::RANGE_48MHZ,
+		}
+	}
+}
+
+/// Where the system clock ultimately comes from
+#[derive(Clone, Copy)]
+pub enum ClockSource
+{
+	/// The internal multi-speed RC oscillator, which needs no parts on the board
+	#[cfg_attr(feature = "board-conduit-hs", allow(dead_code))]
+	Msis(MsisRange),
+	/// An external crystal of the given frequency in Hz, for when precise clocks are needed
+	#[cfg_attr(feature = "board-conduit-v1", allow(dead_code))]
+	Hse(u32),
+}
+
+impl ClockSource
+{
+	const fn frequency(self) -> u32
+	{
+		match self
+		{
+			Self::Msis(range) => range.frequency(),
+			Self::Hse(frequency) => frequency,
+		}
+	}
+
+	const fn tolerancePPM(self) -> u32
+	{
+		match self
+		{
+			Self::Msis(_) => RC_TOLERANCE_PPM,
+			Self::Hse(_) => CRYSTAL_TOLERANCE_PPM,
+		}
+	}
+}
+
+/// Where the USB core's 48MHz clock comes from
+#[derive(Clone, Copy)]
+pub enum UsbClock
+{
+	/// HSI48, trimmed against the host's start of frame packets by the CRS
+	#[cfg_attr(feature = "board-conduit-hs", allow(dead_code))]
+	Hsi48,
+	/// PLL1's Q output, which requires a VCO frequency that divides down to exactly 48MHz. No board needs this
+	/// yet, as they all have a better 48MHz source
+	#[allow(dead_code)]
+	Pll1Q,
+	/// The OTG_HS core's embedded PHY, which takes its reference clock straight from HSE rather than needing 48MHz
+	#[cfg(feature = "usb-hs")]
+	HighSpeedPhy,
+}
+
+/// How to pick the clock the USARTs' baud rate generators run from
+#[derive(Clone, Copy)]
+pub enum UsartClock
+{
+	// Every board so far leaves the choice to BestFor
+	#[allow(dead_code)]
+	Pclk,
+	#[allow(dead_code)]
+	Hsi16,
+	/// Whichever of the above gives the smallest worst case baud rate error over the given rates
+	BestFor(&'static [u32]),
+}
+
+/// The clock the USARTs were actually set up to run from
+#[derive(Clone, Copy, PartialEq)]
+pub enum UsartKernelClock
+{
+	Pclk,
+	Hsi16,
+}
+
+/// Description of the clock tree a board wants, which gets turned into a plan for the RCC at compile time
+pub struct ClockTree
+{
+	pub source: ClockSource,
+	/// Frequency to run the core and busses at, in Hz
+	pub sysclk: u32,
+	pub usb: UsbClock,
+	pub usart: UsartClock,
+}
+
+/// Dividers and multiplier for PLL1
+#[derive(Clone, Copy)]
+pub struct Pll
+{
+	prediv: u32,
+	mul: u32,
+	divr: u32,
+	divq: Option<u32>,
+}
+
+/// The worked out settings for the RCC that give a clock tree
+pub struct ClockPlan
+{
+	source: ClockSource,
+	pll: Option<Pll>,
+	voltageRange: VoltageScale,
+	usb: UsbClock,
+	usart: UsartKernelClock,
+}
+
+impl ClockTree
+{
+	/// Work out how to set the RCC up for this clock tree. This is meant to be evaluated in a const so that a
+	/// clock tree that can't be built is a compile error rather than a fault at startup
+	pub const fn plan(&self) -> ClockPlan
+	{
+		let sourceFrequency = self.source.frequency();
+		if let ClockSource::Hse(frequency) = self.source
+		{
+			assert!(frequency >= HSE_MIN && frequency <= HSE_MAX, "HSE frequency out of range");
+		}
+		assert!(self.sysclk <= MAX_SYSCLK, "Requested SYSCLK is too fast");
+		assert!(self.sysclk >= USB_MIN_SYSCLK, "Requested SYSCLK is too slow for USB");
+
+		#[cfg(feature = "usb-hs")]
+		if let UsbClock::HighSpeedPhy = self.usb
+		{
+			assert!(matches!(self.source, ClockSource::Hse(16_000_000 | 19_200_000 | 20_000_000 | 24_000_000 |
+				26_000_000 | 32_000_000)), "The high speed PHY needs an HSE crystal of a frequency it supports");
+			assert!(self.sysclk >= USB_HS_MIN_SYSCLK, "Requested SYSCLK is too slow for high speed USB");
+		}
+
+		let needsUsbPll = matches!(self.usb, UsbClock::Pll1Q);
+		// If the source can be used as-is then skip the PLL, which saves power
+		let pll = if self.sysclk == sourceFrequency && !needsUsbPll
+		{
+			None
+		}
+		else
+		{
+			match findPll(sourceFrequency, self.sysclk, needsUsbPll)
+			{
+				Some(pll) => Some(pll),
+				None => panic!("No PLL configuration gives the requested SYSCLK"),
+			}
+		};
+
+		// Pick the lowest power voltage range that can run the clock. Range 4 is never used as neither the PLL
+		// nor the USB core can run in it, and the high speed PHY needs at least range 2
+		#[cfg(feature = "usb-hs")]
+		let needsRange2 = matches!(self.usb, UsbClock::HighSpeedPhy);
+		#[cfg(not(feature = "usb-hs"))]
+		let needsRange2 = false;
+		let voltageRange = if self.sysclk > 110 * MHZ
+		{
+			VoltageScale::RANGE1
+		}
+		else if self.sysclk > 55 * MHZ || needsRange2
+		{
+			VoltageScale::RANGE2
+		}
+		else
+		{
+			VoltageScale::RANGE3
+		};
+
+		let usart = match self.usart
+		{
+			UsartClock::Pclk => UsartKernelClock::Pclk,
+			UsartClock::Hsi16 => UsartKernelClock::Hsi16,
+			UsartClock::BestFor(baudRates) =>
+			{
+				let pclkError = worstBaudError(self.sysclk, self.source.tolerancePPM(), baudRates);
+				let hsi16Error = worstBaudError(HSI16_FREQUENCY, RC_TOLERANCE_PPM, baudRates);
+				assert!(pclkError != u32::MAX || hsi16Error != u32::MAX,
+					"No USART clock can generate all the requested baud rates");
+				// Prefer PCLK on a tie, as it doesn't need another oscillator running
+				if hsi16Error < pclkError
+				{
+					UsartKernelClock::Hsi16
+				}
+				else
+				{
+					UsartKernelClock::Pclk
+				}
+			}
+		};
+
+		ClockPlan { source: self.source, pll, voltageRange, usb: self.usb, usart }
+	}
+}
+
+/// Find PLL1 settings that exactly turn the source frequency into SYSCLK (and optionally 48MHz for USB).
+/// The highest PLL input frequency is preferred for the least jitter
+const fn findPll(sourceFrequency: u32, sysclk: u32, needsUsb: bool) -> Option<Pll>
+{
+	let mut prediv = 1;
+	while prediv <= 16
+	{
+		let input = sourceFrequency / prediv;
+		if sourceFrequency % prediv == 0 && input >= PLL_INPUT_MIN && input <= PLL_INPUT_MAX
+		{
+			// Try the even dividers before divide by 1, to keep the VCO up in its range
+			let mut divr = 2;
+			while divr != 0
+			{
+				let vco = sysclk as u64 * divr as u64;
+				let mul = (vco / input as u64) as u32;
+				let vcoValid = vco >= PLL_VCO_MIN as u64 && vco <= PLL_VCO_MAX as u64;
+				if vcoValid && vco % input as u64 == 0 && mul >= PLL_MUL_MIN && mul <= PLL_MUL_MAX
+				{
+					let divq = if vco % USB_CLOCK as u64 == 0 && vco / USB_CLOCK as u64 <= PLL_DIV_MAX as u64
+					{
+						Some((vco / USB_CLOCK as u64) as u32)
+					}
+					else
+					{
+						None
+					};
+					if !needsUsb || divq.is_some()
+					{
+						return Some(Pll { prediv, mul, divr, divq: if needsUsb { divq } else { None } });
+					}
+				}
+				divr = match divr
+				{
+					PLL_DIV_MAX => 1,
+					1 => 0,
+					_ => divr + 2,
+				};
+			}
+		}
+		prediv += 1;
+	}
+	None
+}
+
+/// Work out the worst case error in parts per million a USART running from the given kernel clock would have
+/// generating any of the baud rates, including the clock's own tolerance. Gives u32::MAX if a rate can't be made
+const fn worstBaudError(kernelClock: u32, tolerancePPM: u32, baudRates: &[u32]) -> u32
+{
+	let mut worst = 0;
+	let mut index = 0;
+	while index < baudRates.len()
+	{
+		let error = baudError(kernelClock, baudRates[index]);
+		if error == u32::MAX
+		{
+			return u32::MAX;
+		}
+		if error + tolerancePPM > worst
+		{
+			worst = error + tolerancePPM;
+		}
+		index += 1;
+	}
+	worst
+}
+
+/// Error in parts per million generating a baud rate from a kernel clock, using whichever of 16x and 8x
+/// oversampling gets closer. Gives u32::MAX if the rate is out of reach
+const fn baudError(kernelClock: u32, baudRate: u32) -> u32
+{
+	let mut best = u32::MAX;
+	// With 16x oversampling the baud rate is the kernel clock over the divider, with 8x it's twice that
+	let mut multiplier = 1;
+	while multiplier <= 2
+	{
+		let clock = kernelClock as u64 * multiplier;
+		let divider = (clock + baudRate as u64 / 2) / baudRate as u64;
+		if divider >= USART_DIV_MIN as u64 && divider <= USART_DIV_MAX as u64
+		{
+			let actual = clock * 1_000_000 / divider;
+			let target = baudRate as u64 * 1_000_000;
+			let difference = if actual > target { actual - target } else { target - actual };
+			let error = (difference / baudRate as u64) as u32;
+			if error < best
+			{
+				best = error;
+			}
+		}
+		multiplier += 1;
+	}
+	best
+}
+
+impl ClockPlan
+{
+	/// Which clock the USARTs should be muxed to
+	pub const fn usartClock(&self) -> UsartKernelClock
+	{
+		self.usart
+	}
+
+	/// Build the RCC configuration for this plan. Picking the USART clock muxes is left to the board,
+	/// as which muxes exist depends on which USARTs it uses
+	pub fn config(&self) -> Config
+	{
+		use embassy_stm32::rcc::mux;
+
+		let mut config = Config::default();
+		// Only run the oscillators we need, and turn MSIK off as nothing uses it
+		config.rcc.msik = None;
+		let (msis, pllSource, directSysclk) = match self.source
+		{
+			ClockSource::Msis(range) => (Some(range.range()), PllSource::MSIS, Sysclk::MSIS),
+			ClockSource::Hse(frequency) =>
+			{
+				config.rcc.hse = Some(Hse { freq: Hertz(frequency), mode: HseMode::Oscillator });
+				(None, PllSource::HSE, Sysclk::HSE)
+			}
+		};
+		config.rcc.msis = msis;
+
+		config.rcc.pll1 = self.pll.map(|pll|
+			PllConfig
+			{
+				source: pllSource,
+				prediv: PllPreDiv::from_bits((pll.prediv - 1) as u8),
+				mul: PllMul::from_bits((pll.mul - 1) as u16),
+				divp: None,
+				divq: pll.divq.map(|divq| PllDiv::from_bits((divq - 1) as u8)),
+				divr: Some(PllDiv::from_bits((pll.divr - 1) as u8)),
+			}
+		);
+		config.rcc.sys = match self.pll
+		{
+			Some(_) => Sysclk::PLL1_R,
+			None => directSysclk,
+		};
+		// SYSCLK is never above what the busses can take, so no prescaling is needed anywhere
+		config.rcc.ahb_pre = AHBPrescaler::DIV1;
+		config.rcc.apb1_pre = APBPrescaler::DIV1;
+		config.rcc.apb2_pre = APBPrescaler::DIV1;
+		config.rcc.apb3_pre = APBPrescaler::DIV1;
+		config.rcc.voltage_range = self.voltageRange;
+
+		match self.usb
+		{
+			UsbClock::Hsi48 =>
+			{
+				// Set up the HSI48 for USB w/ CRS to stabalise the clock
+				config.rcc.hsi48 = Some(Hsi48Config { sync_from_usb: true });
+				config.rcc.mux.iclksel = mux::Iclksel::HSI48;
+			}
+			UsbClock::Pll1Q =>
+			{
+				config.rcc.hsi48 = None;
+				config.rcc.mux.iclksel = mux::Iclksel::PLL1_Q;
+			}
+			#[cfg(feature = "usb-hs")]
+			UsbClock::HighSpeedPhy =>
+			{
+				config.rcc.hsi48 = None;
+				config.rcc.mux.otghssel = mux::Otghssel::HSE;
+			}
+		}
+		if self.usart == UsartKernelClock::Hsi16
+		{
+			config.rcc.hsi = true;
+		}
+
+		config
+	}
+}
@@ -0,0 +1,529 @@
+// SPDX-License-Identifier: BSD-3-Clause
+
+use crate::framing::{FrameEncoding, FramingConfig};
+use crate::prbs::PrbsPattern;
+
+/// Longest command line we take, not counting the carriage return that ends it
+pub const MAX_LINE_LENGTH: usize = 64;
+
+/// Backs the host's terminal cursor over the last character and blanks it out
+pub const ERASE: &[u8] = b"\x08 \x08";
+/// Most that one call to LineEditor::take echoes back, which fits in a single packet to the host at any USB speed
+pub const MAX_ECHO_LENGTH: usize = 64;
+
+const BACKSPACE: u8 = 0x08;
+const DELETE: u8 = 0x7f;
+const CARRIAGE_RETURN: u8 = b'\r';
+
+/// A line coding given on the command line, in the same terms as a CDC SET_LINE_CODING request
+#[derive(Clone, Copy)]
+pub struct LineCoding
+{
+	pub baudRate: u32,
+	/// 0 for 1 stop bit, 1 for 1.5 and 2 for 2
+	pub stopBits: u8,
+	/// 0 for none, 1 for odd and 2 for even. Mark and space can't be represented by the UARTs so aren't accepted
+	pub parityType: u8,
+	pub dataBits: u8,
+}
+
+/// What the UART gets used for once back in data mode
+#[derive(Clone, Copy)]
+pub enum DataMode
+{
+	Data,
+	Sniffer,
+	Bert(PrbsPattern),
+	Framed(FramingConfig),
+}
+
+/// A command from a complete line
+#[derive(Clone, Copy)]
+pub enum Command
+{
+	/// Nothing but whitespace, which gets no response
+	Blank,
+	/// Plain `AT`, just checking we're listening
+	Attention,
+	/// `ATI`, identify ourselves
+	Identify,
+	/// `ATO`, go back to data mode
+	Online,
+	/// `AT+CODING?`
+	QueryCoding,
+	/// `AT+CODING=<baud>,<data bits>,<N|O|E>,<1|1.5|2>`
+	SetCoding(LineCoding),
+	/// `AT+MODE?`
+	QueryMode,
+	/// `AT+MODE=DATA`, `AT+MODE=SNIFFER`, `AT+MODE=BERT,<7|15|23|31>` or `AT+MODE=<COBS|SLIP>[,CRC]`
+	SetMode(DataMode),
+	/// `AT+RESET[=<milliseconds>]`, pulse the target's reset line
+	Reset(Option<u32>),
+	/// `AT+BOOT[=<milliseconds>]`, pulse the target's reset line with its boot mode select line held
+	Bootloader(Option<u32>),
+}
+
+/// What a character did to the line being edited
+pub enum Edit
+{
+	/// Nothing, so there's nothing to echo
+	Ignored,
+	/// It was added to the line, and should be echoed
+	Echo(u8),
+	/// The last character was rubbed out, which needs backing over on the host's terminal
+	Erase,
+	/// The line is complete and ready to be finished
+	Line,
+}
+
+/// How far LineEditor::take got through some characters from the host
+pub struct Input
+{
+	/// How many of the characters were taken
+	pub used: usize,
+	/// How much was written out to be echoed
+	pub echoLength: usize,
+	/// Whether the line was ended, and is ready to be finished
+	pub lineComplete: bool,
+}
+
+/// Collects characters from the host into a command line, with enough editing for someone typing at a terminal
+pub struct LineEditor
+{
+	buffer: [u8; MAX_LINE_LENGTH],
+	length: usize,
+	overflowed: bool,
+}
+
+impl LineEditor
+{
+	pub const fn new() -> Self
+	{
+		Self { buffer: [0; MAX_LINE_LENGTH], length: 0, overflowed: false }
+	}
+
+	pub fn push(&mut self, byte: u8) -> Edit
+	{
+		match byte
+		{
+			CARRIAGE_RETURN => Edit::Line,
+			BACKSPACE | DELETE if self.length != 0 =>
+			{
+				self.length -= 1;
+				Edit::Erase
+			}
+			// Line feeds after the carriage return and any other control characters are dropped
+			_ if !byte.is_ascii_graphic() && byte != b' ' => Edit::Ignored,
+			_ if self.length == MAX_LINE_LENGTH =>
+			{
+				self.overflowed = true;
+				Edit::Ignored
+			}
+			_ =>
+			{
+				self.buffer[self.length] = byte;
+				self.length += 1;
+				Edit::Echo(byte)
+			}
+		}
+	}
+
+	/// Take in characters up to the end of the line, writing what to echo back to the host out to echo. Stops
+	/// early once echo mightn't have room for what another character echoes, leaving the rest for another call
+	pub fn take(&mut self, data: &[u8], echo: &mut [u8]) -> Input
+	{
+		let mut input = Input { used: 0, echoLength: 0, lineComplete: false };
+		for byte in data
+		{
+			if echo.len() - input.echoLength < ERASE.len()
+			{
+				break;
+			}
+			input.used += 1;
+			match self.push(*byte)
+			{
+				Edit::Ignored => {}
+				Edit::Echo(byte) =>
+				{
+					echo[input.echoLength] = byte;
+					input.echoLength += 1;
+				}
+				Edit::Erase =>
+				{
+					echo[input.echoLength..input.echoLength + ERASE.len()].copy_from_slice(ERASE);
+					input.echoLength += ERASE.len();
+				}
+				Edit::Line =>
+				{
+					input.lineComplete = true;
+					break;
+				}
+			}
+		}
+		input
+	}
+
+	/// Parse the completed line and start a new one. Returns None if the line isn't a command we know or was too
+	/// long to take in
+	pub fn finish(&mut self) -> Option<Command>
+	{
+		let command = if self.overflowed { None } else { parse(&self.buffer[0..self.length]) };
+		self.length = 0;
+		self.overflowed = false;
+		command
+	}
+}
+
+impl Default for LineEditor
+{
+	fn default() -> Self
+	{
+		Self::new()
+	}
+}
+
+/// Parse a command line, which is case insensitive and may have whitespace around it
+pub fn parse(line: &[u8]) -> Option<Command>
+{
+	let line = line.trim_ascii();
+	if line.is_empty()
+	{
+		return Some(Command::Blank);
+	}
+	let (prefix, command) = line.split_at_checked(2)?;
+	if !prefix.eq_ignore_ascii_case(b"AT")
+	{
+		return None;
+	}
+
+	if command.is_empty()
+	{
+		return Some(Command::Attention);
+	}
+	if command.eq_ignore_ascii_case(b"I")
+	{
+		return Some(Command::Identify);
+	}
+	if command.eq_ignore_ascii_case(b"O")
+	{
+		return Some(Command::Online);
+	}
+
+	// Everything else is an extended command, which is either a query or a name with optional arguments
+	let command = command.strip_prefix(b"+")?;
+	let (name, arguments) = match command.iter().position(|byte| *byte == b'=')
+	{
+		Some(index) => (&command[0..index], Some(&command[index + 1..])),
+		None => (command, None),
+	};
+	match arguments
+	{
+		None if name.eq_ignore_ascii_case(b"CODING?") => Some(Command::QueryCoding),
+		None if name.eq_ignore_ascii_case(b"MODE?") => Some(Command::QueryMode),
+		Some(arguments) if name.eq_ignore_ascii_case(b"CODING") => parseCoding(arguments).map(Command::SetCoding),
+		Some(arguments) if name.eq_ignore_ascii_case(b"MODE") => parseMode(arguments).map(Command::SetMode),
+		_ if name.eq_ignore_ascii_case(b"RESET") => parseDuration(arguments).map(Command::Reset),
+		_ if name.eq_ignore_ascii_case(b"BOOT") => parseDuration(arguments).map(Command::Bootloader),
+		_ => None,
+	}
+}
+
+fn parseCoding(arguments: &[u8]) -> Option<LineCoding>
+{
+	let mut fields = arguments.split(|byte| *byte == b',').map(<[u8]>::trim_ascii);
+	let baudRate = parseNumber(fields.next()?)?;
+	let dataBits = match fields.next()?
+	{
+		b"7" => 7,
+		b"8" => 8,
+		b"9" => 9,
+		_ => return None,
+	};
+	let parityType = match fields.next()?
+	{
+		b"N" | b"n" => 0,
+		b"O" | b"o" => 1,
+		b"E" | b"e" => 2,
+		_ => return None,
+	};
+	let stopBits = match fields.next()?
+	{
+		b"1" => 0,
+		b"1.5" => 1,
+		b"2" => 2,
+		_ => return None,
+	};
+	if fields.next().is_some() || baudRate == 0
+	{
+		return None;
+	}
+	Some(LineCoding { baudRate, stopBits, parityType, dataBits })
+}
+
+fn parseMode(arguments: &[u8]) -> Option<DataMode>
+{
+	let mut fields = arguments.split(|byte| *byte == b',').map(<[u8]>::trim_ascii);
+	let name = fields.next()?;
+	let option = fields.next();
+	if fields.next().is_some()
+	{
+		return None;
+	}
+
+	if name.eq_ignore_ascii_case(b"DATA") && option.is_none()
+	{
+		Some(DataMode::Data)
+	}
+	else if name.eq_ignore_ascii_case(b"SNIFFER") && option.is_none()
+	{
+		Some(DataMode::Sniffer)
+	}
+	else if name.eq_ignore_ascii_case(b"BERT")
+	{
+		let order = u16::try_from(parseNumber(option?)?).ok()?;
+		PrbsPattern::try_from(order).ok().map(DataMode::Bert)
+	}
+	else
+	{
+		let encoding = if name.eq_ignore_ascii_case(b"COBS")
+		{
+			FrameEncoding::Cobs
+		}
+		else if name.eq_ignore_ascii_case(b"SLIP")
+		{
+			FrameEncoding::Slip
+		}
+		else
+		{
+			return None;
+		};
+		let crc = match option
+		{
+			None => false,
+			Some(option) if option.eq_ignore_ascii_case(b"CRC") => true,
+			Some(_) => return None,
+		};
+		Some(DataMode::Framed(FramingConfig { encoding, crc, delimiter: None }))
+	}
+}
+
+// A duration is optional, but if the `=` is there then so must be a number
+fn parseDuration(arguments: Option<&[u8]>) -> Option<Option<u32>>
+{
+	match arguments
+	{
+		None => Some(None),
+		Some(arguments) => parseNumber(arguments.trim_ascii()).map(Some),
+	}
+}
+
+fn parseNumber(digits: &[u8]) -> Option<u32>
+{
+	if digits.is_empty()
+	{
+		return None;
+	}
+	digits.iter().try_fold(0u32, |value, digit|
+	{
+		if !digit.is_ascii_digit()
+		{
+			return None;
+		}
+		value.checked_mul(10)?.checked_add((digit - b'0') as u32)
+	})
+}
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+
+	fn coding(line: &[u8]) -> Option<(u32, u8, u8, u8)>
+	{
+		match parse(line)
+		{
+			Some(Command::SetCoding(coding)) =>
+				Some((coding.baudRate, coding.dataBits, coding.parityType, coding.stopBits)),
+			_ => None,
+		}
+	}
+
+	#[test]
+	fn parsesBasicCommands()
+	{
+		assert!(matches!(parse(b""), Some(Command::Blank)));
+		assert!(matches!(parse(b"  \t"), Some(Command::Blank)));
+		assert!(matches!(parse(b"AT"), Some(Command::Attention)));
+		assert!(matches!(parse(b"  at "), Some(Command::Attention)));
+		assert!(matches!(parse(b"ATI"), Some(Command::Identify)));
+		assert!(matches!(parse(b"ati"), Some(Command::Identify)));
+		assert!(matches!(parse(b"ATO"), Some(Command::Online)));
+		assert!(matches!(parse(b"AT+CODING?"), Some(Command::QueryCoding)));
+		assert!(matches!(parse(b"at+mode?"), Some(Command::QueryMode)));
+	}
+
+	#[test]
+	fn rejectsUnknownCommands()
+	{
+		for line in [&b"A"[..], b"XT", b"ATX", b"ATI1", b"AT+", b"AT+FOO", b"AT+FOO=1", b"AT CODING?", b"AT+CODING?=",
+			b"AT+CODING", b"AT+MODE", b"AT+RESET?"]
+		{
+			assert!(parse(line).is_none(), "{:?}", core::str::from_utf8(line));
+		}
+	}
+
+	#[test]
+	fn parsesCoding()
+	{
+		assert_eq!(coding(b"AT+CODING=115200,8,N,1"), Some((115200, 8, 0, 0)));
+		assert_eq!(coding(b"at+coding=9600, 7, e, 1.5"), Some((9600, 7, 2, 1)));
+		assert_eq!(coding(b"AT+CODING=4000000,9,O,2"), Some((4000000, 9, 1, 2)));
+		assert_eq!(coding(b"AT+CODING=4294967295,8,n,1"), Some((u32::MAX, 8, 0, 0)));
+	}
+
+	#[test]
+	fn rejectsBadCoding()
+	{
+		for line in [&b"AT+CODING="[..], b"AT+CODING=0,8,N,1", b"AT+CODING=4294967296,8,N,1", b"AT+CODING=-1,8,N,1",
+			b"AT+CODING=1e3,8,N,1", b"AT+CODING=115200,6,N,1", b"AT+CODING=115200,8,M,1", b"AT+CODING=115200,8,S,1",
+			b"AT+CODING=115200,8,N,0", b"AT+CODING=115200,8,N,3", b"AT+CODING=115200,8,N", b"AT+CODING=115200,8,N,1,",
+			b"AT+CODING=115200,,N,1", b"AT+CODING=115200 8 N 1"]
+		{
+			assert!(coding(line).is_none(), "{:?}", core::str::from_utf8(line));
+		}
+	}
+
+	#[test]
+	fn parsesModes()
+	{
+		assert!(matches!(parse(b"AT+MODE=DATA"), Some(Command::SetMode(DataMode::Data))));
+		assert!(matches!(parse(b"AT+MODE=sniffer"), Some(Command::SetMode(DataMode::Sniffer))));
+		assert!(matches!(parse(b"AT+MODE=BERT,7"), Some(Command::SetMode(DataMode::Bert(PrbsPattern::Prbs7)))));
+		assert!(matches!(parse(b"AT+MODE=bert, 31"), Some(Command::SetMode(DataMode::Bert(PrbsPattern::Prbs31)))));
+		assert!(matches!(parse(b"AT+MODE=COBS"), Some(Command::SetMode(DataMode::Framed(FramingConfig
+			{ encoding: FrameEncoding::Cobs, crc: false, delimiter: None })))));
+		assert!(matches!(parse(b"AT+MODE=slip,crc"), Some(Command::SetMode(DataMode::Framed(FramingConfig
+			{ encoding: FrameEncoding::Slip, crc: true, delimiter: None })))));
+	}
+
+	#[test]
+	fn rejectsBadModes()
+	{
+		for line in [&b"AT+MODE="[..], b"AT+MODE=FOO", b"AT+MODE=DATA,CRC", b"AT+MODE=SNIFFER,1", b"AT+MODE=BERT",
+			b"AT+MODE=BERT,", b"AT+MODE=BERT,8", b"AT+MODE=BERT,65543", b"AT+MODE=COBS,FOO", b"AT+MODE=SLIP,CRC,CRC"]
+		{
+			assert!(parse(line).is_none(), "{:?}", core::str::from_utf8(line));
+		}
+	}
+
+	#[test]
+	fn parsesResets()
+	{
+		assert!(matches!(parse(b"AT+RESET"), Some(Command::Reset(None))));
+		assert!(matches!(parse(b"AT+RESET=250"), Some(Command::Reset(Some(250)))));
+		assert!(matches!(parse(b"at+boot"), Some(Command::Bootloader(None))));
+		assert!(matches!(parse(b"AT+BOOT= 10 "), Some(Command::Bootloader(Some(10)))));
+		for line in [&b"AT+RESET="[..], b"AT+RESET=1x", b"AT+BOOT=-5", b"AT+BOOT=1,2"]
+		{
+			assert!(parse(line).is_none(), "{:?}", core::str::from_utf8(line));
+		}
+	}
+
+	fn typeLine(editor: &mut LineEditor, line: &[u8]) -> Vec<u8>
+	{
+		line.iter().filter_map(|byte|
+		{
+			match editor.push(*byte)
+			{
+				Edit::Echo(byte) => Some(byte),
+				Edit::Erase => Some(BACKSPACE),
+				Edit::Ignored => None,
+				Edit::Line => Some(CARRIAGE_RETURN),
+			}
+		}).collect()
+	}
+
+	#[test]
+	fn editorTakesLine()
+	{
+		let mut editor = LineEditor::new();
+		assert_eq!(typeLine(&mut editor, b"\nATI\r"), b"ATI\r");
+		assert!(matches!(editor.finish(), Some(Command::Identify)));
+		// Each line starts afresh, and control characters never make it in
+		assert_eq!(typeLine(&mut editor, b"\nA\x1bT\0\r"), b"AT\r");
+		assert!(matches!(editor.finish(), Some(Command::Attention)));
+	}
+
+	#[test]
+	fn editorErases()
+	{
+		let mut editor = LineEditor::new();
+		assert_eq!(typeLine(&mut editor, b"ATX\x08O\r"), b"ATX\x08O\r");
+		assert!(matches!(editor.finish(), Some(Command::Online)));
+		assert_eq!(typeLine(&mut editor, b"ATI\x7f\x7f\x7f\x7fAT\r"), b"ATI\x08\x08\x08AT\r");
+		assert!(matches!(editor.finish(), Some(Command::Attention)));
+	}
+
+	#[test]
+	fn editorRejectsOverflow()
+	{
+		let mut editor = LineEditor::new();
+		let mut line = b"AT".to_vec();
+		line.resize(MAX_LINE_LENGTH, b' ');
+		assert_eq!(typeLine(&mut editor, &line), line);
+		// The line's full, but only characters past the end count as overflowing it
+		assert!(matches!(editor.finish(), Some(Command::Attention)));
+
+		typeLine(&mut editor, &line);
+		assert!(matches!(editor.push(b' '), Edit::Ignored));
+		// Rubbing out doesn't undo having lost a character
+		assert!(matches!(editor.push(BACKSPACE), Edit::Erase));
+		assert!(editor.finish().is_none());
+
+		// And the next line is back to normal
+		typeLine(&mut editor, b"AT\r");
+		assert!(matches!(editor.finish(), Some(Command::Attention)));
+	}
+
+	// Take all of data through the editor the way command mode does, checking every echo fits
+	fn takeAll(editor: &mut LineEditor, mut data: &[u8]) -> (Vec<u8>, bool)
+	{
+		let mut echoed = Vec::new();
+		while !data.is_empty()
+		{
+			let mut echo = [0u8; MAX_ECHO_LENGTH];
+			let input = editor.take(data, &mut echo);
+			assert!(input.used != 0);
+			echoed.extend_from_slice(&echo[0..input.echoLength]);
+			data = &data[input.used..];
+			if input.lineComplete
+			{
+				return (echoed, true);
+			}
+		}
+		(echoed, false)
+	}
+
+	#[test]
+	fn echoFitsOnePacket()
+	{
+		let mut editor = LineEditor::new();
+		let line = [b'X'; MAX_LINE_LENGTH];
+		assert_eq!(takeAll(&mut editor, &line), (line.to_vec(), false));
+
+		// Rubbing out a whole line echoes three times as much as went in, so it goes back over several calls
+		let rubOut = [DELETE; MAX_LINE_LENGTH];
+		let mut echo = [0u8; MAX_ECHO_LENGTH];
+		let input = editor.take(&rubOut, &mut echo);
+		assert!(input.used < MAX_LINE_LENGTH && input.echoLength <= MAX_ECHO_LENGTH);
+		let (echoed, lineComplete) = takeAll(&mut editor, &rubOut[input.used..]);
+		assert!(!lineComplete);
+		assert_eq!(input.echoLength + echoed.len(), MAX_LINE_LENGTH * ERASE.len());
+		assert!(echoed.chunks(ERASE.len()).all(|erase| erase == ERASE));
+
+		// With the line empty again, what comes next is a line of its own
+		assert_eq!(takeAll(&mut editor, b"ATI\rAT\r"), (b"ATI".to_vec(), true));
+		assert!(matches!(editor.finish(), Some(Command::Identify)));
+	}
+
+}
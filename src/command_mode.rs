@@ -0,0 +1,262 @@
+// SPDX-License-Identifier: BSD-3-Clause
+
+use core::fmt::{self, Write};
+use defmt::error;
+use embassy_futures::select::{Either3, select3};
+use embassy_stm32::mode::Async;
+use embassy_stm32::usart::Uart;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::channel::{Receiver, Sender};
+use embassy_time::{Instant, Timer};
+
+use crate::command_line::{Command, Input, LineCoding, LineEditor, MAX_ECHO_LENGTH};
+use crate::escape::{DEFAULT_GUARD_TIME, EscapeDetector, GuardExpiry};
+use crate::packet::{MAX_PACKET_SIZE, Packet};
+use crate::serial::SerialEvent;
+use crate::types::{ParityType, ReceiveRequest, SerialCommand, SerialEncoding, StopBits, TransmitRequest};
+use crate::watchdog::{self, Watched};
+
+/// Largest response line we send, not counting the line endings around it
+const MAX_RESPONSE_LENGTH: usize = 64;
+
+/// Tracks whether the host has escaped out of data mode, and takes in its command lines while it has
+pub struct CommandMode
+{
+	detector: EscapeDetector,
+	editor: LineEditor,
+	active: bool,
+}
+
+impl CommandMode
+{
+	pub const fn new() -> Self
+	{
+		Self { detector: EscapeDetector::new(DEFAULT_GUARD_TIME), editor: LineEditor::new(), active: false }
+	}
+
+	pub fn isActive(&self) -> bool
+	{
+		self.active
+	}
+
+	/// Look for the escape sequence in data from the host, returning what should go on to the target. The output
+	/// must have room for ESCAPE_LENGTH more bytes than the data
+	pub fn fromHost<'a>(&mut self, data: &[u8], output: &'a mut [u8]) -> &'a [u8]
+	{
+		let length = self.detector.feed(data, Instant::now().as_millis(), output);
+		&output[0..length]
+	}
+
+	/// Wait for the guard time after the last data from the host to run out, if anything is waiting on it
+	pub fn guardTimeout(&self) -> impl Future<Output = ()> + use<>
+	{
+		let deadline = self.detector.deadline();
+		async move
+		{
+			match deadline
+			{
+				Some(deadline) => Timer::at(Instant::from_millis(deadline)).await,
+				None => core::future::pending().await,
+			}
+		}
+	}
+
+	/// Act on the guard time running out. Returns how many held back escape characters turned out to be data
+	/// for the target, after switching to command mode if they were an escape sequence instead
+	pub async fn guardTimeElapsed(
+		&mut self,
+		transmitChannel: &Sender<'static, CriticalSectionRawMutex, TransmitRequest, 1>,
+	) -> usize
+	{
+		match self.detector.expire(Instant::now().as_millis())
+		{
+			GuardExpiry::Escape =>
+			{
+				self.active = true;
+				ok(transmitChannel).await;
+				0
+			}
+			GuardExpiry::Release(count) => count,
+			GuardExpiry::Nothing => 0,
+		}
+	}
+
+	/// Go back to data mode. It takes another full guard time of quiet before the host can escape again
+	pub fn leave(&mut self)
+	{
+		self.active = false;
+		self.detector.restart(Instant::now().as_millis());
+	}
+
+	/// Take in and echo characters from the host up to the end of the next command line, or as many as echo in
+	/// one packet. Returns how much of the data was used, along with the command if a line was completed. Lines
+	/// that aren't understood are answered with an error here, and blank ones ignored
+	pub async fn input(
+		&mut self,
+		data: &[u8],
+		transmitChannel: &Sender<'static, CriticalSectionRawMutex, TransmitRequest, 1>,
+	) -> (usize, Option<Command>)
+	{
+		let mut echo = [0u8; MAX_ECHO_LENGTH];
+		let Input { used, echoLength, lineComplete } = self.editor.take(data, &mut echo);
+		if echoLength != 0
+		{
+			send(transmitChannel, &echo[0..echoLength]).await;
+		}
+		if !lineComplete
+		{
+			return (used, None);
+		}
+		match self.editor.finish()
+		{
+			Some(Command::Blank) => (used, None),
+			Some(command) => (used, Some(command)),
+			None =>
+			{
+				error(transmitChannel).await;
+				(used, None)
+			}
+		}
+	}
+}
+
+/// Wait for something to come in while in command mode. Anything from the target meanwhile has nowhere to go
+/// so is discarded
+pub async fn idle(
+	serialPort: &mut Uart<'static, Async>,
+	receiveChannel: &Receiver<'static, CriticalSectionRawMutex, ReceiveRequest, 1>,
+	serialCommands: &Receiver<'static, CriticalSectionRawMutex, SerialCommand, 2>,
+) -> SerialEvent
+{
+	let mut discard = [0u8; MAX_PACKET_SIZE];
+	let discardFuture = async
+	{
+		loop
+		{
+			watchdog::heartbeat(Watched::Serial);
+			if let Err(error) = watchdog::excused(Watched::Serial, serialPort.read_until_idle(&mut discard)).await
+			{
+				error!("Serial interface read failed, {}", error);
+			}
+		}
+	};
+
+	match select3(discardFuture, receiveChannel.receive(), serialCommands.receive()).await
+	{
+		Either3::First(_) => unreachable!("Discarding target data never completes"),
+		Either3::Second(request) => SerialEvent::Request(request),
+		Either3::Third(command) => SerialEvent::Command(command),
+	}
+}
+
+/// Turn a line coding from the command line into the encoding to apply to the UARTs
+pub fn encodingFor(coding: &LineCoding) -> SerialEncoding
+{
+	SerialEncoding::new
+	(
+		coding.baudRate,
+		StopBits::from(coding.stopBits),
+		ParityType::from(coding.parityType),
+		coding.dataBits
+	)
+}
+
+/// Report the line coding in the same form `AT+CODING=` takes it
+pub async fn reportCoding(
+	encoding: &SerialEncoding,
+	transmitChannel: &Sender<'static, CriticalSectionRawMutex, TransmitRequest, 1>,
+)
+{
+	let mut data = [0u8; 7];
+	encoding.toData(&mut data).expect("Encodings always fit their buffer");
+	let baudRate = u32::from_le_bytes([data[0], data[1], data[2], data[3]]);
+	let stopBits = match data[4]
+	{
+		0 => "1",
+		1 => "1.5",
+		_ => "2",
+	};
+	let parityType = match data[5]
+	{
+		0 => 'N',
+		1 => 'O',
+		2 => 'E',
+		3 => 'M',
+		_ => 'S',
+	};
+	respond(transmitChannel, format_args!("+CODING: {},{},{},{}", baudRate, data[6], parityType, stopBits)).await;
+}
+
+/// Send a line of response text to the host, followed by OK
+pub async fn respond(
+	transmitChannel: &Sender<'static, CriticalSectionRawMutex, TransmitRequest, 1>,
+	response: fmt::Arguments<'_>,
+)
+{
+	let mut line = [0u8; MAX_RESPONSE_LENGTH + 4];
+	line[0..2].copy_from_slice(b"\r\n");
+	let mut writer = ResponseWriter { buffer: &mut line[2..MAX_RESPONSE_LENGTH + 2], length: 0 };
+	// Responses are all short and of our own making, so if one gets truncated it's still worth sending
+	let _ = writer.write_fmt(response);
+	let length = writer.length + 2;
+	line[length..length + 2].copy_from_slice(b"\r\n");
+	send(transmitChannel, &line[0..length + 2]).await;
+	ok(transmitChannel).await;
+}
+
+/// Send a bare result code to the host
+pub async fn result(transmitChannel: &Sender<'static, CriticalSectionRawMutex, TransmitRequest, 1>, code: &str)
+{
+	let mut response = [0u8; 16];
+	let length = code.len() + 4;
+	response[0..2].copy_from_slice(b"\r\n");
+	response[2..length - 2].copy_from_slice(code.as_bytes());
+	response[length - 2..length].copy_from_slice(b"\r\n");
+	send(transmitChannel, &response[0..length]).await;
+}
+
+pub async fn ok(transmitChannel: &Sender<'static, CriticalSectionRawMutex, TransmitRequest, 1>)
+{
+	result(transmitChannel, "OK").await;
+}
+
+pub async fn error(transmitChannel: &Sender<'static, CriticalSectionRawMutex, TransmitRequest, 1>)
+{
+	result(transmitChannel, "ERROR").await;
+}
+
+async fn send(transmitChannel: &Sender<'static, CriticalSectionRawMutex, TransmitRequest, 1>, data: &[u8])
+{
+	// Whether the response goes anywhere is down to the host reading it out
+	watchdog::excused(Watched::Serial, async
+	{
+		let packet = Packet::copyFrom(data).await;
+		transmitChannel.send(TransmitRequest::Data(packet)).await;
+	}).await;
+}
+
+/// Formats a response line into a fixed buffer, keeping as much as fits
+struct ResponseWriter<'a>
+{
+	buffer: &'a mut [u8],
+	length: usize,
+}
+
+impl Write for ResponseWriter<'_>
+{
+	fn write_str(&mut self, string: &str) -> fmt::Result
+	{
+		let remaining = self.buffer.len() - self.length;
+		let byteCount = string.len().min(remaining);
+		self.buffer[self.length..self.length + byteCount].copy_from_slice(&string.as_bytes()[0..byteCount]);
+		self.length += byteCount;
+		if byteCount < string.len()
+		{
+			Err(fmt::Error)
+		}
+		else
+		{
+			Ok(())
+		}
+	}
+}
@@ -0,0 +1,213 @@
+// SPDX-License-Identifier: BSD-3-Clause
+
+use core::cell::{Cell, UnsafeCell};
+use core::fmt::{self, Write};
+use core::mem::MaybeUninit;
+use core::panic::PanicInfo;
+use cortex_m::peripheral::SCB;
+use cortex_m_rt::{ExceptionFrame, exception};
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+
+use crate::watchdog;
+
+// Marks the record in RAM as having been written by one of our handlers rather than being power-on garbage
+const CRASH_RECORD_MAGIC: u32 = 0x48535243;
+const MESSAGE_LENGTH: usize = 128;
+const FILE_LENGTH: usize = 64;
+const CRASH_RECORD_HEADER_LENGTH: usize = 40;
+// How many bytes a crash record takes up when sent to the host
+const CRASH_RECORD_LENGTH: usize = CRASH_RECORD_HEADER_LENGTH + FILE_LENGTH + MESSAGE_LENGTH;
+
+/// What brought the firmware down
+#[repr(u8)]
+#[derive(Clone, Copy, PartialEq)]
+enum CrashKind
+{
+	None = 0,
+	Panic = 1,
+	HardFault = 2,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct CrashRecord
+{
+	magic: u32,
+	kind: CrashKind,
+	messageLength: u8,
+	fileLength: u8,
+	line: u32,
+	column: u32,
+	// Configurable fault status, hard fault status, and the fault address registers
+	cfsr: u32,
+	hfsr: u32,
+	mmfar: u32,
+	bfar: u32,
+	// Where the fault happened, from the exception frame
+	pc: u32,
+	lr: u32,
+	xpsr: u32,
+	file: [u8; FILE_LENGTH],
+	message: [u8; MESSAGE_LENGTH],
+}
+
+impl CrashRecord
+{
+	const fn new(kind: CrashKind) -> Self
+	{
+		Self
+		{
+			magic: CRASH_RECORD_MAGIC,
+			kind,
+			messageLength: 0,
+			fileLength: 0,
+			line: 0,
+			column: 0,
+			cfsr: 0,
+			hfsr: 0,
+			mmfar: 0,
+			bfar: 0,
+			pc: 0,
+			lr: 0,
+			xpsr: 0,
+			file: [0; FILE_LENGTH],
+			message: [0; MESSAGE_LENGTH],
+		}
+	}
+
+	pub fn toData(&self, data: &mut [u8]) -> Option<usize>
+	{
+		// There need to be enough bytes to format out the whole record, even if the strings are short
+		if data.len() < CRASH_RECORD_LENGTH
+		{
+			return None;
+		}
+
+		// The reset cause goes along with the record as together they explain the last reset
+		data[0] = watchdog::resetReason();
+		data[1] = self.kind as u8;
+		data[2] = self.messageLength;
+		data[3] = self.fileLength;
+		data[4..8].copy_from_slice(&self.line.to_le_bytes());
+		data[8..12].copy_from_slice(&self.column.to_le_bytes());
+		data[12..16].copy_from_slice(&self.cfsr.to_le_bytes());
+		data[16..20].copy_from_slice(&self.hfsr.to_le_bytes());
+		data[20..24].copy_from_slice(&self.mmfar.to_le_bytes());
+		data[24..28].copy_from_slice(&self.bfar.to_le_bytes());
+		data[28..32].copy_from_slice(&self.pc.to_le_bytes());
+		data[32..36].copy_from_slice(&self.lr.to_le_bytes());
+		data[36..40].copy_from_slice(&self.xpsr.to_le_bytes());
+		let fileOffset = CRASH_RECORD_HEADER_LENGTH;
+		let messageOffset = fileOffset + FILE_LENGTH;
+		data[fileOffset..messageOffset].copy_from_slice(&self.file);
+		data[messageOffset..CRASH_RECORD_LENGTH].copy_from_slice(&self.message);
+		Some(CRASH_RECORD_LENGTH)
+	}
+}
+
+/// Holds the crash record somewhere that isn't zeroed at startup, so it survives the reset after a crash
+struct CrashStorage(UnsafeCell<MaybeUninit<CrashRecord>>);
+
+// This is only written with interrupts effectively stopped in the panic and fault handlers, and only read
+// once at startup before anything else is running
+unsafe impl Sync for CrashStorage {}
+
+#[unsafe(link_section = ".uninit.CRASH_RECORD")]
+static CRASH_STORAGE: CrashStorage = CrashStorage(UnsafeCell::new(MaybeUninit::uninit()));
+
+// The record recovered from the last boot, if there was a crash
+static LAST_CRASH: Mutex<CriticalSectionRawMutex, Cell<CrashRecord>> =
+	Mutex::new(Cell::new(CrashRecord::new(CrashKind::None)));
+
+/// Pick up any crash record left behind by the last boot, and clear it out so it's not reported twice
+pub fn readCrashRecord()
+{
+	let storage = CRASH_STORAGE.0.get();
+	// The storage isn't initialised after power-on, so read it as raw bytes and only trust it with the magic
+	// number present and a valid kind. Even then, the lengths get clamped in case of bit rot
+	let magic = unsafe { (&raw const (*storage.cast::<CrashRecord>()).magic).read_volatile() };
+	let kind = unsafe { (&raw const (*storage.cast::<CrashRecord>()).kind).cast::<u8>().read_volatile() };
+	if magic == CRASH_RECORD_MAGIC && (kind == CrashKind::Panic as u8 || kind == CrashKind::HardFault as u8)
+	{
+		let mut record = unsafe { (*storage).assume_init_read() };
+		record.messageLength = record.messageLength.min(MESSAGE_LENGTH as u8);
+		record.fileLength = record.fileLength.min(FILE_LENGTH as u8);
+		LAST_CRASH.lock(|crash| crash.set(record));
+	}
+	unsafe { (&raw mut (*storage.cast::<CrashRecord>()).magic).write_volatile(0) };
+}
+
+/// Get the record of the last crash, which has a kind of none if the last reset wasn't due to one
+pub fn lastCrash() -> CrashRecord
+{
+	LAST_CRASH.lock(Cell::get)
+}
+
+fn storeAndReset(record: &CrashRecord) -> !
+{
+	unsafe { (*CRASH_STORAGE.0.get()).write(*record) };
+	SCB::sys_reset()
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> !
+{
+	cortex_m::interrupt::disable();
+	defmt::error!("{}", defmt::Display2Format(info));
+
+	let mut record = CrashRecord::new(CrashKind::Panic);
+	let mut message = TruncatingWriter { buffer: &mut record.message, length: 0 };
+	let _ = write!(message, "{}", info.message());
+	record.messageLength = message.length as u8;
+	if let Some(location) = info.location()
+	{
+		let mut file = TruncatingWriter { buffer: &mut record.file, length: 0 };
+		let _ = file.write_str(location.file());
+		record.fileLength = file.length as u8;
+		record.line = location.line();
+		record.column = location.column();
+	}
+	storeAndReset(&record)
+}
+
+#[exception]
+unsafe fn HardFault(frame: &ExceptionFrame) -> !
+{
+	let scb = unsafe { &*SCB::PTR };
+	let mut record = CrashRecord::new(CrashKind::HardFault);
+	record.cfsr = scb.cfsr.read();
+	record.hfsr = scb.hfsr.read();
+	record.mmfar = scb.mmfar.read();
+	record.bfar = scb.bfar.read();
+	record.pc = frame.pc();
+	record.lr = frame.lr();
+	record.xpsr = frame.xpsr();
+	storeAndReset(&record)
+}
+
+/// Formats into a fixed buffer, keeping as much as fits
+struct TruncatingWriter<'a>
+{
+	buffer: &'a mut [u8],
+	length: usize,
+}
+
+impl Write for TruncatingWriter<'_>
+{
+	fn write_str(&mut self, string: &str) -> fmt::Result
+	{
+		let remaining = self.buffer.len() - self.length;
+		let byteCount = string.len().min(remaining);
+		self.buffer[self.length..self.length + byteCount].copy_from_slice(&string.as_bytes()[0..byteCount]);
+		self.length += byteCount;
+		if byteCount < string.len()
+		{
+			Err(fmt::Error)
+		}
+		else
+		{
+			Ok(())
+		}
+	}
+}
@@ -0,0 +1,233 @@
+// SPDX-License-Identifier: BSD-3-Clause
+
+/// How long the line has to be quiet either side of an escape sequence, in milliseconds (Hayes' S12 default)
+pub const DEFAULT_GUARD_TIME: u64 = 1000;
+/// Number of escape characters that make up an escape sequence
+pub const ESCAPE_LENGTH: usize = 3;
+/// The character an escape sequence is made of
+pub const ESCAPE_CHARACTER: u8 = b'+';
+
+/// What running out the guard time after the last byte from the host turned out to mean
+#[derive(Clone, Copy)]
+pub enum GuardExpiry
+{
+	/// A complete escape sequence went unfollowed, so the host wants command mode
+	Escape,
+	/// The given number of held back escape characters weren't an escape sequence after all, and need sending on
+	Release(usize),
+	/// There was nothing waiting on the guard time
+	Nothing,
+}
+
+/// Picks out a Hayes style guarded escape sequence (quiet, `+++`, quiet) from the stream of data from the host.
+/// Times are in milliseconds from any fixed point
+pub struct EscapeDetector
+{
+	guardTime: u64,
+	lastByte: Option<u64>,
+	held: usize,
+}
+
+impl EscapeDetector
+{
+	pub const fn new(guardTime: u64) -> Self
+	{
+		Self { guardTime, lastByte: None, held: 0 }
+	}
+
+	/// Run data from the host through the detector, writing out what should go on to the target and returning
+	/// how much that is. Escape characters that might be the start of an escape sequence are held back until
+	/// it's clear they aren't, so the output needs room for ESCAPE_LENGTH more bytes than the data
+	pub fn feed(&mut self, data: &[u8], now: u64, output: &mut [u8]) -> usize
+	{
+		let mut length = 0;
+		for &byte in data
+		{
+			let quietFor = self.lastByte.map_or(u64::MAX, |lastByte| now.saturating_sub(lastByte));
+			// Anything still held from before a full guard time went by can't be part of what comes next
+			if self.held != 0 && quietFor >= self.guardTime
+			{
+				length += self.release(&mut output[length..]);
+			}
+
+			// An escape sequence has to start after the line's been quiet, and carry on without any pauses
+			let continuesEscape = byte == ESCAPE_CHARACTER && self.held < ESCAPE_LENGTH &&
+				(quietFor >= self.guardTime) == (self.held == 0);
+			if continuesEscape
+			{
+				self.held += 1;
+			}
+			else
+			{
+				length += self.release(&mut output[length..]);
+				output[length] = byte;
+				length += 1;
+			}
+			self.lastByte = Some(now);
+		}
+		length
+	}
+
+	/// When the guard time runs out on the escape characters being held back, if any are
+	pub fn deadline(&self) -> Option<u64>
+	{
+		match (self.held, self.lastByte)
+		{
+			(0, _) | (_, None) => None,
+			(_, Some(lastByte)) => Some(lastByte + self.guardTime),
+		}
+	}
+
+	/// Check the guard time after the last byte from the host, deciding what any held escape characters meant
+	pub fn expire(&mut self, now: u64) -> GuardExpiry
+	{
+		match self.deadline()
+		{
+			Some(deadline) if now >= deadline =>
+			{
+				let held = core::mem::take(&mut self.held);
+				if held == ESCAPE_LENGTH
+				{
+					GuardExpiry::Escape
+				}
+				else
+				{
+					GuardExpiry::Release(held)
+				}
+			}
+			_ => GuardExpiry::Nothing,
+		}
+	}
+
+	/// Start afresh as of now, for coming back from command mode. Another escape sequence needs a full guard
+	/// time of quiet first
+	pub fn restart(&mut self, now: u64)
+	{
+		self.held = 0;
+		self.lastByte = Some(now);
+	}
+
+	fn release(&mut self, output: &mut [u8]) -> usize
+	{
+		let held = core::mem::take(&mut self.held);
+		output[0..held].fill(ESCAPE_CHARACTER);
+		held
+	}
+}
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+
+	const GUARD_TIME: u64 = DEFAULT_GUARD_TIME;
+
+	fn feed(detector: &mut EscapeDetector, data: &[u8], now: u64) -> Vec<u8>
+	{
+		let mut output = vec![0u8; data.len() + ESCAPE_LENGTH];
+		let length = detector.feed(data, now, &mut output);
+		output.truncate(length);
+		output
+	}
+
+	#[test]
+	fn escapeNeedsGuardTimeAfter()
+	{
+		let mut detector = EscapeDetector::new(GUARD_TIME);
+		assert_eq!(feed(&mut detector, b"+++", 0), b"");
+		assert_eq!(detector.deadline(), Some(GUARD_TIME));
+		assert!(matches!(detector.expire(GUARD_TIME - 1), GuardExpiry::Nothing));
+		assert!(matches!(detector.expire(GUARD_TIME), GuardExpiry::Escape));
+		assert_eq!(detector.deadline(), None);
+		assert!(matches!(detector.expire(GUARD_TIME * 2), GuardExpiry::Nothing));
+	}
+
+	#[test]
+	fn dataWithinGuardTimeAfterCancelsEscape()
+	{
+		let mut detector = EscapeDetector::new(GUARD_TIME);
+		assert_eq!(feed(&mut detector, b"+++", 0), b"");
+		assert_eq!(feed(&mut detector, b"a", GUARD_TIME - 1), b"+++a");
+		assert_eq!(detector.deadline(), None);
+		assert!(matches!(detector.expire(GUARD_TIME * 2), GuardExpiry::Nothing));
+	}
+
+	#[test]
+	fn escapeNeedsGuardTimeBefore()
+	{
+		let mut detector = EscapeDetector::new(GUARD_TIME);
+		assert_eq!(feed(&mut detector, b"a", 0), b"a");
+		assert_eq!(feed(&mut detector, b"+++", GUARD_TIME - 1), b"+++");
+		assert_eq!(detector.deadline(), None);
+
+		// Straight after other data in the same packet is no different
+		let mut detector = EscapeDetector::new(GUARD_TIME);
+		assert_eq!(feed(&mut detector, b"a+++", 0), b"a+++");
+
+		// But once the line's been quiet long enough, it counts
+		assert_eq!(feed(&mut detector, b"+++", GUARD_TIME), b"");
+		assert!(matches!(detector.expire(GUARD_TIME * 2), GuardExpiry::Escape));
+	}
+
+	#[test]
+	fn escapeSplitAcrossPackets()
+	{
+		let mut detector = EscapeDetector::new(GUARD_TIME);
+		assert_eq!(feed(&mut detector, b"+", 5000), b"");
+		assert_eq!(feed(&mut detector, b"+", 5010), b"");
+		assert_eq!(feed(&mut detector, b"+", 5020), b"");
+		assert_eq!(detector.deadline(), Some(5020 + GUARD_TIME));
+		assert!(matches!(detector.expire(5020 + GUARD_TIME), GuardExpiry::Escape));
+	}
+
+	#[test]
+	fn pauseWithinEscapeStartsAgain()
+	{
+		let mut detector = EscapeDetector::new(GUARD_TIME);
+		assert_eq!(feed(&mut detector, b"++", 0), b"");
+		// Without the guard time having been checked, the next + still can't join the held ones
+		assert_eq!(feed(&mut detector, b"+", GUARD_TIME), b"++");
+		assert_eq!(feed(&mut detector, b"++", GUARD_TIME + 1), b"");
+		assert!(matches!(detector.expire(GUARD_TIME * 2 + 1), GuardExpiry::Escape));
+	}
+
+	#[test]
+	fn fourthEscapeCharacterReleasesHeld()
+	{
+		let mut detector = EscapeDetector::new(GUARD_TIME);
+		assert_eq!(feed(&mut detector, b"++++", 0), b"++++");
+		assert_eq!(detector.deadline(), None);
+
+		let mut detector = EscapeDetector::new(GUARD_TIME);
+		assert_eq!(feed(&mut detector, b"+++", 0), b"");
+		assert_eq!(feed(&mut detector, b"+", 10), b"++++");
+		assert!(matches!(detector.expire(GUARD_TIME * 2), GuardExpiry::Nothing));
+	}
+
+	#[test]
+	fn partialEscapeIsReleased()
+	{
+		let mut detector = EscapeDetector::new(GUARD_TIME);
+		assert_eq!(feed(&mut detector, b"++", 0), b"");
+		assert!(matches!(detector.expire(GUARD_TIME), GuardExpiry::Release(2)));
+		assert_eq!(detector.deadline(), None);
+
+		// Or if the guard time didn't get checked, they go out ahead of the next data
+		assert_eq!(feed(&mut detector, b"+", GUARD_TIME * 2), b"");
+		assert_eq!(feed(&mut detector, b"x", GUARD_TIME * 4), b"+x");
+	}
+
+	#[test]
+	fn restartNeedsFreshGuardTime()
+	{
+		let mut detector = EscapeDetector::new(GUARD_TIME);
+		assert_eq!(feed(&mut detector, b"++", 0), b"");
+		detector.restart(5000);
+		assert_eq!(detector.deadline(), None);
+		assert_eq!(feed(&mut detector, b"+++", 5000 + GUARD_TIME - 1), b"+++");
+
+		detector.restart(10000);
+		assert_eq!(feed(&mut detector, b"+++", 10000 + GUARD_TIME), b"");
+		assert!(matches!(detector.expire(10000 + GUARD_TIME * 2), GuardExpiry::Escape));
+	}
+}
@@ -0,0 +1,360 @@
+// SPDX-License-Identifier: BSD-3-Clause
+
+/// Largest IP datagram carried between the host and target, matching the host's Ethernet MTU
+pub const MTU: usize = 1500;
+pub const ETHERNET_HEADER_LENGTH: usize = 14;
+/// Largest Ethernet frame exchanged with the host, not counting the FCS
+pub const MAX_ETHERNET_FRAME_SIZE: usize = ETHERNET_HEADER_LENGTH + MTU;
+
+pub type MacAddress = [u8; 6];
+
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const ETHERTYPE_ARP: u16 = 0x0806;
+const ETHERTYPE_IPV6: u16 = 0x86dd;
+
+// ARP over Ethernet for IPv4, from RFC 826
+const ARP_PACKET_LENGTH: usize = 28;
+const ARP_HARDWARE_ETHERNET: u16 = 1;
+const ARP_OPERATION_REQUEST: u16 = 1;
+const ARP_OPERATION_REPLY: u16 = 2;
+
+const IPV6_HEADER_LENGTH: usize = 40;
+const IPV6_NEXT_HEADER_ICMPV6: u8 = 58;
+// Neighbour discovery messages have to arrive with the hop limit untouched, from RFC 4861
+const NDP_HOP_LIMIT: u8 = 255;
+const ICMPV6_NEIGHBOUR_SOLICITATION: u8 = 135;
+const ICMPV6_NEIGHBOUR_ADVERTISEMENT: u8 = 136;
+// Type, code, checksum, reserved and target address
+const NEIGHBOUR_MESSAGE_LENGTH: usize = 24;
+// Target link-layer address option, holding our MAC
+const NDP_OPTION_TARGET_LINK_ADDRESS: u8 = 2;
+const NDP_LINK_ADDRESS_OPTION_LENGTH: usize = 8;
+// Advertisements we send are solicited and should override whatever the host had cached
+const NEIGHBOUR_ADVERTISEMENT_FLAGS: u8 = 0x60;
+
+/// What to do with an Ethernet frame that came from the host
+pub enum HostFrame<'a>
+{
+	/// An IP datagram to send on to the target
+	Datagram(&'a [u8]),
+	/// We answered it ourselves, with a reply frame of the given length to send back to the host
+	Reply(usize),
+	/// Nothing for the target or us
+	Ignored,
+}
+
+/// Work out what to do with a frame from the host. The target is a point to point link with no MAC of its own,
+/// so we stand in for it at the Ethernet level: ARP requests and IPv6 neighbour solicitations for any address
+/// other than the host's own are answered with our MAC, and IP datagrams sent to us are passed on with the
+/// Ethernet header removed.
+pub fn fromHost<'a>(frame: &'a [u8], deviceMac: &MacAddress, reply: &mut [u8]) -> HostFrame<'a>
+{
+	if frame.len() < ETHERNET_HEADER_LENGTH
+	{
+		return HostFrame::Ignored;
+	}
+	let destination = &frame[0..6];
+	let etherType = u16::from_be_bytes([frame[12], frame[13]]);
+	let payload = &frame[ETHERNET_HEADER_LENGTH..];
+
+	// Multicasts (including broadcasts) have the bottom bit of the first byte set
+	if destination != deviceMac && destination[0] & 1 == 0
+	{
+		return HostFrame::Ignored;
+	}
+
+	match etherType
+	{
+		ETHERTYPE_ARP => arpReply(frame, deviceMac, reply).map_or(HostFrame::Ignored, HostFrame::Reply),
+		ETHERTYPE_IPV6 => match neighbourAdvertisement(frame, deviceMac, reply)
+		{
+			Some(length) => HostFrame::Reply(length),
+			None if isNeighbourDiscovery(payload) => HostFrame::Ignored,
+			None => HostFrame::Datagram(payload),
+		},
+		ETHERTYPE_IPV4 => HostFrame::Datagram(payload),
+		_ => HostFrame::Ignored,
+	}
+}
+
+/// Wrap an IP datagram from the target up in an Ethernet frame addressed from us to the host. Returns the frame
+/// length, or None if the datagram isn't IPv4 or IPv6 or doesn't fit
+pub fn toHost(datagram: &[u8], deviceMac: &MacAddress, hostMac: &MacAddress, frame: &mut [u8]) -> Option<usize>
+{
+	let etherType = match datagram.first()? >> 4
+	{
+		4 => ETHERTYPE_IPV4,
+		6 => ETHERTYPE_IPV6,
+		_ => return None,
+	};
+	let length = ETHERNET_HEADER_LENGTH + datagram.len();
+	let frame = frame.get_mut(0..length)?;
+	writeEthernetHeader(frame, hostMac, deviceMac, etherType);
+	frame[ETHERNET_HEADER_LENGTH..].copy_from_slice(datagram);
+	Some(length)
+}
+
+fn writeEthernetHeader(frame: &mut [u8], destination: &MacAddress, source: &MacAddress, etherType: u16)
+{
+	frame[0..6].copy_from_slice(destination);
+	frame[6..12].copy_from_slice(source);
+	frame[12..14].copy_from_slice(&etherType.to_be_bytes());
+}
+
+/// Build a proxy ARP reply claiming the requested address is at our MAC
+fn arpReply(frame: &[u8], deviceMac: &MacAddress, reply: &mut [u8]) -> Option<usize>
+{
+	let request = frame.get(ETHERNET_HEADER_LENGTH..ETHERNET_HEADER_LENGTH + ARP_PACKET_LENGTH)?;
+	let hardwareType = u16::from_be_bytes([request[0], request[1]]);
+	let protocolType = u16::from_be_bytes([request[2], request[3]]);
+	let operation = u16::from_be_bytes([request[6], request[7]]);
+	if hardwareType != ARP_HARDWARE_ETHERNET || protocolType != ETHERTYPE_IPV4 || request[4] != 6 ||
+		request[5] != 4 || operation != ARP_OPERATION_REQUEST
+	{
+		return None;
+	}
+
+	let senderMac = &request[8..14];
+	let senderAddress = &request[14..18];
+	let targetAddress = &request[24..28];
+	// Probes (from 0.0.0.0) and announcements (for the sender's own address) are the host checking nobody else
+	// has its address, and answering them would make it think there's a conflict
+	if senderAddress == [0; 4] || senderAddress == targetAddress
+	{
+		return None;
+	}
+
+	let length = ETHERNET_HEADER_LENGTH + ARP_PACKET_LENGTH;
+	let reply = reply.get_mut(0..length)?;
+	let hostMac: MacAddress = senderMac.try_into().ok()?;
+	writeEthernetHeader(reply, &hostMac, deviceMac, ETHERTYPE_ARP);
+	let arp = &mut reply[ETHERNET_HEADER_LENGTH..];
+	arp[0..6].copy_from_slice(&request[0..6]);
+	arp[6..8].copy_from_slice(&ARP_OPERATION_REPLY.to_be_bytes());
+	arp[8..14].copy_from_slice(deviceMac);
+	arp[14..18].copy_from_slice(targetAddress);
+	arp[18..24].copy_from_slice(senderMac);
+	arp[24..28].copy_from_slice(senderAddress);
+	Some(length)
+}
+
+/// Check whether an IPv6 datagram is a neighbour solicitation, which is for us rather than the target
+fn isNeighbourDiscovery(datagram: &[u8]) -> bool
+{
+	datagram.len() > IPV6_HEADER_LENGTH && datagram[6] == IPV6_NEXT_HEADER_ICMPV6 &&
+		datagram[IPV6_HEADER_LENGTH] == ICMPV6_NEIGHBOUR_SOLICITATION
+}
+
+/// Build a neighbour advertisement claiming the solicited address is at our MAC
+fn neighbourAdvertisement(frame: &[u8], deviceMac: &MacAddress, reply: &mut [u8]) -> Option<usize>
+{
+	let datagram = &frame[ETHERNET_HEADER_LENGTH..];
+	if !isNeighbourDiscovery(datagram) || datagram[7] != NDP_HOP_LIMIT
+	{
+		return None;
+	}
+	let solicitation = datagram.get(IPV6_HEADER_LENGTH..IPV6_HEADER_LENGTH + NEIGHBOUR_MESSAGE_LENGTH)?;
+	let sourceAddress = &datagram[8..24];
+	let targetAddress = &solicitation[8..24];
+	// Solicitations from the unspecified address are duplicate address detection for the host's own address,
+	// and answering them would make it think there's a conflict
+	if sourceAddress == [0; 16] || sourceAddress == targetAddress
+	{
+		return None;
+	}
+
+	let hostMac: MacAddress = frame[6..12].try_into().ok()?;
+	let messageLength = NEIGHBOUR_MESSAGE_LENGTH + NDP_LINK_ADDRESS_OPTION_LENGTH;
+	let length = ETHERNET_HEADER_LENGTH + IPV6_HEADER_LENGTH + messageLength;
+	let reply = reply.get_mut(0..length)?;
+	writeEthernetHeader(reply, &hostMac, deviceMac, ETHERTYPE_IPV6);
+
+	// Answer from the solicited address back to whoever asked
+	let ipv6 = &mut reply[ETHERNET_HEADER_LENGTH..];
+	ipv6[0..4].copy_from_slice(&[0x60, 0, 0, 0]);
+	ipv6[4..6].copy_from_slice(&(messageLength as u16).to_be_bytes());
+	ipv6[6] = IPV6_NEXT_HEADER_ICMPV6;
+	ipv6[7] = NDP_HOP_LIMIT;
+	ipv6[8..24].copy_from_slice(targetAddress);
+	ipv6[24..40].copy_from_slice(sourceAddress);
+
+	let (header, message) = ipv6.split_at_mut(IPV6_HEADER_LENGTH);
+	message[0] = ICMPV6_NEIGHBOUR_ADVERTISEMENT;
+	message[1] = 0;
+	message[2..4].fill(0);
+	message[4] = NEIGHBOUR_ADVERTISEMENT_FLAGS;
+	message[5..8].fill(0);
+	message[8..24].copy_from_slice(targetAddress);
+	message[24] = NDP_OPTION_TARGET_LINK_ADDRESS;
+	message[25] = (NDP_LINK_ADDRESS_OPTION_LENGTH / 8) as u8;
+	message[26..32].copy_from_slice(deviceMac);
+	let checksum = icmpv6Checksum(&header[8..24], &header[24..40], message);
+	message[2..4].copy_from_slice(&checksum.to_be_bytes());
+	Some(length)
+}
+
+/// Internet checksum over an ICMPv6 message and the IPv6 pseudo-header, from RFC 4443
+fn icmpv6Checksum(source: &[u8], destination: &[u8], message: &[u8]) -> u16
+{
+	let length = message.len() as u32;
+	let pseudoHeader = [(length >> 16) as u16, length as u16, 0, IPV6_NEXT_HEADER_ICMPV6 as u16];
+	let words = source.chunks(2)
+		.chain(destination.chunks(2))
+		.chain(message.chunks(2))
+		.map(|word| u16::from_be_bytes([word[0], word.get(1).copied().unwrap_or(0)]))
+		.chain(pseudoHeader);
+	let mut sum = words.fold(0u32, |sum, word| sum + word as u32);
+	while sum > 0xffff
+	{
+		sum = (sum & 0xffff) + (sum >> 16);
+	}
+	!(sum as u16)
+}
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+
+	const DEVICE_MAC: MacAddress = [0x02, 0, 0, 0, 0, 0x01];
+	const HOST_MAC: MacAddress = [0x02, 0, 0, 0, 0, 0x02];
+	const BROADCAST_MAC: MacAddress = [0xff; 6];
+	const HOST_IPV4: [u8; 4] = [192, 168, 7, 1];
+	const TARGET_IPV4: [u8; 4] = [192, 168, 7, 2];
+	// fe80::2 for the host and fe80::1 for the target
+	const HOST_IPV6: [u8; 16] = [0xfe, 0x80, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2];
+	const TARGET_IPV6: [u8; 16] = [0xfe, 0x80, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1];
+	// The solicited-node multicast address for the target's, and the MAC it maps to
+	const SOLICITED_NODE_IPV6: [u8; 16] = [0xff, 0x02, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0xff, 0, 0, 1];
+	const SOLICITED_NODE_MAC: MacAddress = [0x33, 0x33, 0xff, 0, 0, 1];
+
+	fn frame(destination: &MacAddress, etherType: u16, payload: &[u8]) -> Vec<u8>
+	{
+		let mut frame = Vec::new();
+		frame.extend_from_slice(destination);
+		frame.extend_from_slice(&HOST_MAC);
+		frame.extend_from_slice(&etherType.to_be_bytes());
+		frame.extend_from_slice(payload);
+		frame
+	}
+
+	fn arpRequest(senderAddress: [u8; 4], targetAddress: [u8; 4]) -> Vec<u8>
+	{
+		let mut arp = vec![0, 1, 0x08, 0x00, 6, 4, 0, 1];
+		arp.extend_from_slice(&HOST_MAC);
+		arp.extend_from_slice(&senderAddress);
+		arp.extend_from_slice(&[0; 6]);
+		arp.extend_from_slice(&targetAddress);
+		frame(&BROADCAST_MAC, ETHERTYPE_ARP, &arp)
+	}
+
+	fn neighbourSolicitation(sourceAddress: [u8; 16], targetAddress: [u8; 16], hopLimit: u8) -> Vec<u8>
+	{
+		// A solicitation with its source link-layer address option, the checksum of which we don't check
+		let mut ipv6 = vec![0x60, 0, 0, 0, 0, 32, IPV6_NEXT_HEADER_ICMPV6, hopLimit];
+		ipv6.extend_from_slice(&sourceAddress);
+		ipv6.extend_from_slice(&SOLICITED_NODE_IPV6);
+		ipv6.extend_from_slice(&[ICMPV6_NEIGHBOUR_SOLICITATION, 0, 0, 0, 0, 0, 0, 0]);
+		ipv6.extend_from_slice(&targetAddress);
+		ipv6.extend_from_slice(&[1, 1]);
+		ipv6.extend_from_slice(&HOST_MAC);
+		frame(&SOLICITED_NODE_MAC, ETHERTYPE_IPV6, &ipv6)
+	}
+
+	fn reply(frame: &[u8]) -> Option<Vec<u8>>
+	{
+		let mut reply = [0u8; MAX_ETHERNET_FRAME_SIZE];
+		match fromHost(frame, &DEVICE_MAC, &mut reply)
+		{
+			HostFrame::Reply(length) => Some(reply[0..length].to_vec()),
+			_ => None,
+		}
+	}
+
+	#[test]
+	fn answersArpRequests()
+	{
+		let mut expected = frame(&HOST_MAC, ETHERTYPE_ARP, &[0, 1, 0x08, 0x00, 6, 4, 0, 2]);
+		expected[6..12].copy_from_slice(&DEVICE_MAC);
+		expected.extend_from_slice(&DEVICE_MAC);
+		expected.extend_from_slice(&TARGET_IPV4);
+		expected.extend_from_slice(&HOST_MAC);
+		expected.extend_from_slice(&HOST_IPV4);
+		assert_eq!(reply(&arpRequest(HOST_IPV4, TARGET_IPV4)), Some(expected));
+	}
+
+	#[test]
+	fn ignoresArpProbesAndAnnouncements()
+	{
+		assert!(matches!(fromHost(&arpRequest([0; 4], HOST_IPV4), &DEVICE_MAC, &mut [0; 64]), HostFrame::Ignored));
+		assert!(matches!(fromHost(&arpRequest(HOST_IPV4, HOST_IPV4), &DEVICE_MAC, &mut [0; 64]), HostFrame::Ignored));
+
+		// Nor is anything but a request answered
+		let mut arpReply = arpRequest(HOST_IPV4, TARGET_IPV4);
+		arpReply[ETHERNET_HEADER_LENGTH + 7] = 2;
+		assert!(matches!(fromHost(&arpReply, &DEVICE_MAC, &mut [0; 64]), HostFrame::Ignored));
+	}
+
+	#[test]
+	fn answersNeighbourSolicitations()
+	{
+		let mut expected = frame(&HOST_MAC, ETHERTYPE_IPV6, &[0x60, 0, 0, 0, 0, 32, IPV6_NEXT_HEADER_ICMPV6, 255]);
+		expected[6..12].copy_from_slice(&DEVICE_MAC);
+		expected.extend_from_slice(&TARGET_IPV6);
+		expected.extend_from_slice(&HOST_IPV6);
+		// Solicited and override flags, and a checksum worked out independently
+		expected.extend_from_slice(&[ICMPV6_NEIGHBOUR_ADVERTISEMENT, 0, 0x18, 0x1c, 0x60, 0, 0, 0]);
+		expected.extend_from_slice(&TARGET_IPV6);
+		expected.extend_from_slice(&[2, 1]);
+		expected.extend_from_slice(&DEVICE_MAC);
+		assert_eq!(reply(&neighbourSolicitation(HOST_IPV6, TARGET_IPV6, 255)), Some(expected));
+	}
+
+	#[test]
+	fn ignoresDuplicateAddressDetection()
+	{
+		let mut reply = [0u8; MAX_ETHERNET_FRAME_SIZE];
+		for solicitation in [
+			neighbourSolicitation([0; 16], HOST_IPV6, 255),
+			neighbourSolicitation(HOST_IPV6, HOST_IPV6, 255),
+			// And solicitations that have been routed aren't to be believed
+			neighbourSolicitation(HOST_IPV6, TARGET_IPV6, 64),
+		]
+		{
+			// None of them go to the target either
+			assert!(matches!(fromHost(&solicitation, &DEVICE_MAC, &mut reply), HostFrame::Ignored));
+		}
+	}
+
+	#[test]
+	fn passesOnDatagrams()
+	{
+		let ipv4 = [0x45, 0, 0, 20, 0, 0, 0, 0, 64, 17, 0, 0, 192, 168, 7, 1, 192, 168, 7, 2];
+		let datagram = frame(&DEVICE_MAC, ETHERTYPE_IPV4, &ipv4);
+		assert!(matches!(fromHost(&datagram, &DEVICE_MAC, &mut []), HostFrame::Datagram(data) if data == ipv4));
+
+		// Unless they're for some other MAC, or something other than IP
+		let elsewhere = frame(&HOST_MAC, ETHERTYPE_IPV4, &ipv4);
+		assert!(matches!(fromHost(&elsewhere, &DEVICE_MAC, &mut []), HostFrame::Ignored));
+		let other = frame(&DEVICE_MAC, 0x88cc, &ipv4);
+		assert!(matches!(fromHost(&other, &DEVICE_MAC, &mut []), HostFrame::Ignored));
+		assert!(matches!(fromHost(&datagram[0..13], &DEVICE_MAC, &mut []), HostFrame::Ignored));
+	}
+
+	#[test]
+	fn wrapsDatagramsForHost()
+	{
+		let mut output = [0u8; MAX_ETHERNET_FRAME_SIZE];
+		for (datagram, etherType) in [(&[0x45, 1, 2, 3][..], ETHERTYPE_IPV4), (&[0x60, 4, 5][..], ETHERTYPE_IPV6)]
+		{
+			let length = toHost(datagram, &DEVICE_MAC, &HOST_MAC, &mut output).unwrap();
+			let mut expected = frame(&HOST_MAC, etherType, datagram);
+			expected[6..12].copy_from_slice(&DEVICE_MAC);
+			assert_eq!(output[0..length], expected[..]);
+		}
+
+		assert_eq!(toHost(&[0x50, 0, 0], &DEVICE_MAC, &HOST_MAC, &mut output), None);
+		assert_eq!(toHost(&[], &DEVICE_MAC, &HOST_MAC, &mut output), None);
+		assert_eq!(toHost(&[0x45; MTU + 1], &DEVICE_MAC, &HOST_MAC, &mut output), None);
+	}
+}
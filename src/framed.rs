@@ -0,0 +1,165 @@
+// SPDX-License-Identifier: BSD-3-Clause
+
+use defmt::error;
+use embassy_futures::select::{Either3, select3};
+use embassy_stm32::mode::Async;
+use embassy_stm32::usart::{Uart, UartRx, UartTx};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::channel::{Receiver, Sender};
+
+use crate::capture;
+use crate::framing::{self, DecodeResult, FrameDecoder, FramingConfig, MAX_ENCODED_FRAME_SIZE, MAX_FRAME_SIZE};
+use crate::packet::{MAX_PACKET_SIZE, Packet};
+use crate::pcapng::PacketDirection;
+use crate::serial::SerialEvent;
+use crate::types::{ReceiveRequest, SerialCommand, TransmitRequest};
+use crate::watchdog::{self, Watched};
+
+/// Conduit data as frames until a line coding change or command comes in that needs the serial task's attention.
+///
+/// Data from the UART is split into frames when the line goes idle (or on the configured delimiter, which is
+/// kept as the last byte of the frame) and each is encoded and sent on to the host. Frames longer than the frame
+/// buffer are split. Data from the host is decoded and only whole frames that pass their CRC
+/// check (if enabled) are written to the UART, with anything malformed discarded.
+pub async fn run(
+	serialPort: &mut Uart<'static, Async>,
+	config: &FramingConfig,
+	transmitChannel: &Sender<'static, CriticalSectionRawMutex, TransmitRequest, 1>,
+	receiveChannel: &Receiver<'static, CriticalSectionRawMutex, ReceiveRequest, 1>,
+	serialCommands: &Receiver<'static, CriticalSectionRawMutex, SerialCommand, 2>,
+) -> SerialEvent
+{
+	let (transmitter, receiver) = serialPort.split_ref();
+	// Host data is handled here rather than by the serial task so a partially received frame isn't lost to it
+	match select3
+	(
+		receiveFrames(receiver, config, transmitChannel),
+		transmitFrames(transmitter, config, receiveChannel),
+		serialCommands.receive(),
+	).await
+	{
+		Either3::First(_) => unreachable!("Frame receiver never completes"),
+		Either3::Second(request) => SerialEvent::Request(request),
+		Either3::Third(command) => SerialEvent::Command(command),
+	}
+}
+
+async fn receiveFrames(
+	receiver: &mut UartRx<'static, Async>,
+	config: &FramingConfig,
+	transmitChannel: &Sender<'static, CriticalSectionRawMutex, TransmitRequest, 1>,
+)
+{
+	let mut frame = [0u8; MAX_FRAME_SIZE];
+	let mut length = 0;
+
+	loop
+	{
+		watchdog::heartbeat(Watched::Serial);
+		let byteCount = match watchdog::excused(Watched::Serial, receiver.read_until_idle(&mut frame[length..])).await
+		{
+			Ok(byteCount) => byteCount,
+			Err(error) =>
+			{
+				// Whatever we had of the current frame can't be trusted any more
+				error!("Serial interface read failed, {}", error);
+				length = 0;
+				continue;
+			}
+		};
+		capture::data(PacketDirection::Inbound, &frame[length..length + byteCount]);
+		let end = length + byteCount;
+
+		match config.delimiter
+		{
+			// The read finishing means either the line went idle or the frame buffer filled, and either ends the frame
+			None =>
+			{
+				sendFrame(&frame[0..end], config, transmitChannel).await;
+				length = 0;
+			}
+			Some(delimiter) =>
+			{
+				// Send every frame that's now complete, only searching the new data for delimiters
+				let mut start = 0;
+				while let Some(position) = frame[length..end].iter().position(|byte| *byte == delimiter)
+				{
+					let frameEnd = length + position + 1;
+					sendFrame(&frame[start..frameEnd], config, transmitChannel).await;
+					start = frameEnd;
+					length = frameEnd;
+				}
+
+				// Keep what's left as the start of the next frame, unless it fills the buffer with no delimiter
+				if start == 0 && end == frame.len()
+				{
+					sendFrame(&frame, config, transmitChannel).await;
+					length = 0;
+				}
+				else
+				{
+					frame.copy_within(start..end, 0);
+					length = end - start;
+				}
+			}
+		}
+	}
+}
+
+async fn sendFrame(
+	frame: &[u8],
+	config: &FramingConfig,
+	transmitChannel: &Sender<'static, CriticalSectionRawMutex, TransmitRequest, 1>,
+)
+{
+	if frame.is_empty()
+	{
+		return;
+	}
+
+	let mut encodedFrame = [0u8; MAX_ENCODED_FRAME_SIZE];
+	let byteCount = framing::encodeFrame(config, frame, &mut encodedFrame)
+		.expect("Encoded frames always fit in the buffer");
+	// The encoding is self-delimiting, so long frames can go to the host split over several packets. Each one
+	// waits on the host reading out the last
+	for chunk in encodedFrame[0..byteCount].chunks(MAX_PACKET_SIZE)
+	{
+		watchdog::excused(Watched::Serial, async
+		{
+			transmitChannel
+				.send(TransmitRequest::Data(Packet::copyFrom(chunk).await))
+				.await;
+		}).await;
+	}
+}
+
+async fn transmitFrames(
+	transmitter: &mut UartTx<'static, Async>,
+	config: &FramingConfig,
+	receiveChannel: &Receiver<'static, CriticalSectionRawMutex, ReceiveRequest, 1>,
+) -> ReceiveRequest
+{
+	let mut decoder: FrameDecoder = FrameDecoder::new(*config);
+
+	loop
+	{
+		watchdog::heartbeat(Watched::Serial);
+		let data = match watchdog::excused(Watched::Serial, receiveChannel.receive()).await
+		{
+			ReceiveRequest::Data(data) => data,
+			request => return request,
+		};
+
+		for byte in data.iter()
+		{
+			match decoder.decode(*byte)
+			{
+				DecodeResult::Pending => {}
+				DecodeResult::Frame(_) =>
+					transmitter.write(decoder.frame()).await.expect("Serial interface writes never fail"),
+				DecodeResult::Error =>
+					error!("Discarding malformed frame from host"),
+			}
+		}
+	}
+}
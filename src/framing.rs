@@ -0,0 +1,538 @@
+// SPDX-License-Identifier: BSD-3-Clause
+
+/// Largest frame payload we handle, excluding any CRC
+pub const MAX_FRAME_SIZE: usize = 256;
+/// Largest possible encoded frame: the payload and CRC, SLIP's worst case of every byte escaped, and delimiters
+pub const MAX_ENCODED_FRAME_SIZE: usize = ((MAX_FRAME_SIZE + CRC_LENGTH) * 2) + 2;
+
+const CRC_LENGTH: usize = 2;
+
+// COBS frames are terminated by a single 0 byte
+const COBS_DELIMITER: u8 = 0x00;
+// Largest code byte COBS uses, for a block of 254 non-zero bytes with no implied 0 after
+const COBS_MAX_CODE: u8 = 0xff;
+
+// SLIP special characters, from RFC 1055
+const SLIP_END: u8 = 0xc0;
+const SLIP_ESC: u8 = 0xdb;
+const SLIP_ESC_END: u8 = 0xdc;
+const SLIP_ESC_ESC: u8 = 0xdd;
+
+#[repr(u8)]
+#[derive(Clone, Copy, PartialEq)]
+pub enum FrameEncoding
+{
+	Cobs = 1,
+	Slip = 2,
+}
+
+/// How frames are delimited on the UART and encoded for the host
+#[derive(Clone, Copy)]
+pub struct FramingConfig
+{
+	pub encoding: FrameEncoding,
+	/// Whether frames carry a trailing CRC-16 in both directions
+	pub crc: bool,
+	/// Byte that ends a frame coming from the UART, or None to end frames when the line goes idle
+	pub delimiter: Option<u8>,
+}
+
+impl FramingConfig
+{
+	/// Decode a framing configuration from a control request. The low byte of the value selects the encoding,
+	/// bit 8 enables CRCs and bit 9 switches from idle to delimiter splitting with the delimiter in the index
+	pub fn fromRequest(value: u16, index: u16) -> Option<Self>
+	{
+		let encoding = match value & 0xff
+		{
+			1 => FrameEncoding::Cobs,
+			2 => FrameEncoding::Slip,
+			_ => return None,
+		};
+		let delimiter = if value & 0x0200 != 0
+		{
+			Some(u8::try_from(index).ok()?)
+		}
+		else
+		{
+			None
+		};
+
+		Some(Self { encoding, crc: value & 0x0100 != 0, delimiter })
+	}
+}
+
+/// CRC-16/CCITT-FALSE (polynomial 0x1021, initial value 0xffff)
+pub fn crc16(data: &[u8]) -> u16
+{
+	data.iter().fold(0xffff, |crc, byte|
+	{
+		(0..8).fold(crc ^ ((*byte as u16) << 8), |crc, _|
+		{
+			if crc & 0x8000 != 0
+			{
+				(crc << 1) ^ 0x1021
+			}
+			else
+			{
+				crc << 1
+			}
+		})
+	})
+}
+
+/// Encode a frame, appending the CRC (little endian) first if enabled. Returns the encoded length, or None
+/// if the output buffer is too small
+pub fn encodeFrame(config: &FramingConfig, frame: &[u8], output: &mut [u8]) -> Option<usize>
+{
+	let crc = crc16(frame).to_le_bytes();
+	let crc = if config.crc { &crc[..] } else { &crc[0..0] };
+	let bytes = frame.iter().chain(crc.iter()).copied();
+
+	match config.encoding
+	{
+		FrameEncoding::Cobs => cobsEncode(bytes, output),
+		FrameEncoding::Slip => slipEncode(bytes, output),
+	}
+}
+
+fn cobsEncode(bytes: impl Iterator<Item = u8>, output: &mut [u8]) -> Option<usize>
+{
+	// Each block starts with a code byte giving the distance to the next 0, which we fill in once we know it
+	let mut codeOffset = 0;
+	let mut code = 1;
+	let mut offset = 1;
+
+	for byte in bytes
+	{
+		// A full block only gets closed off once there's more to come, so a frame ending on one doesn't gain
+		// an empty block after it
+		if code == COBS_MAX_CODE
+		{
+			*output.get_mut(codeOffset)? = code;
+			codeOffset = offset;
+			offset += 1;
+			code = 1;
+		}
+
+		if byte == 0
+		{
+			*output.get_mut(codeOffset)? = code;
+			codeOffset = offset;
+			offset += 1;
+			code = 1;
+		}
+		else
+		{
+			*output.get_mut(offset)? = byte;
+			offset += 1;
+			code += 1;
+		}
+	}
+
+	*output.get_mut(codeOffset)? = code;
+	*output.get_mut(offset)? = COBS_DELIMITER;
+	Some(offset + 1)
+}
+
+fn slipEncode(bytes: impl Iterator<Item = u8>, output: &mut [u8]) -> Option<usize>
+{
+	// Start with an END to flush out any line noise the receiver may have picked up
+	*output.get_mut(0)? = SLIP_END;
+	let mut offset = 1;
+
+	for byte in bytes
+	{
+		let escaped = match byte
+		{
+			SLIP_END => Some(SLIP_ESC_END),
+			SLIP_ESC => Some(SLIP_ESC_ESC),
+			_ => None,
+		};
+		match escaped
+		{
+			Some(escaped) =>
+			{
+				output.get_mut(offset..offset + 2)?.copy_from_slice(&[SLIP_ESC, escaped]);
+				offset += 2;
+			}
+			None =>
+			{
+				*output.get_mut(offset)? = byte;
+				offset += 1;
+			}
+		}
+	}
+
+	*output.get_mut(offset)? = SLIP_END;
+	Some(offset + 1)
+}
+
+pub enum DecodeResult
+{
+	/// More bytes are needed to complete the frame
+	Pending,
+	/// A frame of the given length is complete and can be read with FrameDecoder::frame()
+	Frame(usize),
+	/// The frame was malformed, too long, or failed its CRC check, and has been discarded
+	Error,
+}
+
+/// Incrementally decodes frames from a byte stream, holding frames of up to CAPACITY bytes including any CRC
+pub struct FrameDecoder<const CAPACITY: usize = { MAX_FRAME_SIZE + CRC_LENGTH }>
+{
+	config: FramingConfig,
+	buffer: [u8; CAPACITY],
+	length: usize,
+	// Length of the last completed frame, which stays valid until the next byte is decoded
+	frameLength: usize,
+	// Set when the current frame has gone wrong, so the rest of it is discarded up to the next delimiter
+	discarding: bool,
+	// COBS: bytes left in the current block, and whether a 0 is implied at the end of it
+	remaining: u8,
+	pendingZero: bool,
+	// SLIP: whether the previous byte was an ESC
+	escaped: bool,
+}
+
+impl<const CAPACITY: usize> FrameDecoder<CAPACITY>
+{
+	pub const fn new(config: FramingConfig) -> Self
+	{
+		Self
+		{
+			config,
+			buffer: [0; CAPACITY],
+			length: 0,
+			frameLength: 0,
+			discarding: false,
+			remaining: 0,
+			pendingZero: false,
+			escaped: false,
+		}
+	}
+
+	/// The most recently completed frame
+	pub fn frame(&self) -> &[u8]
+	{
+		&self.buffer[0..self.frameLength]
+	}
+
+	/// Feed the next byte of the stream in
+	pub fn decode(&mut self, byte: u8) -> DecodeResult
+	{
+		match self.config.encoding
+		{
+			FrameEncoding::Cobs => self.decodeCobs(byte),
+			FrameEncoding::Slip => self.decodeSlip(byte),
+		}
+	}
+
+	fn decodeCobs(&mut self, byte: u8) -> DecodeResult
+	{
+		if byte == COBS_DELIMITER
+		{
+			// A delimiter part way through a block means the frame got truncated
+			if self.remaining != 0
+			{
+				self.discarding = true;
+			}
+			return self.endFrame();
+		}
+
+		if self.remaining == 0
+		{
+			// This is a code byte, so first put down the 0 implied by the end of the previous block
+			if self.pendingZero
+			{
+				self.push(0);
+			}
+			self.remaining = byte - 1;
+			self.pendingZero = byte != COBS_MAX_CODE;
+		}
+		else
+		{
+			self.push(byte);
+			self.remaining -= 1;
+		}
+		DecodeResult::Pending
+	}
+
+	fn decodeSlip(&mut self, byte: u8) -> DecodeResult
+	{
+		if self.escaped
+		{
+			self.escaped = false;
+			match byte
+			{
+				SLIP_ESC_END => self.push(SLIP_END),
+				SLIP_ESC_ESC => self.push(SLIP_ESC),
+				_ => self.discarding = true,
+			}
+		}
+		else
+		{
+			match byte
+			{
+				SLIP_END => return self.endFrame(),
+				SLIP_ESC => self.escaped = true,
+				_ => self.push(byte),
+			}
+		}
+		DecodeResult::Pending
+	}
+
+	fn push(&mut self, byte: u8)
+	{
+		match self.buffer.get_mut(self.length)
+		{
+			Some(slot) =>
+			{
+				*slot = byte;
+				self.length += 1;
+			}
+			None => self.discarding = true,
+		}
+	}
+
+	fn endFrame(&mut self) -> DecodeResult
+	{
+		let discarding = self.discarding;
+		let length = self.length;
+		self.discarding = false;
+		self.remaining = 0;
+		self.pendingZero = false;
+		self.escaped = false;
+		self.length = 0;
+
+		if discarding
+		{
+			return DecodeResult::Error;
+		}
+		// Back to back delimiters are just idle line, not empty frames
+		if length == 0
+		{
+			return DecodeResult::Pending;
+		}
+
+		if self.config.crc
+		{
+			if length < CRC_LENGTH
+			{
+				return DecodeResult::Error;
+			}
+			let payloadLength = length - CRC_LENGTH;
+			let crc = u16::from_le_bytes([self.buffer[payloadLength], self.buffer[payloadLength + 1]]);
+			if crc != crc16(&self.buffer[0..payloadLength])
+			{
+				return DecodeResult::Error;
+			}
+			self.frameLength = payloadLength;
+			DecodeResult::Frame(payloadLength)
+		}
+		else
+		{
+			self.frameLength = length;
+			DecodeResult::Frame(length)
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+
+	const COBS: FramingConfig = FramingConfig { encoding: FrameEncoding::Cobs, crc: false, delimiter: None };
+	const SLIP: FramingConfig = FramingConfig { encoding: FrameEncoding::Slip, crc: false, delimiter: None };
+
+	fn encode(config: &FramingConfig, frame: &[u8]) -> Vec<u8>
+	{
+		let mut output = [0u8; MAX_ENCODED_FRAME_SIZE];
+		let length = encodeFrame(config, frame, &mut output).unwrap();
+		output[0..length].to_vec()
+	}
+
+	// Feed a stream through a decoder, collecting each frame it completes or None for each error
+	fn decode<const CAPACITY: usize>(decoder: &mut FrameDecoder<CAPACITY>, stream: &[u8]) -> Vec<Option<Vec<u8>>>
+	{
+		stream.iter().filter_map(|byte|
+		{
+			match decoder.decode(*byte)
+			{
+				DecodeResult::Pending => None,
+				DecodeResult::Frame(length) =>
+				{
+					assert_eq!(decoder.frame().len(), length);
+					Some(Some(decoder.frame().to_vec()))
+				}
+				DecodeResult::Error => Some(None),
+			}
+		}).collect()
+	}
+
+	fn roundtrip(config: &FramingConfig, frame: &[u8])
+	{
+		let encoded = encode(config, frame);
+		assert_eq!(decode(&mut FrameDecoder::<{ MAX_FRAME_SIZE + CRC_LENGTH }>::new(*config), &encoded),
+			[Some(frame.to_vec())]);
+	}
+
+	#[test]
+	fn crcMatchesCheckValue()
+	{
+		assert_eq!(crc16(b"123456789"), 0x29b1);
+		assert_eq!(crc16(&[]), 0xffff);
+	}
+
+	#[test]
+	fn cobsEncodesZeros()
+	{
+		assert_eq!(encode(&COBS, &[0x11, 0x00, 0x22]), [0x02, 0x11, 0x02, 0x22, 0x00]);
+		assert_eq!(encode(&COBS, &[0x00]), [0x01, 0x01, 0x00]);
+		assert_eq!(encode(&COBS, &[0x00, 0x00]), [0x01, 0x01, 0x01, 0x00]);
+		assert_eq!(encode(&COBS, &[0x11, 0x22, 0x00]), [0x03, 0x11, 0x22, 0x01, 0x00]);
+	}
+
+	#[test]
+	fn cobsEncodesFullBlock()
+	{
+		// Exactly 254 non-zero bytes fill a block that ends the frame with no implied 0
+		let frame: Vec<u8> = (1..=254).collect();
+		let encoded = encode(&COBS, &frame);
+		assert_eq!(encoded.len(), 256);
+		assert_eq!(encoded[0], 0xff);
+		assert_eq!(&encoded[1..255], &frame[..]);
+		assert_eq!(encoded[255], 0x00);
+		roundtrip(&COBS, &frame);
+
+		// One more byte needs a second block
+		let frame: Vec<u8> = (1..=255).collect();
+		let encoded = encode(&COBS, &frame);
+		assert_eq!(&encoded[254..], &[0xfe, 0x02, 0xff, 0x00]);
+		roundtrip(&COBS, &frame);
+
+		// As does a 0 straight after a full block
+		let mut frame: Vec<u8> = (1..=254).collect();
+		frame.push(0);
+		assert_eq!(&encode(&COBS, &frame)[255..], &[0x01, 0x01, 0x00]);
+		roundtrip(&COBS, &frame);
+	}
+
+	#[test]
+	fn cobsRoundtrips()
+	{
+		for frame in [&b"a"[..], &[0x00], &[0x00, 0x00, 0x00], &[0x11, 0x00, 0x00, 0x22], &[0xff; MAX_FRAME_SIZE]]
+		{
+			roundtrip(&COBS, frame);
+		}
+		let frame: Vec<u8> = (0..MAX_FRAME_SIZE).map(|index| (index % 7) as u8).collect();
+		roundtrip(&COBS, &frame);
+	}
+
+	#[test]
+	fn slipEscapesSpecialBytes()
+	{
+		assert_eq!(encode(&SLIP, &[0x01, SLIP_END, 0x02, SLIP_ESC, 0x03]),
+			[SLIP_END, 0x01, SLIP_ESC, SLIP_ESC_END, 0x02, SLIP_ESC, SLIP_ESC_ESC, 0x03, SLIP_END]);
+		// The escaped forms on their own pass through untouched
+		assert_eq!(encode(&SLIP, &[SLIP_ESC_END, SLIP_ESC_ESC]), [SLIP_END, SLIP_ESC_END, SLIP_ESC_ESC, SLIP_END]);
+	}
+
+	#[test]
+	fn slipRoundtrips()
+	{
+		for frame in [&b"a"[..], &[SLIP_END], &[SLIP_ESC, SLIP_ESC], &[SLIP_END; MAX_FRAME_SIZE], &[0x00]]
+		{
+			roundtrip(&SLIP, frame);
+		}
+	}
+
+	#[test]
+	fn slipRejectsBadEscape()
+	{
+		let mut decoder = FrameDecoder::<16>::new(SLIP);
+		assert_eq!(decode(&mut decoder, &[0x01, SLIP_ESC, 0x02, 0x03, SLIP_END, 0x04, SLIP_END]),
+			[None, Some(vec![0x04])]);
+	}
+
+	#[test]
+	fn cobsRejectsTruncatedBlock()
+	{
+		let mut decoder = FrameDecoder::<16>::new(COBS);
+		assert_eq!(decode(&mut decoder, &[0x04, 0x11, 0x22, 0x00, 0x02, 0x33, 0x00]), [None, Some(vec![0x33])]);
+	}
+
+	#[test]
+	fn crcRoundtripsAndIsChecked()
+	{
+		for encoding in [FrameEncoding::Cobs, FrameEncoding::Slip]
+		{
+			let config = FramingConfig { encoding, crc: true, delimiter: None };
+			roundtrip(&config, b"123456789");
+			roundtrip(&config, &[0xff; MAX_FRAME_SIZE]);
+
+			// The CRC is appended little endian
+			let mut encoded = encode(&config, b"123456789");
+			let plain = encode(&FramingConfig { crc: false, ..config }, b"123456789\xb1\x29");
+			assert_eq!(encoded, plain);
+
+			// Corrupting any bit of the payload fails the check
+			encoded[3] ^= 0x01;
+			let mut decoder = FrameDecoder::<{ MAX_FRAME_SIZE + CRC_LENGTH }>::new(config);
+			assert_eq!(decode(&mut decoder, &encoded), [None]);
+
+			// As does a frame too short to hold a CRC
+			let encoded = encode(&FramingConfig { crc: false, ..config }, &[0x01]);
+			assert_eq!(decode(&mut decoder, &encoded), [None]);
+		}
+	}
+
+	#[test]
+	fn oversizeFramesAreDiscarded()
+	{
+		for config in [COBS, SLIP]
+		{
+			let mut decoder = FrameDecoder::<4>::new(config);
+			let mut stream = encode(&config, &[0x01; 5]);
+			stream.extend(encode(&config, &[0x02; 4]));
+			assert_eq!(decode(&mut decoder, &stream), [None, Some(vec![0x02; 4])]);
+		}
+	}
+
+	#[test]
+	fn emptyFramesAreIdle()
+	{
+		// An empty frame can't be told apart from back to back delimiters, so it's dropped as idle line
+		for config in [COBS, SLIP]
+		{
+			let mut decoder = FrameDecoder::<16>::new(config);
+			let mut stream = encode(&config, &[]);
+			stream.extend(encode(&config, b"a"));
+			assert_eq!(decode(&mut decoder, &stream), [Some(b"a".to_vec())]);
+		}
+		let mut decoder = FrameDecoder::<16>::new(COBS);
+		assert_eq!(decode(&mut decoder, &[0x00, 0x00, 0x00]), []);
+	}
+
+	#[test]
+	fn encodeNeedsRoom()
+	{
+		let mut output = [0u8; 4];
+		assert_eq!(encodeFrame(&COBS, b"abc", &mut output), None);
+		assert_eq!(encodeFrame(&COBS, b"ab", &mut output), Some(4));
+		assert_eq!(encodeFrame(&SLIP, &[SLIP_END, 0x01], &mut output), None);
+		assert_eq!(encodeFrame(&SLIP, &[SLIP_END], &mut output), Some(4));
+	}
+
+	#[test]
+	fn requestsDecode()
+	{
+		let config = FramingConfig::fromRequest(0x0301, 0x7e).unwrap();
+		assert!(config.encoding == FrameEncoding::Cobs && config.crc && config.delimiter == Some(0x7e));
+		let config = FramingConfig::fromRequest(0x0002, 0x7e).unwrap();
+		assert!(config.encoding == FrameEncoding::Slip && !config.crc && config.delimiter.is_none());
+		assert!(FramingConfig::fromRequest(0x0003, 0).is_none());
+		assert!(FramingConfig::fromRequest(0x0201, 0x100).is_none());
+	}
+}
@@ -0,0 +1,84 @@
+// SPDX-License-Identifier: BSD-3-Clause
+
+use defmt::debug;
+use embassy_embedded_hal::SetConfig;
+use embassy_stm32::mode::Async;
+use embassy_stm32::rcc;
+use embassy_stm32::usart::{Config as UartConfig, Uart, UartRx};
+use embassy_time::Duration;
+
+use crate::board::
+{
+	self, ClockResources, FULL_SPEED_CLOCKS, LOW_POWER_CLOCKS, LOW_POWER_MAX_BAUD, TARGET_UART_REGISTERS,
+};
+use crate::clocks::ClockPlan;
+use crate::types::SerialEncoding;
+
+/// How long both directions have to be quiet before dropping to the low power clocks
+pub const IDLE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Moves the part between its full speed and low power clocks, keeping the UARTs on the line coding
+/// in effect as it does
+pub struct FrequencyScaler
+{
+	rcc: ClockResources,
+	lowPower: bool,
+}
+
+impl FrequencyScaler
+{
+	/// Take over the RCC, which starts out on the full speed clocks
+	pub fn new(rcc: ClockResources) -> Self
+	{
+		Self { rcc, lowPower: false }
+	}
+
+	/// Whether the link is slow enough that we'd drop to the low power clocks once it goes idle
+	pub fn shouldScaleDown(&self, encoding: &SerialEncoding) -> bool
+	{
+		!self.lowPower && encoding.baudRate <= LOW_POWER_MAX_BAUD
+	}
+
+	/// Drop to the low power clocks
+	pub fn scaleDown(&mut self, serialPort: &mut Uart<'static, Async>, snifferPort: &mut UartRx<'static, Async>,
+		config: &UartConfig)
+	{
+		if !self.lowPower
+		{
+			debug!("Link idle, dropping to low power clocks");
+			self.switchTo(&LOW_POWER_CLOCKS, serialPort, snifferPort, config);
+			self.lowPower = true;
+		}
+	}
+
+	/// Get back onto the full speed clocks ahead of anything that needs them
+	pub fn scaleUp(&mut self, serialPort: &mut Uart<'static, Async>, snifferPort: &mut UartRx<'static, Async>,
+		config: &UartConfig)
+	{
+		if self.lowPower
+		{
+			debug!("Returning to full speed clocks");
+			self.switchTo(&FULL_SPEED_CLOCKS, serialPort, snifferPort, config);
+			self.lowPower = false;
+		}
+	}
+
+	fn switchTo(&mut self, plan: &ClockPlan, serialPort: &mut Uart<'static, Async>,
+		snifferPort: &mut UartRx<'static, Async>, config: &UartConfig)
+	{
+		// Let anything still going out on the wire finish, as the baud rate is about to go wrong for a moment
+		serialPort.blocking_flush()
+			.expect("Serial interface flushes never fail");
+		// Likewise let any character the target is part way through sending finish arriving, so the switch lands
+		// between characters rather than corrupting one. BUSY clears at the end of every character, so even a
+		// target sending back to back never holds this up for longer than one
+		while TARGET_UART_REGISTERS.isr().read().busy() {}
+		rcc::reinit(board::clockConfig(plan).rcc, &mut self.rcc.peripheral);
+		// The baud rate generators were set up for the old kernel clock, so work their dividers out again
+		// to keep the line coding the host asked for
+		serialPort.set_config(config)
+			.expect("Unable to restore encoding state after clock change");
+		snifferPort.set_config(config)
+			.expect("Unable to restore encoding state after clock change");
+	}
+}
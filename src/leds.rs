@@ -0,0 +1,35 @@
+// SPDX-License-Identifier: BSD-3-Clause
+
+use embassy_stm32::gpio::{Output, Speed};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::signal::Signal;
+use embassy_time::{Duration, Timer};
+
+use crate::board::{self, LED_ACTIVE, LedResources};
+
+// How long the activity LED stays lit for each burst of traffic
+const ACTIVITY_FLASH: Duration = Duration::from_millis(20);
+
+static ACTIVITY: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// Note that data has moved through the conduit, so the activity LED gets flashed
+pub fn activity()
+{
+	ACTIVITY.signal(());
+}
+
+/// Light the status LED to show the firmware is up, then flash the activity LED whenever data moves
+#[embassy_executor::task]
+pub async fn ledTask(leds: LedResources)
+{
+	let _status = Output::new(leds.status, LED_ACTIVE, Speed::Low);
+	let mut activityLed = Output::new(leds.activity, board::inactive(LED_ACTIVE), Speed::Low);
+
+	loop
+	{
+		ACTIVITY.wait().await;
+		activityLed.set_level(LED_ACTIVE);
+		Timer::after(ACTIVITY_FLASH).await;
+		activityLed.set_level(board::inactive(LED_ACTIVE));
+	}
+}
@@ -0,0 +1,18 @@
+// SPDX-License-Identifier: BSD-3-Clause
+
+// The parts of the firmware that don't touch the hardware, kept apart from it so they can be built and tested on
+// the host with `cargo test-host`
+
+#![allow(non_snake_case)]
+#![cfg_attr(not(test), no_std)]
+
+pub mod atomic_ref_counted;
+pub mod command_line;
+pub mod escape;
+pub mod ethernet;
+pub mod framing;
+pub mod pcapng;
+pub mod prbs;
+pub mod ref_counted;
+pub mod run_multiple;
+pub mod sequence;
@@ -4,65 +4,128 @@
 #![no_std]
 #![no_main]
 
-mod ref_counted;
-mod resources;
-mod run_multiple;
+#[cfg(all(feature = "log-rtt", feature = "log-usb"))]
+compile_error!("Only one of the log-rtt and log-usb features can be enabled at a time");
+// Full speed USB only has enough endpoints for one of the optional interfaces
+#[cfg(all(feature = "log-usb", feature = "network", not(feature = "usb-hs")))]
+compile_error!("The log-usb and network features can only be enabled together with usb-hs");
+
+mod bert;
+mod board;
+mod capture;
+mod clocks;
+#[cfg(feature = "command-mode")]
+mod command_mode;
+mod crash;
+mod framed;
+mod frequency_scaling;
+mod leds;
+#[cfg(feature = "modem-inputs")]
+mod modem;
+#[cfg(feature = "network")]
+mod network;
+mod packet;
+#[cfg(feature = "target-power")]
+mod power;
+mod self_test;
 mod serial;
 mod serial_number;
+mod settings;
+mod sniffer;
+mod target;
 mod types;
 mod usb;
+#[cfg(feature = "log-usb")]
+mod usb_log;
 mod usb_types;
+mod vendor;
+mod watchdog;
 
+#[cfg(feature = "heap")]
 extern crate alloc;
 
-use embassy_executor::Spawner;
+// Everything that doesn't touch the hardware lives in the library so it can be tested on the host
+#[cfg(feature = "command-mode")]
+use usb_serial_conduit::{command_line, escape};
+#[cfg(feature = "network")]
+use usb_serial_conduit::ethernet;
+use usb_serial_conduit::{framing, pcapng, prbs, ref_counted, run_multiple, sequence};
+
+use embassy_executor::{InterruptExecutor, Spawner};
 use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
 use embassy_sync::channel::Channel;
-use embedded_alloc::LlffHeap as Heap;
 // Magically inject the parts of the defmt machinary that are needed for doing defmt over RTT 🙃
+#[cfg(feature = "log-rtt")]
 use defmt_rtt as _;
-// Magically inject #[panic_handler] so we get panic handling.. don't ask, it's absolutely magic how this can do that.
-use panic_probe as _;
-use static_cell::ConstStaticCell;
 
-use crate::resources::resources::*;
+use crate::board::resources::*;
+use crate::leds::ledTask;
+#[cfg(feature = "modem-inputs")]
+use crate::modem::modemTask;
+#[cfg(feature = "target-power")]
+use crate::power::powerTask;
 use crate::serial::serialTask;
 use crate::serial_number::readSerialNumber;
-use crate::types::{ReceiveRequest, TransmitRequest};
+use crate::settings::settingsTask;
+use crate::target::targetTask;
+use crate::types::{ReceiveRequest, SerialCommand, TransmitRequest};
 use crate::usb::usbTask;
-
-const HEAP_SIZE: usize = 1024 * 4; // 4KiB heap
-#[global_allocator]
-static HEAP: Heap = Heap::empty();
-static HEAP_MEM: ConstStaticCell<[u8; HEAP_SIZE]> = ConstStaticCell::new([0; HEAP_SIZE]);
+use crate::watchdog::watchdogTask;
 
 // Create a pair of channels for moving information between the USB and serial tasks
 static TRANSMIT_CHANNEL: Channel<CriticalSectionRawMutex, TransmitRequest, 1> = Channel::new();
 static RECEIVE_CHANNEL: Channel<CriticalSectionRawMutex, ReceiveRequest, 1> = Channel::new();
+// And one for the USB control plane to tell the serial task to change what it's doing
+static SERIAL_COMMAND_CHANNEL: Channel<CriticalSectionRawMutex, SerialCommand, 2> = Channel::new();
+
+// USB runs on its own executor at a higher priority than serial processing so control responses and bulk
+// packet turnaround never wait behind UART work. It's driven from an interrupt nothing else uses, which the
+// board picks and provides the handler for
+static USB_EXECUTOR: InterruptExecutor = InterruptExecutor::new();
 
 #[embassy_executor::main]
 async fn main(spawner: Spawner)
 {
-	// Initialise our heap so we can use allocating types
-	unsafe
-	{
-		let heapMemory = HEAP_MEM.take() as *mut u8;
-		HEAP.init(heapMemory as usize, HEAP_SIZE);
-	}
+	// Set up the memory the USB and serial tasks pass packets between each other in
+	packet::init();
 
 	// Initialise the execution environment so we're on the right clock
-	let peripherals = resources::init();
+	let peripherals = board::init();
 	let resources = split_resources!(peripherals);
+	// Find out why we reset, and what went wrong if it was a crash, so the host can ask about it
+	watchdog::readResetReason();
+	crash::readCrashRecord();
 
+	// Load any persisted user settings, keeping hold of the flash so they can be updated later
+	let flash = settings::init(resources.flash);
 	// Read the serial number for the USB task to use
 	readSerialNumber();
 
-	// Spawn the task to handle USB for us
-	spawner.spawn(usbTask(
-		resources.usb, TRANSMIT_CHANNEL.receiver(), RECEIVE_CHANNEL.sender()
+	// Set up the interrupt priorities so USB always wins over the serial side of things
+	board::configureInterruptPriorities();
+
+	// Spawn the task to handle USB for us on the high priority executor
+	let usbSpawner = USB_EXECUTOR.start(board::USB_EXECUTOR_INTERRUPT);
+	usbSpawner.spawn(usbTask(
+		resources.usb, TRANSMIT_CHANNEL.receiver(), RECEIVE_CHANNEL.sender(), SERIAL_COMMAND_CHANNEL.sender()
 	).unwrap());
 	// And then the one to handle serial
 	spawner.spawn(serialTask(
-		resources.uart, TRANSMIT_CHANNEL.sender(), RECEIVE_CHANNEL.receiver()
+		resources.uart, resources.sniffer, resources.clocks, TRANSMIT_CHANNEL.sender(),
+		RECEIVE_CHANNEL.receiver(), SERIAL_COMMAND_CHANNEL.receiver()
 	).unwrap());
+	// The one that plays reset and boot mode sequences on the target's control lines
+	spawner.spawn(targetTask(resources.control).unwrap());
+	// The one that drives the LEDs
+	spawner.spawn(ledTask(resources.leds).unwrap());
+	// The one that watches the target's modem control outputs, if they're in use
+	#[cfg(feature = "modem-inputs")]
+	spawner.spawn(modemTask(resources.modem).unwrap());
+	// The one that switches and monitors the target's power, if that's in use
+	#[cfg(feature = "target-power")]
+	spawner.spawn(powerTask(resources.power).unwrap());
+	// The one that writes settings changes back to flash
+	spawner.spawn(settingsTask(flash).unwrap());
+	// And finally the one that resets us if any of the others wedge
+	spawner.spawn(watchdogTask(resources.watchdog).unwrap());
 }
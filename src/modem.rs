@@ -0,0 +1,76 @@
+// SPDX-License-Identifier: BSD-3-Clause
+
+use embassy_futures::select::{Either, select, select3};
+use embassy_stm32::exti::ExtiInput;
+use embassy_stm32::gpio::Pull;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::signal::Signal;
+use embassy_time::{Duration, Timer};
+
+use crate::board::{MODEM_INPUT_ACTIVE, ModemResources};
+use crate::usb_types::UsbCdcSerialState;
+
+// How long the inputs have to stay put after an edge before the new levels are believed
+const DEBOUNCE_TIME: Duration = Duration::from_millis(10);
+
+static STATE_CHANGE: Signal<CriticalSectionRawMutex, UsbCdcSerialState> = Signal::new();
+
+/// Wait for the modem control inputs to change, getting their new state as SERIAL_STATE bits. The first call
+/// gets the state they started out in
+pub async fn changed() -> UsbCdcSerialState
+{
+	STATE_CHANGE.wait().await
+}
+
+/// Watch the target's DCD, DSR and RI outputs, passing on each debounced change for the host to be notified of
+#[embassy_executor::task]
+pub async fn modemTask(modem: ModemResources)
+{
+	// Pulled up so that the inputs read inactive with nothing connected
+	let mut dcd = ExtiInput::new(modem.dcd, modem.dcd_exti, Pull::Up);
+	let mut dsr = ExtiInput::new(modem.dsr, modem.dsr_exti, Pull::Up);
+	let mut ri = ExtiInput::new(modem.ri, modem.ri_exti, Pull::Up);
+
+	let mut state = sample(&dcd, &dsr, &ri);
+	STATE_CHANGE.signal(state);
+
+	loop
+	{
+		select3(dcd.wait_for_any_edge(), dsr.wait_for_any_edge(), ri.wait_for_any_edge()).await;
+		// Wait for the inputs to settle, starting over each time another edge comes along
+		loop
+		{
+			let edgeFuture = select3(dcd.wait_for_any_edge(), dsr.wait_for_any_edge(), ri.wait_for_any_edge());
+			if let Either::First(()) = select(Timer::after(DEBOUNCE_TIME), edgeFuture).await
+			{
+				break;
+			}
+		}
+
+		// Bounces that settled back where they started aren't a change
+		let newState = sample(&dcd, &dsr, &ri);
+		if newState != state
+		{
+			state = newState;
+			STATE_CHANGE.signal(state);
+		}
+	}
+}
+
+fn sample(dcd: &ExtiInput<'static>, dsr: &ExtiInput<'static>, ri: &ExtiInput<'static>) -> UsbCdcSerialState
+{
+	let mut state = UsbCdcSerialState::none();
+	if dcd.get_level() == MODEM_INPUT_ACTIVE
+	{
+		state |= UsbCdcSerialState::RxCarrier;
+	}
+	if dsr.get_level() == MODEM_INPUT_ACTIVE
+	{
+		state |= UsbCdcSerialState::TxCarrier;
+	}
+	if ri.get_level() == MODEM_INPUT_ACTIVE
+	{
+		state |= UsbCdcSerialState::RingSignal;
+	}
+	state
+}
@@ -0,0 +1,226 @@
+// SPDX-License-Identifier: BSD-3-Clause
+
+use defmt::{debug, error};
+use embassy_futures::join::join;
+use embassy_futures::select::{Either3, select3};
+use embassy_stm32::mode::Async;
+use embassy_stm32::uid::uid;
+use embassy_stm32::usart::{Uart, UartRx, UartTx};
+use embassy_stm32::usb::Driver;
+use embassy_sync::blocking_mutex::raw::{CriticalSectionRawMutex, NoopRawMutex};
+use embassy_sync::channel::{Channel, Receiver};
+use embassy_sync::mutex::Mutex;
+use embassy_usb::class::cdc_ncm::CdcNcmClass;
+
+use crate::board::UsbPeripheral;
+use crate::capture;
+use crate::ethernet::{self, HostFrame, MAX_ETHERNET_FRAME_SIZE, MTU, MacAddress};
+use crate::framing::{self, DecodeResult, FrameDecoder, FrameEncoding, FramingConfig};
+use crate::pcapng::PacketDirection;
+use crate::serial::SerialEvent;
+use crate::types::{ReceiveRequest, SerialCommand};
+use crate::watchdog::{self, Watched};
+
+// IP datagrams go over the UART as plain SLIP, from RFC 1055
+const SLIP: FramingConfig = FramingConfig { encoding: FrameEncoding::Slip, crc: false, delimiter: None };
+// Worst case SLIP encoding of a datagram: every byte escaped, plus the delimiters either end
+const MAX_ENCODED_DATAGRAM_SIZE: usize = (MTU * 2) + 2;
+
+/// An IP datagram on its way between the host and target
+struct Datagram
+{
+	data: [u8; MTU],
+	length: usize,
+}
+
+impl Datagram
+{
+	fn copyFrom(data: &[u8]) -> Option<Self>
+	{
+		let mut datagram = Self { data: [0; MTU], length: data.len() };
+		datagram.data.get_mut(0..data.len())?.copy_from_slice(data);
+		Some(datagram)
+	}
+
+	fn data(&self) -> &[u8]
+	{
+		&self.data[0..self.length]
+	}
+}
+
+// Datagrams from the host waiting to go to the target, and from the target waiting to go to the host
+static TO_TARGET: Channel<CriticalSectionRawMutex, Datagram, 1> = Channel::new();
+static TO_HOST: Channel<CriticalSectionRawMutex, Datagram, 1> = Channel::new();
+
+/// The MAC the host's network interface is given. Both MACs are locally administered and derived from the UID
+/// so they're stable across resets and unique between devices
+pub fn hostMac() -> MacAddress
+{
+	let uniqueID = uid();
+	[0x02, uniqueID[4], uniqueID[3], uniqueID[2], uniqueID[1], uniqueID[0]]
+}
+
+/// The MAC we answer for the target with
+fn deviceMac() -> MacAddress
+{
+	let mut mac = hostMac();
+	mac[0] = 0x06;
+	mac
+}
+
+/// Mark the start of network mode, throwing away anything the host sent while the link wasn't running
+pub fn start()
+{
+	TO_TARGET.clear();
+}
+
+/// Run the host side of the network interface, turning the host's Ethernet frames into datagrams for the target
+/// and the target's datagrams back into Ethernet frames
+pub async fn run(class: CdcNcmClass<'static, Driver<'static, UsbPeripheral>>) -> !
+{
+	let (sender, mut receiver) = class.split();
+	// Replies to ARP and neighbour discovery go out alongside datagrams from the target
+	let sender = Mutex::<NoopRawMutex, _>::new(sender);
+	let deviceMac = deviceMac();
+	let hostMac = hostMac();
+
+	let fromHost = async
+	{
+		let mut frame = [0u8; MAX_ETHERNET_FRAME_SIZE];
+		let mut reply = [0u8; MAX_ETHERNET_FRAME_SIZE];
+		loop
+		{
+			// Wait for the host to bring the interface up, which also tells it the link is connected
+			if receiver.wait_connection().await.is_err()
+			{
+				continue;
+			}
+			// Then handle frames until it takes it down again
+			while let Ok(length) = receiver.read_packet(&mut frame).await
+			{
+				match ethernet::fromHost(&frame[0..length], &deviceMac, &mut reply)
+				{
+					// If the target hasn't taken the last datagram yet, this one gets dropped just like on a
+					// congested network
+					HostFrame::Datagram(datagram) =>
+					{
+						if let Some(datagram) = Datagram::copyFrom(datagram)
+						{
+							let _ = TO_TARGET.try_send(datagram);
+						}
+					}
+					HostFrame::Reply(length) =>
+					{
+						if sender.lock().await.write_packet(&reply[0..length]).await.is_err()
+						{
+							debug!("Network interface went down, dropping reply to host");
+						}
+					}
+					HostFrame::Ignored => {}
+				}
+			}
+		}
+	};
+
+	let toHost = async
+	{
+		let mut frame = [0u8; MAX_ETHERNET_FRAME_SIZE];
+		loop
+		{
+			let datagram = TO_HOST.receive().await;
+			// Anything that isn't IPv4 or IPv6 can't be put in a frame for the host, so it's dropped
+			let Some(length) = ethernet::toHost(datagram.data(), &deviceMac, &hostMac, &mut frame) else
+			{
+				error!("Discarding datagram from target that isn't IPv4 or IPv6");
+				continue;
+			};
+			// As is anything that comes in while the host has the interface down, as there's nowhere for it to go
+			if sender.lock().await.write_packet(&frame[0..length]).await.is_err()
+			{
+				debug!("Network interface down, dropping datagram to host");
+			}
+		}
+	};
+
+	join(fromHost, toHost).await;
+	unreachable!("Network interface handling never completes")
+}
+
+/// Carry IP datagrams between the network interface and the target as SLIP over the UART, until a line coding
+/// change or command comes in that needs the serial task's attention. Data arriving on the serial port's own
+/// interface meanwhile has nowhere to go, so is handed back to be discarded
+pub async fn runLink(
+	serialPort: &mut Uart<'static, Async>,
+	receiveChannel: &Receiver<'static, CriticalSectionRawMutex, ReceiveRequest, 1>,
+	serialCommands: &Receiver<'static, CriticalSectionRawMutex, SerialCommand, 2>,
+) -> SerialEvent
+{
+	let (transmitter, receiver) = serialPort.split_ref();
+	match select3
+	(
+		join(receiveDatagrams(receiver), transmitDatagrams(transmitter)),
+		receiveChannel.receive(),
+		serialCommands.receive(),
+	).await
+	{
+		Either3::First(_) => unreachable!("Datagram forwarding never completes"),
+		Either3::Second(request) => SerialEvent::Request(request),
+		Either3::Third(command) => SerialEvent::Command(command),
+	}
+}
+
+async fn receiveDatagrams(receiver: &mut UartRx<'static, Async>) -> !
+{
+	let mut decoder: FrameDecoder<MTU> = FrameDecoder::new(SLIP);
+	let mut buffer = [0u8; 64];
+
+	loop
+	{
+		watchdog::heartbeat(Watched::Serial);
+		let byteCount = match watchdog::excused(Watched::Serial, receiver.read_until_idle(&mut buffer)).await
+		{
+			Ok(byteCount) => byteCount,
+			Err(error) =>
+			{
+				error!("Serial interface read failed, {}", error);
+				continue;
+			}
+		};
+		capture::data(PacketDirection::Inbound, &buffer[0..byteCount]);
+
+		for byte in &buffer[0..byteCount]
+		{
+			match decoder.decode(*byte)
+			{
+				DecodeResult::Pending => {}
+				DecodeResult::Frame(_) =>
+				{
+					// If the host side hasn't taken the last datagram yet, this one gets dropped just like on a
+					// congested network, rather than holding up reception from the target
+					let sent = Datagram::copyFrom(decoder.frame()).map(|datagram| TO_HOST.try_send(datagram));
+					if let Some(Err(_)) = sent
+					{
+						debug!("Host hasn't taken the last datagram yet, dropping datagram from target");
+					}
+				}
+				DecodeResult::Error =>
+					error!("Discarding malformed datagram from target"),
+			}
+		}
+	}
+}
+
+async fn transmitDatagrams(transmitter: &mut UartTx<'static, Async>) -> !
+{
+	let mut encoded = [0u8; MAX_ENCODED_DATAGRAM_SIZE];
+
+	loop
+	{
+		watchdog::heartbeat(Watched::Serial);
+		let datagram = watchdog::excused(Watched::Serial, TO_TARGET.receive()).await;
+		let byteCount = framing::encodeFrame(&SLIP, datagram.data(), &mut encoded)
+			.expect("Encoded datagrams always fit in the buffer");
+		capture::data(PacketDirection::Outbound, &encoded[0..byteCount]);
+		transmitter.write(&encoded[0..byteCount]).await.expect("Serial interface writes never fail");
+	}
+}
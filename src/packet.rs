@@ -0,0 +1,183 @@
+// SPDX-License-Identifier: BSD-3-Clause
+
+use core::ops::{Deref, DerefMut};
+
+/// Largest packet that can be passed between the USB and serial tasks. Anything bigger has to be split up.
+/// This is a couple of USB packets' worth, so high speed gets more to keep up with multi-megabaud links
+#[cfg(not(feature = "usb-hs"))]
+pub const MAX_PACKET_SIZE: usize = 128;
+#[cfg(feature = "usb-hs")]
+pub const MAX_PACKET_SIZE: usize = 1024;
+
+#[cfg(feature = "heap")]
+pub use heap::{Packet, init};
+#[cfg(not(feature = "heap"))]
+pub use pool::{Packet, init};
+
+/// Packets allocated on the heap, which sizes them exactly but can run out under bursty traffic
+#[cfg(feature = "heap")]
+mod heap
+{
+	use alloc::boxed::Box;
+	use embedded_alloc::LlffHeap as Heap;
+	use static_cell::ConstStaticCell;
+
+	use super::MAX_PACKET_SIZE;
+
+	#[cfg(not(feature = "usb-hs"))]
+	const HEAP_SIZE: usize = 1024 * 4; // 4KiB heap
+	#[cfg(feature = "usb-hs")]
+	const HEAP_SIZE: usize = 1024 * 16; // 16KiB heap, for the larger packets
+	#[global_allocator]
+	static HEAP: Heap = Heap::empty();
+	static HEAP_MEM: ConstStaticCell<[u8; HEAP_SIZE]> = ConstStaticCell::new([0; HEAP_SIZE]);
+
+	/// Initialise our heap so we can allocate packets
+	pub fn init()
+	{
+		unsafe
+		{
+			let heapMemory = HEAP_MEM.take() as *mut u8;
+			HEAP.init(heapMemory as usize, HEAP_SIZE);
+		}
+	}
+
+	pub struct Packet
+	{
+		data: Box<[u8]>,
+	}
+
+	impl Packet
+	{
+		/// Allocate a zeroed packet of the requested length
+		pub async fn new(length: usize) -> Self
+		{
+			assert!(length <= MAX_PACKET_SIZE, "Packet too large");
+			let data = unsafe
+			{
+				Box::new_zeroed_slice(length)
+					.assume_init()
+			};
+			Self { data }
+		}
+
+		pub(super) fn data(&self) -> &[u8]
+		{
+			&self.data
+		}
+
+		pub(super) fn dataMut(&mut self) -> &mut [u8]
+		{
+			&mut self.data
+		}
+	}
+}
+
+/// Packets allocated from a fixed pool of statically sized buffers, so memory use is fully determined at link
+/// time. When the pool runs dry, allocating waits for a packet to be freed rather than failing
+#[cfg(not(feature = "heap"))]
+mod pool
+{
+	use core::cell::UnsafeCell;
+	use core::slice;
+	use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+	use embassy_sync::channel::Channel;
+
+	use super::MAX_PACKET_SIZE;
+
+	// Enough for both channels to be full with both tasks building their next packet, and then some
+	const PACKET_COUNT: usize = 16;
+
+	struct PacketBuffers([UnsafeCell<[u8; MAX_PACKET_SIZE]>; PACKET_COUNT]);
+
+	// Each buffer is only ever accessed through the one Packet that owns its index
+	unsafe impl Sync for PacketBuffers {}
+
+	static PACKET_BUFFERS: PacketBuffers =
+		PacketBuffers([const { UnsafeCell::new([0; MAX_PACKET_SIZE]) }; PACKET_COUNT]);
+	// Indices of the buffers not currently in use
+	static FREE_PACKETS: Channel<CriticalSectionRawMutex, u8, PACKET_COUNT> = Channel::new();
+
+	/// Put every buffer in the pool up for allocation
+	pub fn init()
+	{
+		for index in 0..PACKET_COUNT
+		{
+			FREE_PACKETS.try_send(index as u8)
+				.expect("Packet pool large enough for all buffers");
+		}
+	}
+
+	pub struct Packet
+	{
+		index: u8,
+		length: usize,
+	}
+
+	impl Packet
+	{
+		/// Allocate a zeroed packet of the requested length, waiting for one to become free if need be
+		pub async fn new(length: usize) -> Self
+		{
+			assert!(length <= MAX_PACKET_SIZE, "Packet too large");
+			let mut packet = Self { index: FREE_PACKETS.receive().await, length };
+			packet.dataMut().fill(0);
+			packet
+		}
+
+		// Raw pointer to the start of this packet's buffer, which only this packet ever makes references from
+		fn buffer(&self) -> *mut u8
+		{
+			PACKET_BUFFERS.0[self.index as usize].get().cast()
+		}
+
+		pub(super) fn data(&self) -> &[u8]
+		{
+			unsafe { slice::from_raw_parts(self.buffer(), self.length) }
+		}
+
+		pub(super) fn dataMut(&mut self) -> &mut [u8]
+		{
+			// Handing out a mutable view needs the packet borrowed mutably, so it can't alias one from data()
+			unsafe { slice::from_raw_parts_mut(self.buffer(), self.length) }
+		}
+	}
+
+	impl Drop for Packet
+	{
+		fn drop(&mut self)
+		{
+			// There's room in the free list for every buffer, so handing this one back can't fail
+			let _ = FREE_PACKETS.try_send(self.index);
+		}
+	}
+}
+
+impl Packet
+{
+	/// Allocate a packet holding a copy of data
+	pub async fn copyFrom(data: &[u8]) -> Self
+	{
+		let mut packet = Self::new(data.len()).await;
+		packet.copy_from_slice(data);
+		packet
+	}
+}
+
+impl Deref for Packet
+{
+	type Target = [u8];
+
+	fn deref(&self) -> &[u8]
+	{
+		self.data()
+	}
+}
+
+impl DerefMut for Packet
+{
+	fn deref_mut(&mut self) -> &mut [u8]
+	{
+		self.dataMut()
+	}
+}
@@ -0,0 +1,243 @@
+// SPDX-License-Identifier: BSD-3-Clause
+
+// Block types
+const BLOCK_SECTION_HEADER: u32 = 0x0a0d0d0a;
+const BLOCK_INTERFACE_DESCRIPTION: u32 = 0x00000001;
+const BLOCK_ENHANCED_PACKET: u32 = 0x00000006;
+
+// Lets readers determine the endianness the file was written in
+const BYTE_ORDER_MAGIC: u32 = 0x1a2b3c4d;
+
+// Option codes
+const OPTION_END: u16 = 0;
+const OPTION_COMMENT: u16 = 1;
+const OPTION_SHB_USER_APPLICATION: u16 = 4;
+const OPTION_IF_NAME: u16 = 2;
+const OPTION_EPB_FLAGS: u16 = 2;
+
+/// Link type for the raw bytes of a serial link. There's no standard type for this, so use the first of the
+/// user-defined types, which Wireshark can be told to decode with any dissector
+pub const LINKTYPE_USER0: u16 = 147;
+
+/// Direction of a packet relative to the conduit, encoded as in the epb_flags option
+#[repr(u32)]
+#[derive(Clone, Copy)]
+pub enum PacketDirection
+{
+	/// Received from the target
+	Inbound = 1,
+	/// Sent to the target
+	Outbound = 2,
+}
+
+/// Helper for building a block, with its header and trailer, into a buffer
+struct BlockWriter<'a>
+{
+	data: &'a mut [u8],
+	offset: usize,
+}
+
+impl<'a> BlockWriter<'a>
+{
+	fn new(data: &'a mut [u8], blockType: u32) -> Option<Self>
+	{
+		let mut writer = Self { data, offset: 0 };
+		writer.u32(blockType)?;
+		// Placeholder for the block length, which gets filled in by finish()
+		writer.u32(0)?;
+		Some(writer)
+	}
+
+	fn bytes(&mut self, bytes: &[u8]) -> Option<()>
+	{
+		// Everything in a block is padded out to a multiple of 4 bytes
+		let paddedLength = bytes.len().next_multiple_of(4);
+		let target = self.data.get_mut(self.offset..self.offset + paddedLength)?;
+		target[0..bytes.len()].copy_from_slice(bytes);
+		target[bytes.len()..].fill(0);
+		self.offset += paddedLength;
+		Some(())
+	}
+
+	fn u16(&mut self, value: u16) -> Option<()>
+	{
+		let target = self.data.get_mut(self.offset..self.offset + 2)?;
+		target.copy_from_slice(&value.to_le_bytes());
+		self.offset += 2;
+		Some(())
+	}
+
+	fn u32(&mut self, value: u32) -> Option<()>
+	{
+		let target = self.data.get_mut(self.offset..self.offset + 4)?;
+		target.copy_from_slice(&value.to_le_bytes());
+		self.offset += 4;
+		Some(())
+	}
+
+	fn option(&mut self, code: u16, value: &[u8]) -> Option<()>
+	{
+		self.u16(code)?;
+		self.u16(value.len() as u16)?;
+		self.bytes(value)
+	}
+
+	fn finish(mut self, hasOptions: bool) -> Option<usize>
+	{
+		if hasOptions
+		{
+			self.option(OPTION_END, &[])?;
+		}
+		// The block length appears both after the block type and at the very end of the block
+		let length = self.offset + 4;
+		self.u32(length as u32)?;
+		self.data[4..8].copy_from_slice(&(length as u32).to_le_bytes());
+		Some(length)
+	}
+}
+
+/// Write a section header block, which starts a pcapng stream
+pub fn sectionHeaderBlock(data: &mut [u8], application: &str) -> Option<usize>
+{
+	let mut block = BlockWriter::new(data, BLOCK_SECTION_HEADER)?;
+	block.u32(BYTE_ORDER_MAGIC)?;
+	// Version 1.0
+	block.u16(1)?;
+	block.u16(0)?;
+	// The section length is not known up front as this is a stream
+	block.u32(u32::MAX)?;
+	block.u32(u32::MAX)?;
+	block.option(OPTION_SHB_USER_APPLICATION, application.as_bytes())?;
+	block.finish(true)
+}
+
+/// Write an interface description block. Timestamps are left at the default resolution of microseconds
+pub fn interfaceDescriptionBlock(data: &mut [u8], linkType: u16, snapLength: u32, name: &str) -> Option<usize>
+{
+	let mut block = BlockWriter::new(data, BLOCK_INTERFACE_DESCRIPTION)?;
+	block.u16(linkType)?;
+	block.u16(0)?;
+	block.u32(snapLength)?;
+	block.option(OPTION_IF_NAME, name.as_bytes())?;
+	block.finish(true)
+}
+
+/// Write an enhanced packet block for interface 0, with a timestamp in microseconds. Events that aren't
+/// traffic can be recorded as a packet with no data and a comment
+pub fn enhancedPacketBlock(
+	data: &mut [u8],
+	timestamp: u64,
+	direction: PacketDirection,
+	packet: &[u8],
+	comment: Option<&str>,
+) -> Option<usize>
+{
+	let mut block = BlockWriter::new(data, BLOCK_ENHANCED_PACKET)?;
+	block.u32(0)?;
+	block.u32((timestamp >> 32) as u32)?;
+	block.u32(timestamp as u32)?;
+	// Captured and original lengths are the same as nothing gets truncated
+	block.u32(packet.len() as u32)?;
+	block.u32(packet.len() as u32)?;
+	block.bytes(packet)?;
+	block.option(OPTION_EPB_FLAGS, &(direction as u32).to_le_bytes())?;
+	if let Some(comment) = comment
+	{
+		block.option(OPTION_COMMENT, comment.as_bytes())?;
+	}
+	block.finish(true)
+}
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+
+	fn block(blockType: u32, body: &[&[u8]]) -> Vec<u8>
+	{
+		let body = body.concat();
+		let length = (body.len() as u32 + 12).to_le_bytes();
+		[&blockType.to_le_bytes()[..], &length, &body, &length].concat()
+	}
+
+	#[test]
+	fn sectionHeaderBlockIsExact()
+	{
+		let mut data = [0xffu8; 64];
+		assert_eq!(sectionHeaderBlock(&mut data, "abcde"), Some(44));
+		let expected = block(0x0a0d0d0a, &[
+			&[0x4d, 0x3c, 0x2b, 0x1a],
+			&[1, 0, 0, 0],
+			&[0xff; 8],
+			// shb_userappl, padded from 5 bytes up to 8
+			&[4, 0, 5, 0], b"abcde", &[0, 0, 0],
+			&[0, 0, 0, 0],
+		]);
+		assert_eq!(&data[0..44], &expected[..]);
+	}
+
+	#[test]
+	fn interfaceDescriptionBlockIsExact()
+	{
+		let mut data = [0xffu8; 64];
+		assert_eq!(interfaceDescriptionBlock(&mut data, LINKTYPE_USER0, 0x1234, "uart"), Some(32));
+		let expected = block(0x00000001, &[
+			&[147, 0, 0, 0],
+			&[0x34, 0x12, 0, 0],
+			// if_name, which needs no padding
+			&[2, 0, 4, 0], b"uart",
+			&[0, 0, 0, 0],
+		]);
+		assert_eq!(&data[0..32], &expected[..]);
+	}
+
+	#[test]
+	fn enhancedPacketBlockIsExact()
+	{
+		let mut data = [0xffu8; 64];
+		let timestamp = 0x0102030405060708;
+		assert_eq!(enhancedPacketBlock(&mut data, timestamp, PacketDirection::Inbound, b"hello", None), Some(52));
+		let expected = block(0x00000006, &[
+			&[0, 0, 0, 0],
+			// The timestamp goes high word first
+			&[4, 3, 2, 1, 8, 7, 6, 5],
+			&[5, 0, 0, 0],
+			&[5, 0, 0, 0],
+			b"hello", &[0, 0, 0],
+			&[2, 0, 4, 0], &[1, 0, 0, 0],
+			&[0, 0, 0, 0],
+		]);
+		assert_eq!(&data[0..52], &expected[..]);
+	}
+
+	#[test]
+	fn enhancedPacketBlockMarksDirection()
+	{
+		let mut data = [0u8; 64];
+		let length = enhancedPacketBlock(&mut data, 0, PacketDirection::Outbound, &[], None).unwrap();
+		assert_eq!(length, 44);
+		assert_eq!(&data[28..36], &[2, 0, 4, 0, 2, 0, 0, 0]);
+		assert_eq!(&data[40..44], &44u32.to_le_bytes());
+	}
+
+	#[test]
+	fn enhancedPacketBlockCarriesComment()
+	{
+		let mut data = [0xffu8; 64];
+		let length = enhancedPacketBlock(&mut data, 0, PacketDirection::Inbound, &[], Some("event")).unwrap();
+		assert_eq!(length, 56);
+		assert_eq!(&data[36..48], &[1, 0, 5, 0, b'e', b'v', b'e', b'n', b't', 0, 0, 0]);
+		assert_eq!(&data[48..52], &[0, 0, 0, 0]);
+		assert_eq!(&data[4..8], &56u32.to_le_bytes());
+		assert_eq!(&data[52..56], &56u32.to_le_bytes());
+	}
+
+	#[test]
+	fn blocksNeedRoomForTrailer()
+	{
+		let mut data = [0u8; 52];
+		assert_eq!(enhancedPacketBlock(&mut data[0..51], 0, PacketDirection::Inbound, b"hello", None), None);
+		assert_eq!(enhancedPacketBlock(&mut data, 0, PacketDirection::Inbound, b"hello", None), Some(52));
+		assert_eq!(sectionHeaderBlock(&mut data[0..4], ""), None);
+	}
+}
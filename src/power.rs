@@ -0,0 +1,229 @@
+// SPDX-License-Identifier: BSD-3-Clause
+
+use core::cell::Cell;
+use defmt::{error, info};
+use embassy_futures::select::{Either, select};
+use embassy_stm32::adc::{Adc, Resolution};
+use embassy_stm32::gpio::{Output, Speed};
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::channel::Channel;
+use embassy_sync::signal::Signal;
+use embassy_time::{Duration, Ticker, Timer};
+
+use crate::board::{self, LOAD_SWITCH_ACTIVE, PowerResources, TARGET_POWER_SENSE};
+use crate::capture;
+
+// The ADC is run at 12 bits, which is plenty for a supply monitor
+const ADC_FULL_SCALE: u32 = 4095;
+// How often the target's supply is measured
+const SAMPLE_INTERVAL: Duration = Duration::from_millis(5);
+// How many samples in a row have to be over the limit to trip, so the target's inrush current doesn't trip it
+const TRIP_SAMPLES: u32 = 4;
+// How long a power cycle leaves the target off for when the host doesn't say
+const DEFAULT_CYCLE_TIME: Duration = Duration::from_secs(1);
+
+static POWER_COMMANDS: Channel<CriticalSectionRawMutex, PowerCommand, 1> = Channel::new();
+static POWER_STATUS: Mutex<CriticalSectionRawMutex, Cell<PowerStatus>> =
+	Mutex::new(Cell::new(PowerStatus::new()));
+static TRIPPED: Signal<CriticalSectionRawMutex, bool> = Signal::new();
+
+/// How the target's supply is brought out to the ADC on a board
+pub struct PowerSense
+{
+	/// The ADC's reference voltage
+	pub referenceMillivolts: u32,
+	/// Ratio of the divider between the target's supply and its ADC input
+	pub voltageDivider: u32,
+	/// Resistance of the shunt the target's supply current goes through
+	pub shuntMilliohms: u32,
+	/// Gain of the amplifier between the shunt and its ADC input
+	pub senseGain: u32,
+	/// Current above which the target's power is cut
+	pub overcurrentLimit: u32,
+}
+
+impl PowerSense
+{
+	const fn pinMillivolts(&self, reading: u16) -> u32
+	{
+		reading as u32 * self.referenceMillivolts / ADC_FULL_SCALE
+	}
+
+	/// Work out the target's supply voltage from a reading of the voltage divider
+	pub const fn supplyMillivolts(&self, reading: u16) -> u32
+	{
+		self.pinMillivolts(reading) * self.voltageDivider
+	}
+
+	/// Work out the target's supply current from a reading of the current sense amplifier
+	pub const fn supplyMilliamps(&self, reading: u16) -> u32
+	{
+		self.pinMillivolts(reading) * 1000 / (self.senseGain * self.shuntMilliohms)
+	}
+}
+
+/// What the host can ask the target's load switch to do
+#[derive(Clone, Copy)]
+pub enum PowerCommand
+{
+	On,
+	Off,
+	/// Turn the target off for the given time, then back on again
+	Cycle(Duration),
+}
+
+impl PowerCommand
+{
+	/// Decode a power command from a control request. wValue is 0 for off, 1 for on and 2 for a power cycle, which
+	/// leaves the target off for the number of milliseconds in wIndex (or a second if that's 0)
+	pub fn fromRequest(value: u16, index: u16) -> Option<Self>
+	{
+		match value
+		{
+			0 => Some(Self::Off),
+			1 => Some(Self::On),
+			2 if index == 0 => Some(Self::Cycle(DEFAULT_CYCLE_TIME)),
+			2 => Some(Self::Cycle(Duration::from_millis(index as u64))),
+			_ => None,
+		}
+	}
+}
+
+#[repr(u8)]
+#[derive(Clone, Copy, PartialEq)]
+pub enum PowerState
+{
+	Off = 0,
+	On = 1,
+	/// Power was cut by the overcurrent trip, and stays off until the host turns it back on
+	Tripped = 2,
+}
+
+#[derive(Clone, Copy)]
+pub struct PowerStatus
+{
+	state: PowerState,
+	millivolts: u16,
+	milliamps: u16,
+}
+
+impl PowerStatus
+{
+	const fn new() -> Self
+	{
+		Self { state: PowerState::Off, millivolts: 0, milliamps: 0 }
+	}
+
+	pub fn toData(&self, data: &mut [u8]) -> Option<usize>
+	{
+		// There need to be at least 5 bytes to format out the power status
+		if data.len() < 5
+		{
+			return None;
+		}
+
+		data[0] = self.state as u8;
+		data[1..3].copy_from_slice(&self.millivolts.to_le_bytes());
+		data[3..5].copy_from_slice(&self.milliamps.to_le_bytes());
+		Some(5)
+	}
+}
+
+/// Get the state of the target's power along with the latest measurements of its supply
+pub fn status() -> PowerStatus
+{
+	POWER_STATUS.lock(Cell::get)
+}
+
+/// Ask for the target's power to be switched. Returns false if the last request is still being carried out
+pub fn request(command: PowerCommand) -> bool
+{
+	POWER_COMMANDS.try_send(command).is_ok()
+}
+
+/// Wait for the overcurrent trip to cut the target's power, or for the host to restore it afterwards
+pub async fn trippedChanged() -> bool
+{
+	TRIPPED.wait().await
+}
+
+/// Switch the target's power as the host asks, and keep an eye on its supply while it's on, cutting it if the
+/// target draws too much current
+#[embassy_executor::task]
+pub async fn powerTask(power: PowerResources)
+{
+	// The target starts out powered, just as if there were no load switch
+	let mut loadSwitch = Output::new(power.enable, LOAD_SWITCH_ACTIVE, Speed::Low);
+	let mut adc = Adc::new(power.adc);
+	adc.set_resolution(Resolution::BITS12);
+	let mut voltagePin = power.voltage;
+	let mut currentPin = power.current;
+
+	let mut state = PowerState::On;
+	let mut overcurrentSamples = 0;
+	let mut ticker = Ticker::every(SAMPLE_INTERVAL);
+
+	loop
+	{
+		match select(POWER_COMMANDS.receive(), ticker.next()).await
+		{
+			Either::First(command) =>
+			{
+				if state == PowerState::Tripped
+				{
+					TRIPPED.signal(false);
+				}
+				match command
+				{
+					PowerCommand::On => loadSwitch.set_level(LOAD_SWITCH_ACTIVE),
+					PowerCommand::Off => loadSwitch.set_level(board::inactive(LOAD_SWITCH_ACTIVE)),
+					PowerCommand::Cycle(duration) =>
+					{
+						loadSwitch.set_level(board::inactive(LOAD_SWITCH_ACTIVE));
+						POWER_STATUS.lock(|status| status.set(PowerStatus::new()));
+						Timer::after(duration).await;
+						loadSwitch.set_level(LOAD_SWITCH_ACTIVE);
+					}
+				}
+				state = match command
+				{
+					PowerCommand::Off => PowerState::Off,
+					_ => PowerState::On,
+				};
+				overcurrentSamples = 0;
+				let description = if state == PowerState::On { "on" } else { "off" };
+				info!("Target power {}", description);
+				capture::event(format_args!("Target power {}", description));
+			}
+			Either::Second(()) => {}
+		}
+
+		let millivolts = TARGET_POWER_SENSE.supplyMillivolts(adc.blocking_read(&mut voltagePin));
+		let milliamps = TARGET_POWER_SENSE.supplyMilliamps(adc.blocking_read(&mut currentPin));
+		if state == PowerState::On && milliamps > TARGET_POWER_SENSE.overcurrentLimit
+		{
+			overcurrentSamples += 1;
+			if overcurrentSamples == TRIP_SAMPLES
+			{
+				loadSwitch.set_level(board::inactive(LOAD_SWITCH_ACTIVE));
+				state = PowerState::Tripped;
+				error!("Target drawing {}mA, cutting its power", milliamps);
+				capture::event(format_args!("Target power tripped at {}mA", milliamps));
+				TRIPPED.signal(true);
+			}
+		}
+		else
+		{
+			overcurrentSamples = 0;
+		}
+
+		let status = PowerStatus
+		{
+			state,
+			millivolts: millivolts.min(u16::MAX as u32) as u16,
+			milliamps: milliamps.min(u16::MAX as u32) as u16,
+		};
+		POWER_STATUS.lock(|current| current.set(status));
+	}
+}
@@ -0,0 +1,475 @@
+// SPDX-License-Identifier: BSD-3-Clause
+
+// Number of consecutive correctly predicted bits needed before the checker considers itself locked
+const LOCK_THRESHOLD: u32 = 64;
+// Window over which bit errors are counted to decide if lock has been lost, and how many are too many
+const LOSS_OF_LOCK_WINDOW: u32 = 64;
+const LOSS_OF_LOCK_ERRORS: u32 = 16;
+
+/// The pseudo-random binary sequences we can generate and check. PRBS15, 23 and 31 are sent inverted, as ITU-T
+/// O.150 specifies, so test equipment can check them. PRBS7 isn't an O.150 sequence, and is sent as it comes
+#[repr(u8)]
+#[derive(Clone, Copy)]
+pub enum PrbsPattern
+{
+	/// x^7 + x^6 + 1
+	Prbs7 = 7,
+	/// x^15 + x^14 + 1
+	Prbs15 = 15,
+	/// x^23 + x^18 + 1
+	Prbs23 = 23,
+	/// x^31 + x^28 + 1
+	Prbs31 = 31,
+}
+
+impl TryFrom<u16> for PrbsPattern
+{
+	type Error = ();
+
+	fn try_from(value: u16) -> Result<Self, Self::Error>
+	{
+		match value
+		{
+			7 => Ok(Self::Prbs7),
+			15 => Ok(Self::Prbs15),
+			23 => Ok(Self::Prbs23),
+			31 => Ok(Self::Prbs31),
+			_ => Err(()),
+		}
+	}
+}
+
+impl PrbsPattern
+{
+	/// The order of the polynomial, which is also the length of the shift register
+	const fn order(self) -> u32
+	{
+		self as u32
+	}
+
+	/// The second (non-leading) feedback tap of the polynomial
+	const fn tap(self) -> u32
+	{
+		match self
+		{
+			Self::Prbs7 => 6,
+			Self::Prbs15 => 14,
+			Self::Prbs23 => 18,
+			Self::Prbs31 => 28,
+		}
+	}
+
+	/// Whether the sequence goes out inverted from what the shift register produces
+	const fn inverted(self) -> bool
+	{
+		!matches!(self, Self::Prbs7)
+	}
+
+	const fn mask(self) -> u32
+	{
+		(1u32 << self.order()) - 1
+	}
+
+	/// Compute the next bit of the sequence from the current register state
+	const fn feedback(self, state: u32) -> u32
+	{
+		((state >> (self.order() - 1)) ^ (state >> (self.tap() - 1))) & 1
+	}
+}
+
+/// Generates a PRBS as a stream of characters, least significant bit first to match UART bit order
+pub struct PrbsGenerator
+{
+	pattern: PrbsPattern,
+	state: u32,
+}
+
+impl PrbsGenerator
+{
+	pub const fn new(pattern: PrbsPattern) -> Self
+	{
+		// Any non-zero seed works, the all-1's seed is conventional
+		Self { pattern, state: pattern.mask() }
+	}
+
+	fn nextBit(&mut self) -> u8
+	{
+		let bit = self.pattern.feedback(self.state);
+		self.state = ((self.state << 1) | bit) & self.pattern.mask();
+		(bit ^ self.pattern.inverted() as u32) as u8
+	}
+
+	/// Generate the next character of the sequence, using only the bottom `bits` bits
+	pub fn nextCharacter(&mut self, bits: u8) -> u8
+	{
+		(0..bits).fold(0, |character, bit| character | (self.nextBit() << bit))
+	}
+
+	/// Fill a buffer with the next characters of the sequence
+	pub fn fill(&mut self, buffer: &mut [u8], bits: u8)
+	{
+		for character in buffer.iter_mut()
+		{
+			*character = self.nextCharacter(bits);
+		}
+	}
+}
+
+#[derive(Clone, Copy, Default)]
+pub struct PrbsStatistics
+{
+	pub bitsChecked: u64,
+	pub bitErrors: u64,
+	pub characterErrors: u64,
+	pub lockLosses: u32,
+}
+
+/// Checks a received stream against a PRBS.
+///
+/// The checker starts out self-synchronising, predicting each bit from the previous ones received, until it
+/// has seen enough consecutive correct bits to be locked. Once locked it free-runs, shifting in its own
+/// predictions so that each bit error is only counted once, and drops back to synchronising if too many
+/// errors arrive in a short space as that indicates a slip rather than a noisy line.
+pub struct PrbsChecker
+{
+	pattern: PrbsPattern,
+	state: u32,
+	locked: bool,
+	run: u32,
+	windowBits: u32,
+	windowErrors: u32,
+	statistics: PrbsStatistics,
+}
+
+impl PrbsChecker
+{
+	pub const fn new(pattern: PrbsPattern) -> Self
+	{
+		Self
+		{
+			pattern,
+			state: 0,
+			locked: false,
+			run: 0,
+			windowBits: 0,
+			windowErrors: 0,
+			statistics: PrbsStatistics { bitsChecked: 0, bitErrors: 0, characterErrors: 0, lockLosses: 0 },
+		}
+	}
+
+	pub fn locked(&self) -> bool
+	{
+		self.locked
+	}
+
+	pub fn statistics(&self) -> PrbsStatistics
+	{
+		self.statistics
+	}
+
+	/// Check a received character, of which only the bottom `bits` bits are used
+	pub fn checkCharacter(&mut self, character: u8, bits: u8)
+	{
+		let mut characterErrors = 0;
+		for bit in 0..bits
+		{
+			// Undo any inversion so the bits can be checked against the shift register
+			let bit = ((character >> bit) & 1) ^ self.pattern.inverted() as u8;
+			if !self.checkBit(bit as u32)
+			{
+				characterErrors += 1;
+			}
+		}
+
+		if self.locked && characterErrors != 0
+		{
+			self.statistics.characterErrors += 1;
+		}
+	}
+
+	/// Check a buffer of received characters
+	pub fn check(&mut self, buffer: &[u8], bits: u8)
+	{
+		for character in buffer
+		{
+			self.checkCharacter(*character, bits);
+		}
+	}
+
+	/// Returns false if the bit was counted as an error
+	fn checkBit(&mut self, bit: u32) -> bool
+	{
+		let expected = self.pattern.feedback(self.state);
+		let correct = bit == expected;
+
+		if self.locked
+		{
+			self.statistics.bitsChecked += 1;
+			self.windowBits += 1;
+			if !correct
+			{
+				self.statistics.bitErrors += 1;
+				self.windowErrors += 1;
+			}
+
+			// Free-run on our own prediction so each error is only seen once
+			self.state = ((self.state << 1) | expected) & self.pattern.mask();
+
+			if self.windowBits == LOSS_OF_LOCK_WINDOW
+			{
+				if self.windowErrors >= LOSS_OF_LOCK_ERRORS
+				{
+					self.locked = false;
+					self.run = 0;
+					self.statistics.lockLosses += 1;
+				}
+				self.windowBits = 0;
+				self.windowErrors = 0;
+			}
+			correct
+		}
+		else
+		{
+			// Self-synchronise from what was actually received until enough bits in a row were right.
+			// The all-0's state is never part of the sequence, so a stuck-low line must not count
+			self.state = ((self.state << 1) | bit) & self.pattern.mask();
+			self.run = if correct && self.state != 0 { self.run + 1 } else { 0 };
+			if self.run >= LOCK_THRESHOLD + self.pattern.order()
+			{
+				self.locked = true;
+				self.windowBits = 0;
+				self.windowErrors = 0;
+			}
+			true
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+
+	const PATTERNS: [PrbsPattern; 4] = [PrbsPattern::Prbs7, PrbsPattern::Prbs15, PrbsPattern::Prbs23, PrbsPattern::Prbs31];
+
+	fn bits(characters: &[u8], bitsPerCharacter: u8) -> Vec<u8>
+	{
+		characters.iter()
+			.flat_map(|character| (0..bitsPerCharacter).map(move |bit| (character >> bit) & 1))
+			.collect()
+	}
+
+	fn generate(pattern: PrbsPattern, length: usize) -> Vec<u8>
+	{
+		let mut generator = PrbsGenerator::new(pattern);
+		let mut buffer = vec![0; length];
+		generator.fill(&mut buffer, 8);
+		buffer
+	}
+
+	/// A checker that's been fed enough of the sequence to lock, along with the generator to carry on from
+	fn lockedChecker(pattern: PrbsPattern) -> (PrbsChecker, PrbsGenerator)
+	{
+		let mut generator = PrbsGenerator::new(pattern);
+		let mut checker = PrbsChecker::new(pattern);
+		while !checker.locked()
+		{
+			checker.checkCharacter(generator.nextCharacter(8), 8);
+		}
+		(checker, generator)
+	}
+
+	#[test]
+	fn patternsFromOrder()
+	{
+		for pattern in PATTERNS
+		{
+			assert!(matches!(PrbsPattern::try_from(pattern as u16), Ok(decoded) if decoded as u8 == pattern as u8));
+		}
+		for order in [0, 6, 8, 16, 32]
+		{
+			assert!(PrbsPattern::try_from(order).is_err());
+		}
+	}
+
+	#[test]
+	fn generatorFollowsPolynomial()
+	{
+		// Straight from the polynomial x^n + x^k + 1, each bit is the XOR of the ones n and k bits before it. The
+		// inversion cancels out between those two, so an inverted sequence has each bit flipped from that
+		for pattern in PATTERNS
+		{
+			let (order, tap) = (pattern.order() as usize, pattern.tap() as usize);
+			let inverted = pattern.inverted() as u8;
+			let sequence = bits(&generate(pattern, 512), 8);
+			for index in order..sequence.len()
+			{
+				let expected = sequence[index - order] ^ sequence[index - tap] ^ inverted;
+				assert_eq!(sequence[index], expected, "PRBS{} bit {}", order, index);
+			}
+		}
+	}
+
+	#[test]
+	fn generatorStartsFromAllOnes()
+	{
+		// Worked by hand: the taps see matching 1's, so feed back 0's, until the seed has shifted along far enough
+		// that only the leading tap still holds a 1
+		let sequence = bits(&generate(PrbsPattern::Prbs7, 2), 8);
+		assert_eq!(sequence, [0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 1, 1, 0, 0]);
+	}
+
+	#[test]
+	fn o150PatternsAreInverted()
+	{
+		// The seed starts them off the same way as PRBS7, with a run as long as the second tap's position before
+		// the first flip, but with every bit inverted
+		for pattern in [PrbsPattern::Prbs15, PrbsPattern::Prbs23, PrbsPattern::Prbs31]
+		{
+			let tap = pattern.tap() as usize;
+			let sequence = bits(&generate(pattern, 4), 8);
+			assert!(sequence[0..tap].iter().all(|bit| *bit == 1), "PRBS{} isn't inverted", pattern.order());
+			assert_eq!(sequence[tap], 0);
+		}
+	}
+
+	#[test]
+	fn checkerNeedsInvertedPatterns()
+	{
+		// PRBS15 as the shift register produces it doesn't follow the inverted sequence at all
+		let mut checker = PrbsChecker::new(PrbsPattern::Prbs15);
+		let uninverted = generate(PrbsPattern::Prbs15, 256).iter().map(|character| !character).collect::<Vec<_>>();
+		checker.check(&uninverted, 8);
+		assert!(!checker.locked());
+	}
+
+	#[test]
+	fn generatorHasMaximalPeriod()
+	{
+		// PRBS31's period is too long to walk here, but it's built exactly the same way as the rest
+		for pattern in [PrbsPattern::Prbs7, PrbsPattern::Prbs15, PrbsPattern::Prbs23]
+		{
+			let mut generator = PrbsGenerator::new(pattern);
+			let period = (1u32 << pattern.order()) - 1;
+			let mut ones = 0;
+			for step in 1..=period
+			{
+				ones += generator.nextBit() as u32;
+				assert!(step == period || generator.state != pattern.mask(), "PRBS{} repeats early", pattern.order());
+			}
+			assert_eq!(generator.state, pattern.mask());
+			// A maximal length sequence has one more 1 than it has 0's, or one fewer when it's inverted
+			let expectedOnes = if pattern.inverted() { (1 << (pattern.order() - 1)) - 1 } else { 1 << (pattern.order() - 1) };
+			assert_eq!(ones, expectedOnes, "PRBS{}", pattern.order());
+		}
+	}
+
+	#[test]
+	fn checkerLocksOnSequence()
+	{
+		for pattern in PATTERNS
+		{
+			for bitsPerCharacter in [7, 8]
+			{
+				let mut generator = PrbsGenerator::new(pattern);
+				let mut checker = PrbsChecker::new(pattern);
+				let mut bitsFed = 0;
+				while !checker.locked()
+				{
+					checker.checkCharacter(generator.nextCharacter(bitsPerCharacter), bitsPerCharacter);
+					bitsFed += bitsPerCharacter as u32;
+					assert!(bitsFed <= LOCK_THRESHOLD + 2 * pattern.order() + 8, "PRBS{} took too long to lock", pattern.order());
+				}
+				assert!(bitsFed >= LOCK_THRESHOLD + pattern.order());
+				// Nothing before lock gets counted, only whatever's left of the character it happened in
+				assert!(checker.statistics().bitsChecked < bitsPerCharacter as u64);
+				assert_eq!(checker.statistics().bitErrors, 0);
+			}
+		}
+	}
+
+	#[test]
+	fn checkerLocksPartWayThroughSequence()
+	{
+		let mut generator = PrbsGenerator::new(PrbsPattern::Prbs15);
+		let mut skipped = [0; 1000];
+		generator.fill(&mut skipped, 8);
+
+		let mut checker = PrbsChecker::new(PrbsPattern::Prbs15);
+		let mut buffer = [0; 32];
+		generator.fill(&mut buffer, 8);
+		checker.check(&buffer, 8);
+		assert!(checker.locked());
+	}
+
+	#[test]
+	fn checkerNeverLocksOnStuckLine()
+	{
+		let mut checker = PrbsChecker::new(PrbsPattern::Prbs7);
+		checker.check(&[0; 256], 8);
+		assert!(!checker.locked());
+	}
+
+	#[test]
+	fn checkerCountsInjectedErrors()
+	{
+		let (mut checker, mut generator) = lockedChecker(PrbsPattern::Prbs23);
+		let bitsChecked = checker.statistics().bitsChecked;
+		let mut buffer = [0; 400];
+		generator.fill(&mut buffer, 8);
+		// Flip one bit in every 20th character, which is well under the loss of lock rate
+		let mut injected = 0;
+		for character in buffer.iter_mut().step_by(20)
+		{
+			*character ^= 1 << (injected % 8);
+			injected += 1;
+		}
+		checker.check(&buffer, 8);
+
+		let statistics = checker.statistics();
+		assert!(checker.locked());
+		assert_eq!(statistics.bitsChecked - bitsChecked, buffer.len() as u64 * 8);
+		// Free-running means each flipped bit only counts once, rather than again as it passes each tap
+		assert_eq!(statistics.bitErrors, injected);
+		assert_eq!(statistics.characterErrors, injected);
+		assert_eq!(statistics.lockLosses, 0);
+	}
+
+	#[test]
+	fn checkerCountsBurstAsOneCharacterError()
+	{
+		let (mut checker, mut generator) = lockedChecker(PrbsPattern::Prbs7);
+		let character = generator.nextCharacter(8);
+		checker.checkCharacter(character ^ 0x0f, 8);
+
+		let statistics = checker.statistics();
+		assert_eq!(statistics.bitErrors, 4);
+		assert_eq!(statistics.characterErrors, 1);
+	}
+
+	#[test]
+	fn checkerLosesLockOnSlip()
+	{
+		let (mut checker, mut generator) = lockedChecker(PrbsPattern::Prbs15);
+		// Losing a bit puts the whole stream out of step, which looks like half the bits being wrong. That has
+		// to be noticed by the end of the next full loss of lock window
+		generator.nextCharacter(1);
+		let mut charactersFed = 0;
+		while checker.locked()
+		{
+			checker.checkCharacter(generator.nextCharacter(8), 8);
+			charactersFed += 1;
+			assert!(charactersFed <= 2 * LOSS_OF_LOCK_WINDOW / 8, "Slip not noticed");
+		}
+		assert_eq!(checker.statistics().lockLosses, 1);
+
+		// After which it picks the sequence back up from where it now is
+		let bitErrors = checker.statistics().bitErrors;
+		let mut buffer = [0; 16];
+		generator.fill(&mut buffer, 8);
+		checker.check(&buffer, 8);
+		assert!(checker.locked());
+		assert_eq!(checker.statistics().lockLosses, 1);
+		assert_eq!(checker.statistics().bitErrors, bitErrors);
+	}
+}
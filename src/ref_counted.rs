@@ -8,11 +8,16 @@ use core::
 type BorrowCounter = isize;
 const UNUSED: BorrowCounter = 0;
 
+/// Head of a pool's list of slots that have been released and can be handed out again
+type FreeList<T> = Cell<Option<NonNull<RcInner<T>>>>;
+
 /// This represents a pool capable of holding N T's
 pub struct RcPool<T: Sized, const N: usize>
 {
-	pool: [MaybeUninit<RcInner<T>>; N],
-	allocated: usize,
+	pool: [UnsafeCell<MaybeUninit<RcInner<T>>>; N],
+	// How many slots have ever been handed out; slots beyond this have never been used
+	allocated: Cell<usize>,
+	freeList: FreeList<T>,
 }
 
 impl<T, const N: usize> RcPool<T, N>
@@ -22,32 +27,63 @@ impl<T, const N: usize> RcPool<T, N>
 	{
 		Self
 		{
-			pool: [const { MaybeUninit::uninit() }; N],
-			allocated: 0,
+			pool: [const { UnsafeCell::new(MaybeUninit::uninit()) }; N],
+			allocated: Cell::new(0),
+			freeList: Cell::new(None),
 		}
 	}
 }
 
+impl<T, const N: usize> Default for RcPool<T, N>
+{
+	fn default() -> Self
+	{
+		Self::new()
+	}
+}
+
 impl<T: Sized, const N: usize> RcPool<T, N>
 {
-	pub fn alloc(&mut self, value: T) -> Option<Rc<T>>
+	/// Allocate a slot from the pool for value. The pool must live forever as the Rc's hand their slots back
+	/// to it when the last reference to one goes away
+	pub fn alloc(&'static self, value: T) -> Option<Rc<T>>
 	{
-		if self.allocated == N
+		// Prefer recycling a released slot, and only then start on one that's never been used
+		let slot = match self.freeList.get()
 		{
-			None
-		}
-		else
+			Some(slot) =>
+			{
+				self.freeList.set(unsafe { slot.as_ref() }.nextFree.get());
+				slot
+			}
+			None =>
+			{
+				let allocated = self.allocated.get();
+				if allocated == N
+				{
+					return None;
+				}
+				self.allocated.set(allocated + 1);
+				unsafe { NonNull::new_unchecked(self.pool[allocated].get().cast()) }
+			}
+		};
+
+		unsafe
 		{
-			let inner = self.pool[self.allocated]
-				.write(
-					RcInner
-					{
-						refCount: Cell::new(1),
-						borrowCount: Cell::new(UNUSED),
-						value: UnsafeCell::new(value),
-					}
-				);
-			Some(Rc::fromInner(inner))
+			slot.write
+			(
+				RcInner
+				{
+					refCount: Cell::new(1),
+					// All the strong references together hold one weak reference, so the slot outlives the value
+					weakCount: Cell::new(1),
+					borrowCount: Cell::new(UNUSED),
+					value: UnsafeCell::new(value),
+					freeList: NonNull::from(&self.freeList),
+					nextFree: Cell::new(None),
+				}
+			);
+			Some(Rc::fromInnerIn(slot))
 		}
 	}
 }
@@ -56,8 +92,12 @@ impl<T: Sized, const N: usize> RcPool<T, N>
 struct RcInner<T: Sized>
 {
 	refCount: Cell<usize>,
+	weakCount: Cell<usize>,
 	borrowCount: Cell<BorrowCounter>,
 	value: UnsafeCell<T>,
+	// The free list of the pool this came from, and the next free slot after this one when on that list
+	freeList: NonNull<FreeList<T>>,
+	nextFree: Cell<Option<NonNull<RcInner<T>>>>,
 }
 
 impl<T> RcInner<T>
@@ -89,6 +129,34 @@ impl<T> RcInner<T>
 	{
 		self.refCount.set(self.count() - 1);
 	}
+
+	fn weakCount(&self) -> usize
+	{
+		self.weakCount.get()
+	}
+
+	fn incWeakCount(&self)
+	{
+		let count = self.weakCount().wrapping_add(1);
+		self.weakCount.set(count);
+		if count == 0
+		{
+			panic!("Weak reference count overflowed for Rc at {:?}", self as *const Self);
+		}
+	}
+
+	/// Drop a weak reference, handing the slot back to its pool if it was the last one
+	unsafe fn decWeakCount(ptr: NonNull<Self>)
+	{
+		let inner = unsafe { ptr.as_ref() };
+		inner.weakCount.set(inner.weakCount() - 1);
+		if inner.weakCount() == 0
+		{
+			let freeList = unsafe { inner.freeList.as_ref() };
+			inner.nextFree.set(freeList.get());
+			freeList.set(Some(ptr));
+		}
+	}
 }
 
 /// A reference counted pointer to some data, allocated from a pool
@@ -100,15 +168,6 @@ pub struct Rc<T: Sized>
 
 impl<T: Sized> Rc<T>
 {
-	fn fromInner(inner: &mut RcInner<T>) -> Self
-	{
-		Self
-		{
-			ptr: unsafe { NonNull::new_unchecked(inner) },
-			marker: PhantomData,
-		}
-	}
-
 	#[inline]
 	unsafe fn fromInnerIn(ptr: NonNull<RcInner<T>>) -> Self
 	{
@@ -121,11 +180,34 @@ impl<T: Sized> Rc<T>
 		unsafe { self.ptr.as_ref() }
 	}
 
+	/// Make a new weak reference to this data, which does not keep it alive
+	pub fn downgrade(this: &Self) -> Weak<T>
+	{
+		this.inner().incWeakCount();
+		Weak { ptr: this.ptr, marker: PhantomData }
+	}
+
 	#[inline]
 	pub fn borrow(&self) -> Ref<'_, T>
 	{
-		let value = unsafe { NonNull::new_unchecked(self.inner().value.get()) };
-		Ref { value, marker: PhantomData }
+		match self.tryBorrow()
+		{
+			Ok(sharedRef) => sharedRef,
+			Err(_) => panic!("Rc already mutably borrowed"),
+		}
+	}
+
+	pub fn tryBorrow(&self) -> Result<Ref<'_, T>, BorrowError>
+	{
+		match BorrowRef::new(&self.inner().borrowCount)
+		{
+			Some(borrow) =>
+			{
+				let value = unsafe { NonNull::new_unchecked(self.inner().value.get()) };
+				Ok(Ref { value, _borrow: borrow, marker: PhantomData })
+			},
+			None => Err(BorrowError),
+		}
 	}
 
 	#[inline]
@@ -134,7 +216,7 @@ impl<T: Sized> Rc<T>
 		match self.tryBorrowMut()
 		{
 			Ok(mutRef) => mutRef,
-			Err(_) => panic!("Rc already mutably borrowed"),
+			Err(_) => panic!("Rc already borrowed"),
 		}
 	}
 
@@ -177,15 +259,67 @@ impl<T: Sized> Drop for Rc<T>
 			// If we were the last reference, destroy the contained object
 			unsafe
 			{
-				ptr::drop_in_place(&mut (*self.ptr.as_ptr()).value);
+				ptr::drop_in_place(self.inner().value.get());
+				// Then give up the weak reference all the strong ones held, which releases the slot if no Weak's remain
+				RcInner::decWeakCount(self.ptr);
 			}
 		}
 	}
 }
 
+/// A non-owning reference to some data in an Rc, which can be upgraded back to an Rc while the data is alive
+pub struct Weak<T: Sized>
+{
+	ptr: NonNull<RcInner<T>>,
+	marker: PhantomData<RcInner<T>>,
+}
+
+impl<T: Sized> Weak<T>
+{
+	#[inline(always)]
+	fn inner(&self) -> &RcInner<T>
+	{
+		unsafe { self.ptr.as_ref() }
+	}
+
+	/// Get a strong reference to the data, if it has not yet been destroyed
+	pub fn upgrade(&self) -> Option<Rc<T>>
+	{
+		if self.inner().count() == 0
+		{
+			None
+		}
+		else
+		{
+			self.inner().incCount();
+			Some(unsafe { Rc::fromInnerIn(self.ptr) })
+		}
+	}
+}
+
+impl<T: Sized> Clone for Weak<T>
+{
+	#[inline]
+	fn clone(&self) -> Self
+	{
+		self.inner().incWeakCount();
+		Self { ptr: self.ptr, marker: PhantomData }
+	}
+}
+
+impl<T: Sized> Drop for Weak<T>
+{
+	#[inline]
+	fn drop(&mut self)
+	{
+		unsafe { RcInner::decWeakCount(self.ptr) }
+	}
+}
+
 pub struct Ref<'b, T: ?Sized + 'b>
 {
 	value: NonNull<T>,
+	_borrow: BorrowRef<'b>,
 	marker: PhantomData<&'b T>,
 }
 
@@ -200,6 +334,7 @@ impl<T: ?Sized> Deref for Ref<'_, T>
 	}
 }
 
+pub struct BorrowError;
 pub struct BorrowMutError;
 
 pub struct RefMut<'b, T: ?Sized + 'b>
@@ -228,6 +363,41 @@ impl<T: ?Sized> DerefMut for RefMut<'_, T>
 	}
 }
 
+struct BorrowRef<'b>
+{
+	borrow: &'b Cell<BorrowCounter>,
+}
+
+impl<'b> BorrowRef<'b>
+{
+	#[inline]
+	fn new(borrow: &'b Cell<BorrowCounter>) -> Option<Self>
+	{
+		// Shared borrows count up from UNUSED, so anything below it means a mutable borrow is live
+		let count = borrow.get();
+		if count < UNUSED || count == BorrowCounter::MAX
+		{
+			None
+		}
+		else
+		{
+			borrow.set(count + 1);
+			Some(BorrowRef { borrow })
+		}
+	}
+}
+
+impl Drop for BorrowRef<'_>
+{
+	#[inline]
+	fn drop(&mut self)
+	{
+		let borrow = self.borrow.get();
+		debug_assert!(borrow > UNUSED);
+		self.borrow.set(borrow - 1);
+	}
+}
+
 struct BorrowRefMut<'b>
 {
 	borrow: &'b Cell<BorrowCounter>,
@@ -260,3 +430,162 @@ impl Drop for BorrowRefMut<'_>
 		self.borrow.replace(borrow + 1);
 	}
 }
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+
+	// Counts how many times values holding the counter have been dropped
+	struct Tracked(std::rc::Rc<Cell<usize>>);
+
+	impl Drop for Tracked
+	{
+		fn drop(&mut self)
+		{
+			self.0.set(self.0.get() + 1);
+		}
+	}
+
+	// Run a test against a pool that lives for 'static as far as the Rc's know, but gets cleaned up after so
+	// nothing shows up as leaked under Miri. The test must have dropped everything it took from the pool
+	fn withPool<T: 'static, const N: usize>(test: impl FnOnce(&'static RcPool<T, N>))
+	{
+		let pool: *mut RcPool<T, N> = Box::into_raw(Box::new(RcPool::new()));
+		test(unsafe { &*pool });
+		drop(unsafe { Box::from_raw(pool) });
+	}
+
+	#[test]
+	fn borrowsSeeValue()
+	{
+		withPool::<u32, 1>(|pool|
+		{
+			let rc = pool.alloc(5).unwrap();
+			assert_eq!(*rc.borrow(), 5);
+			*rc.borrowMut() += 1;
+			let clone = rc.clone();
+			assert_eq!(*clone.borrow(), 6);
+		});
+	}
+
+	#[test]
+	fn borrowsExcludeMutableBorrows()
+	{
+		withPool::<u32, 1>(|pool|
+		{
+			let rc = pool.alloc(0).unwrap();
+			{
+				let first = rc.borrow();
+				let second = rc.tryBorrow();
+				assert!(second.is_ok());
+				assert!(matches!(rc.tryBorrowMut(), Err(BorrowMutError)));
+				drop(first);
+				assert!(matches!(rc.tryBorrowMut(), Err(BorrowMutError)));
+			}
+			{
+				let mutRef = rc.borrowMut();
+				assert!(matches!(rc.tryBorrow(), Err(BorrowError)));
+				assert!(matches!(rc.clone().tryBorrowMut(), Err(BorrowMutError)));
+				drop(mutRef);
+			}
+			assert!(rc.tryBorrow().is_ok());
+			assert!(rc.tryBorrowMut().is_ok());
+		});
+	}
+
+	#[test]
+	fn valueDropsWithLastReference()
+	{
+		let drops = std::rc::Rc::new(Cell::new(0));
+		withPool::<Tracked, 1>(|pool|
+		{
+			let rc = pool.alloc(Tracked(drops.clone())).unwrap();
+			let clone = rc.clone();
+			drop(rc);
+			assert_eq!(drops.get(), 0);
+			drop(clone);
+			assert_eq!(drops.get(), 1);
+		});
+	}
+
+	#[test]
+	fn poolExhausts()
+	{
+		withPool::<u32, 2>(|pool|
+		{
+			let first = pool.alloc(1).unwrap();
+			let second = pool.alloc(2).unwrap();
+			assert!(pool.alloc(3).is_none());
+			drop(first);
+			let third = pool.alloc(3).unwrap();
+			assert!(pool.alloc(4).is_none());
+			assert_eq!((*second.borrow(), *third.borrow()), (2, 3));
+		});
+	}
+
+	#[test]
+	fn freedSlotsAreReused()
+	{
+		withPool::<u32, 3>(|pool|
+		{
+			let first = pool.alloc(1).unwrap();
+			let second = pool.alloc(2).unwrap();
+			let (firstSlot, secondSlot) = (first.ptr, second.ptr);
+			assert!(firstSlot != secondSlot);
+
+			// The most recently released slot is handed out first
+			drop(first);
+			drop(second);
+			let reused = pool.alloc(3).unwrap();
+			assert!(reused.ptr == secondSlot);
+			let reused2 = pool.alloc(4).unwrap();
+			assert!(reused2.ptr == firstSlot);
+			// Before finally moving on to the slot that's never been used
+			let fresh = pool.alloc(5).unwrap();
+			assert!(fresh.ptr != firstSlot && fresh.ptr != secondSlot);
+			assert_eq!(*reused.borrow(), 3);
+		});
+	}
+
+	#[test]
+	fn weakUpgradesWhileAlive()
+	{
+		withPool::<u32, 1>(|pool|
+		{
+			let rc = pool.alloc(7).unwrap();
+			let weak = Rc::downgrade(&rc);
+			let upgraded = weak.upgrade().unwrap();
+			assert_eq!(*upgraded.borrow(), 7);
+			// The upgraded Rc keeps the value alive on its own
+			drop(rc);
+			let again = weak.clone().upgrade().unwrap();
+			assert_eq!(*again.borrow(), 7);
+		});
+	}
+
+	#[test]
+	fn weakFailsToUpgradeAfterLastDrop()
+	{
+		let drops = std::rc::Rc::new(Cell::new(0));
+		withPool::<Tracked, 1>(|pool|
+		{
+			let rc = pool.alloc(Tracked(drops.clone())).unwrap();
+			let weak = Rc::downgrade(&rc);
+			let weakClone = weak.clone();
+			drop(rc);
+			assert_eq!(drops.get(), 1);
+			assert!(weak.upgrade().is_none());
+
+			// The slot stays out of the pool until the last Weak goes too
+			assert!(pool.alloc(Tracked(drops.clone())).is_none());
+			assert_eq!(drops.get(), 2);
+			drop(weak);
+			assert!(pool.alloc(Tracked(drops.clone())).is_none());
+			drop(weakClone);
+			let rc = pool.alloc(Tracked(drops.clone())).unwrap();
+			drop(rc);
+			assert_eq!(drops.get(), 4);
+		});
+	}
+}
@@ -1,6 +1,7 @@
 // SPDX-License-Identifier: BSD-3-Clause
 
 use core::{pin::Pin, task::{Context, Poll}};
+use embassy_futures::select::{Either, Either3, Either4};
 
 enum MaybeDone<Fut: Future>
 {
@@ -8,6 +9,8 @@ enum MaybeDone<Fut: Future>
 	Future(Fut),
 	// A the output of a Future that has completed
 	Done(Fut::Output),
+	// The output of the completed Future has been handed back out
+	Taken,
 }
 
 impl<Fut: Future> MaybeDone<Fut>
@@ -29,44 +32,354 @@ impl<Fut: Future> MaybeDone<Fut>
 			_ => true,
 		}
 	}
+
+	fn take(self: Pin<&mut Self>) -> Fut::Output
+	{
+		// The Future has already been dropped by the time we get here, so moving the output out is fine
+		let this = unsafe { self.get_unchecked_mut() };
+		match core::mem::replace(this, Self::Taken)
+		{
+			Self::Done(result) => result,
+			_ => panic!("Output taken from a Future that has not completed"),
+		}
+	}
+}
+
+/// Define a combinator that runs a fixed number of Futures concurrently to completion, returning their
+/// outputs as a tuple in the order the Futures were given
+macro_rules! defineRun
+{
+	($name:ident; $($future:ident: $type:ident),+) =>
+	{
+		pub struct $name<$($type: Future),+>
+		{
+			$($future: MaybeDone<$type>,)+
+		}
+
+		impl<$($type: Future),+> $name<$($type),+>
+		{
+			pub fn new($($future: $type),+) -> Self
+			{
+				Self
+				{
+					$($future: MaybeDone::Future($future),)+
+				}
+			}
+		}
+
+		impl<$($type: Future),+> Future for $name<$($type),+>
+		{
+			type Output = ($(<$type as Future>::Output,)+);
+
+			fn poll(self: Pin<&mut Self>, ctx: &mut Context<'_>) -> Poll<Self::Output>
+			{
+				let this = unsafe { self.get_unchecked_mut() };
+				// Every unfinished Future has to be polled on every wake, not just up to the first pending one,
+				// otherwise the later ones never get to register their wakers
+				let mut allDone = true;
+				$(allDone &= unsafe { Pin::new_unchecked(&mut this.$future) }.poll(ctx);)+
+
+				if allDone
+				{
+					Poll::Ready(($(unsafe { Pin::new_unchecked(&mut this.$future) }.take(),)+))
+				}
+				else
+				{
+					Poll::Pending
+				}
+			}
+		}
+	};
 }
 
-pub struct RunTwo<Future1: Future, Future2: Future>
+defineRun!(RunTwo; future1: Future1, future2: Future2);
+defineRun!(RunThree; future1: Future1, future2: Future2, future3: Future3);
+defineRun!(RunFour; future1: Future1, future2: Future2, future3: Future3, future4: Future4);
+defineRun!(RunFive; future1: Future1, future2: Future2, future3: Future3, future4: Future4, future5: Future5);
+defineRun!
+(
+	RunSix;
+	future1: Future1, future2: Future2, future3: Future3, future4: Future4, future5: Future5, future6: Future6
+);
+
+/// Run all the given Futures concurrently to completion, picking the combinator for however many there are
+#[macro_export]
+macro_rules! runAll
+{
+	($future1:expr, $future2:expr $(,)?) =>
+		{ $crate::run_multiple::RunTwo::new($future1, $future2) };
+	($future1:expr, $future2:expr, $future3:expr $(,)?) =>
+		{ $crate::run_multiple::RunThree::new($future1, $future2, $future3) };
+	($future1:expr, $future2:expr, $future3:expr, $future4:expr $(,)?) =>
+		{ $crate::run_multiple::RunFour::new($future1, $future2, $future3, $future4) };
+	($future1:expr, $future2:expr, $future3:expr, $future4:expr, $future5:expr $(,)?) =>
+		{ $crate::run_multiple::RunFive::new($future1, $future2, $future3, $future4, $future5) };
+	($future1:expr, $future2:expr, $future3:expr, $future4:expr, $future5:expr, $future6:expr $(,)?) =>
+		{ $crate::run_multiple::RunSix::new($future1, $future2, $future3, $future4, $future5, $future6) };
+}
+
+pub use runAll;
+
+/// Tracks which Future a fair select should poll first, so that each gets its turn at going first no matter how
+/// busy the others are
+pub struct RoundRobin
+{
+	next: usize,
+}
+
+impl RoundRobin
 {
-	future1: MaybeDone<Future1>,
-	future2: MaybeDone<Future2>,
+	pub const fn new() -> Self
+	{
+		Self { next: 0 }
+	}
+}
+
+impl Default for RoundRobin
+{
+	fn default() -> Self
+	{
+		Self::new()
+	}
 }
 
-impl<Future1: Future, Future2: Future> RunTwo<Future1, Future2>
+/// Define a select combinator that completes with the output of the first of its Futures to complete, polling
+/// them in rotation starting from the one after whichever won last time rather than always in the same order
+macro_rules! defineSelect
 {
-	pub fn new(future1: Future1, future2: Future2) -> Self
+	($name:ident, $either:ident; $($index:literal => $future:ident: $type:ident => $variant:ident),+) =>
 	{
-		Self
+		pub struct $name<'a, $($type: Future),+>
 		{
-			future1: MaybeDone::Future(future1),
-			future2: MaybeDone::Future(future2),
+			rotation: &'a mut RoundRobin,
+			$($future: $type,)+
 		}
-	}
+
+		impl<'a, $($type: Future),+> $name<'a, $($type),+>
+		{
+			pub fn new(rotation: &'a mut RoundRobin, $($future: $type),+) -> Self
+			{
+				Self
+				{
+					rotation,
+					$($future,)+
+				}
+			}
+		}
+
+		impl<$($type: Future),+> Future for $name<'_, $($type),+>
+		{
+			type Output = $either<$(<$type as Future>::Output),+>;
+
+			fn poll(self: Pin<&mut Self>, ctx: &mut Context<'_>) -> Poll<Self::Output>
+			{
+				let this = unsafe { self.get_unchecked_mut() };
+				const COUNT: usize = [$($index),+].len();
+
+				for offset in 0..COUNT
+				{
+					let index = (this.rotation.next + offset) % COUNT;
+					$(
+						if index == $index
+						{
+							if let Poll::Ready(result) = unsafe { Pin::new_unchecked(&mut this.$future) }.poll(ctx)
+							{
+								this.rotation.next = (index + 1) % COUNT;
+								return Poll::Ready($either::$variant(result));
+							}
+						}
+					)+
+				}
+				Poll::Pending
+			}
+		}
+	};
 }
 
-impl<Future1: Future, Future2: Future> Future for RunTwo<Future1, Future2>
+defineSelect!(SelectTwo, Either; 0 => future1: Future1 => First, 1 => future2: Future2 => Second);
+defineSelect!
+(
+	SelectThree, Either3;
+	0 => future1: Future1 => First, 1 => future2: Future2 => Second, 2 => future3: Future3 => Third
+);
+defineSelect!
+(
+	SelectFour, Either4;
+	0 => future1: Future1 => First, 1 => future2: Future2 => Second, 2 => future3: Future3 => Third,
+	3 => future4: Future4 => Fourth
+);
+
+#[cfg(test)]
+mod tests
 {
-	type Output = ();
+	use super::*;
+	use core::cell::Cell;
+	use core::future::{pending, ready};
+	use core::pin::pin;
+	use std::sync::Arc;
+	use std::sync::atomic::{AtomicUsize, Ordering};
+	use std::task::{Wake, Waker};
+
+	/// Counts how many times it's been woken
+	struct CountingWaker(AtomicUsize);
 
-	fn poll(self: Pin<&mut Self>, ctx: &mut Context<'_>) -> Poll<()>
+	impl Wake for CountingWaker
 	{
-		let this = unsafe { self.get_unchecked_mut() };
-		let allDone =
-			unsafe { Pin::new_unchecked(&mut this.future1) }.poll(ctx) &&
-			unsafe { Pin::new_unchecked(&mut this.future2) }.poll(ctx);
+		fn wake(self: Arc<Self>)
+		{
+			self.wake_by_ref();
+		}
+
+		fn wake_by_ref(self: &Arc<Self>)
+		{
+			self.0.fetch_add(1, Ordering::Relaxed);
+		}
+	}
+
+	fn countingWaker() -> (Arc<CountingWaker>, Waker)
+	{
+		let counter = Arc::new(CountingWaker(AtomicUsize::new(0)));
+		(counter.clone(), Waker::from(counter))
+	}
+
+	/// Completes on the given poll, waking its waker each time before then the way a real Future would once
+	/// whatever it's waiting on happens
+	struct Countdown<'a, T>
+	{
+		polls: &'a Cell<usize>,
+		readyOn: usize,
+		output: Option<T>,
+	}
+
+	impl<'a, T: Unpin> Countdown<'a, T>
+	{
+		fn new(polls: &'a Cell<usize>, readyOn: usize, output: T) -> Self
+		{
+			Self { polls, readyOn, output: Some(output) }
+		}
+	}
+
+	impl<T: Unpin> Future for Countdown<'_, T>
+	{
+		type Output = T;
+
+		fn poll(mut self: Pin<&mut Self>, ctx: &mut Context<'_>) -> Poll<T>
+		{
+			self.polls.set(self.polls.get() + 1);
+			if self.polls.get() >= self.readyOn
+			{
+				Poll::Ready(self.output.take().expect("Countdown polled after completing"))
+			}
+			else
+			{
+				ctx.waker().wake_by_ref();
+				Poll::Pending
+			}
+		}
+	}
+
+	#[test]
+	fn runPollsEveryUnfinishedFuture()
+	{
+		let (counter, waker) = countingWaker();
+		let mut ctx = Context::from_waker(&waker);
+		let (polls1, polls2) = (Cell::new(0), Cell::new(0));
+		let mut run = pin!(RunTwo::new(Countdown::new(&polls1, 3, 1), Countdown::new(&polls2, 2, 2)));
+
+		// The second Future has to be driven even though the first is still pending
+		assert!(run.as_mut().poll(&mut ctx).is_pending());
+		assert_eq!((polls1.get(), polls2.get()), (1, 1));
+		assert_eq!(counter.0.load(Ordering::Relaxed), 2);
+
+		assert!(run.as_mut().poll(&mut ctx).is_pending());
+		assert_eq!((polls1.get(), polls2.get()), (2, 2));
+		assert_eq!(counter.0.load(Ordering::Relaxed), 3);
+
+		// And once it's complete it's left alone
+		assert_eq!(run.as_mut().poll(&mut ctx), Poll::Ready((1, 2)));
+		assert_eq!((polls1.get(), polls2.get()), (3, 2));
+	}
+
+	#[test]
+	fn runReturnsOutputsInOrder()
+	{
+		let (_, waker) = countingWaker();
+		let mut ctx = Context::from_waker(&waker);
+		let polls = Cell::new(0);
+		// ready() panics if polled again after completing, so this also checks finished Futures aren't
+		let mut run = pin!(runAll!(ready(1u8), Countdown::new(&polls, 3, 'b'), ready("three")));
+
+		assert!(run.as_mut().poll(&mut ctx).is_pending());
+		assert!(run.as_mut().poll(&mut ctx).is_pending());
+		assert_eq!(run.as_mut().poll(&mut ctx), Poll::Ready((1, 'b', "three")));
+	}
+
+	#[test]
+	fn runDrivesSixFutures()
+	{
+		let (_, waker) = countingWaker();
+		let mut ctx = Context::from_waker(&waker);
+		let polls: [Cell<usize>; 6] = Default::default();
+		let mut run = pin!(runAll!(
+			Countdown::new(&polls[0], 6, 0), Countdown::new(&polls[1], 5, 1), Countdown::new(&polls[2], 4, 2),
+			Countdown::new(&polls[3], 3, 3), Countdown::new(&polls[4], 2, 4), Countdown::new(&polls[5], 1, 5),
+		));
 
-		if allDone
+		for poll in 1..6
 		{
-			Poll::Ready(())
+			assert!(run.as_mut().poll(&mut ctx).is_pending());
+			// Every Future gets polled until it's done
+			for (index, polls) in polls.iter().enumerate()
+			{
+				assert_eq!(polls.get(), poll.min(6 - index));
+			}
 		}
-		else
+		assert_eq!(run.as_mut().poll(&mut ctx), Poll::Ready((0, 1, 2, 3, 4, 5)));
+	}
+
+	#[test]
+	fn selectPollsEveryPendingFuture()
+	{
+		let (counter, waker) = countingWaker();
+		let mut ctx = Context::from_waker(&waker);
+		let polls: [Cell<usize>; 4] = Default::default();
+		let mut rotation = RoundRobin::new();
+		let mut select = pin!(SelectFour::new(
+			&mut rotation, Countdown::new(&polls[0], 3, 0), Countdown::new(&polls[1], 3, 1),
+			Countdown::new(&polls[2], 3, 2), Countdown::new(&polls[3], 2, 3),
+		));
+
+		assert!(select.as_mut().poll(&mut ctx).is_pending());
+		assert!(polls.iter().all(|polls| polls.get() == 1));
+		assert_eq!(counter.0.load(Ordering::Relaxed), 4);
+
+		assert_eq!(select.as_mut().poll(&mut ctx), Poll::Ready(Either4::Fourth(3)));
+		assert!(polls.iter().all(|polls| polls.get() == 2));
+	}
+
+	#[test]
+	fn selectReturnsFirstToComplete()
+	{
+		let (_, waker) = countingWaker();
+		let mut ctx = Context::from_waker(&waker);
+		let mut rotation = RoundRobin::new();
+		let mut select = pin!(SelectThree::new(&mut rotation, pending::<u8>(), ready(2u16), pending::<u32>()));
+
+		assert_eq!(select.as_mut().poll(&mut ctx), Poll::Ready(Either3::Second(2)));
+	}
+
+	#[test]
+	fn selectTakesTurnsGoingFirst()
+	{
+		let (_, waker) = countingWaker();
+		let mut ctx = Context::from_waker(&waker);
+		let mut rotation = RoundRobin::new();
+
+		// With both always ready, each wins every other time rather than the first always winning
+		for expected in [Either::First(1), Either::Second(2), Either::First(1), Either::Second(2)]
 		{
-			Poll::Pending
+			let mut select = pin!(SelectTwo::new(&mut rotation, ready(1), ready(2)));
+			assert_eq!(select.as_mut().poll(&mut ctx), Poll::Ready(expected));
 		}
 	}
 }
@@ -0,0 +1,231 @@
+// SPDX-License-Identifier: BSD-3-Clause
+
+use core::cell::Cell;
+use embassy_embedded_hal::SetConfig;
+use embassy_futures::join::join;
+use embassy_stm32::mode::Async;
+use embassy_stm32::usart::{Config as UartConfig, Uart};
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_time::{Duration, with_timeout};
+
+use crate::board::TARGET_UART_REGISTERS;
+use crate::serial::applyEncoding;
+use crate::types::{ParityType, SerialEncoding, StopBits};
+use crate::watchdog::{self, Watched};
+
+// Every baud rate the self-test exercises
+const BAUD_RATES: [u32; 11] =
+[
+	9600, 19200, 38400, 57600, 115200, 230400, 460800, 921600, 1000000, 2000000, 3000000,
+];
+
+// Every framing (data bits, parity, stop bits) the self-test exercises at each baud rate
+const FRAMINGS: [(u8, ParityType, StopBits); 8] =
+[
+	(8, ParityType::None, StopBits::One),
+	(8, ParityType::Even, StopBits::One),
+	(8, ParityType::Odd, StopBits::One),
+	(8, ParityType::None, StopBits::Two),
+	(7, ParityType::None, StopBits::One),
+	(7, ParityType::Even, StopBits::One),
+	(7, ParityType::Odd, StopBits::One),
+	(7, ParityType::None, StopBits::Two),
+];
+
+// Pattern pushed through the loopback: all-0's and all-1's, alternating bits, walking ones, then a count
+const PATTERN: [u8; 32] =
+[
+	0x00, 0xff, 0x55, 0xaa, 0x0f, 0xf0, 0x33, 0xcc,
+	0x01, 0x02, 0x04, 0x08, 0x10, 0x20, 0x40, 0x80,
+	0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07,
+	0x08, 0x09, 0x0a, 0x0b, 0x0c, 0x0d, 0x0e, 0x0f,
+];
+// Longest a character can be on the wire: start bit, 8 data bits, parity and 2 stop bits
+const MAX_CHARACTER_BITS: u64 = 12;
+
+static SELF_TEST_RESULT: Mutex<CriticalSectionRawMutex, Cell<SelfTestResult>> =
+	Mutex::new(Cell::new(SelfTestResult::new()));
+
+#[repr(u8)]
+#[derive(Clone, Copy)]
+pub enum SelfTestState
+{
+	NotRun = 0,
+	Running = 1,
+	Passed = 2,
+	Failed = 3,
+}
+
+#[derive(Clone, Copy)]
+pub struct SelfTestResult
+{
+	state: SelfTestState,
+	configurationsTested: u16,
+	configurationsFailed: u16,
+	byteErrors: u32,
+	lineErrors: u32,
+	firstFailure: Option<SerialEncoding>,
+}
+
+impl SelfTestResult
+{
+	const fn new() -> Self
+	{
+		Self
+		{
+			state: SelfTestState::NotRun,
+			configurationsTested: 0,
+			configurationsFailed: 0,
+			byteErrors: 0,
+			lineErrors: 0,
+			firstFailure: None,
+		}
+	}
+
+	pub fn toData(&self, data: &mut [u8]) -> Option<usize>
+	{
+		// There need to be at least 21 bytes to format out a self-test result
+		if data.len() < 21
+		{
+			return None;
+		}
+
+		data[0] = self.state as u8;
+		data[1] = 0;
+		data[2..4].copy_from_slice(&self.configurationsTested.to_le_bytes());
+		data[4..6].copy_from_slice(&self.configurationsFailed.to_le_bytes());
+		data[6..10].copy_from_slice(&self.byteErrors.to_le_bytes());
+		data[10..14].copy_from_slice(&self.lineErrors.to_le_bytes());
+		// The first configuration to fail is reported as a line coding, or all 0's if nothing failed
+		match &self.firstFailure
+		{
+			Some(encoding) => { encoding.toData(&mut data[14..21])?; },
+			None => data[14..21].fill(0),
+		}
+		Some(21)
+	}
+}
+
+/// Mark the self-test as running ahead of the serial task getting to it, so stale results aren't read back
+pub fn pending()
+{
+	SELF_TEST_RESULT.lock(|result| result.set(SelfTestResult { state: SelfTestState::Running, ..SelfTestResult::new() }));
+}
+
+/// Get the outcome of the most recent self-test
+pub fn result() -> SelfTestResult
+{
+	SELF_TEST_RESULT.lock(Cell::get)
+}
+
+/// Run the UART through internal loopback at every supported baud rate and framing, then
+/// restore the configuration that was in effect beforehand.
+///
+/// Loopback is done by putting the UART into half-duplex mode, which routes the transmitter back
+/// into the receiver. The TX pin is still driven while this happens, so the target will see the patterns.
+pub async fn run(serialPort: &mut Uart<'static, Async>, config: &UartConfig)
+{
+	let mut result = SelfTestResult { state: SelfTestState::Running, ..SelfTestResult::new() };
+
+	for baudRate in BAUD_RATES
+	{
+		for (dataBits, parityType, stopBits) in FRAMINGS
+		{
+			let encoding = SerialEncoding::new(baudRate, stopBits, parityType, dataBits);
+			result.configurationsTested += 1;
+			if !testConfiguration(serialPort, config, &encoding, &mut result).await
+			{
+				result.configurationsFailed += 1;
+				if result.firstFailure.is_none()
+				{
+					result.firstFailure = Some(encoding);
+				}
+			}
+			// The whole run takes longer than the watchdog allows, so check in after each configuration
+			watchdog::heartbeat(Watched::Serial);
+		}
+	}
+
+	result.state = if result.configurationsFailed == 0
+	{
+		SelfTestState::Passed
+	}
+	else
+	{
+		SelfTestState::Failed
+	};
+	SELF_TEST_RESULT.lock(|current| current.set(result));
+
+	// Put everything back how it was
+	setLoopback(false);
+	serialPort.set_config(config)
+		.expect("Unable to restore previous encoding state");
+}
+
+async fn testConfiguration(
+	serialPort: &mut Uart<'static, Async>,
+	baseConfig: &UartConfig,
+	encoding: &SerialEncoding,
+	result: &mut SelfTestResult,
+) -> bool
+{
+	let mut config = baseConfig.clone();
+	applyEncoding(&mut config, encoding);
+	if serialPort.set_config(&config).is_err()
+	{
+		return false;
+	}
+	// Reconfiguring the UART resets the duplex mode, so loopback has to be (re)applied after
+	setLoopback(true);
+
+	// 7 bit framings can only carry the bottom 7 bits of each byte
+	let pattern = PATTERN.map(|byte| byte & encoding.characterMask());
+	let mut received = [0u8; PATTERN.len()];
+
+	// Allow twice the time the pattern should take on the wire, plus some slack for DMA setup
+	let patternMicros = (PATTERN.len() as u64 * MAX_CHARACTER_BITS * 1_000_000) / encoding.baudRate as u64;
+	let timeout = Duration::from_micros(patternMicros * 2) + Duration::from_millis(10);
+
+	let (transmitter, receiver) = serialPort.split_ref();
+	let (writeResult, readResult) = join
+	(
+		transmitter.write(&pattern),
+		with_timeout(timeout, receiver.read(&mut received)),
+	).await;
+
+	match (writeResult, readResult)
+	{
+		(Ok(()), Ok(Ok(()))) =>
+		{
+			// With 7 data bits the parity bit is read back in the top bit of each byte, so that's not compared
+			let byteErrors = pattern.iter()
+				.zip(received.iter())
+				.filter(|(expected, actual)| **expected != **actual & encoding.characterMask())
+				.count() as u32;
+			result.byteErrors += byteErrors;
+			byteErrors == 0
+		}
+		// Framing, parity, noise and overrun errors all count as line errors
+		(_, Ok(Err(_))) | (Err(_), _) =>
+		{
+			result.lineErrors += 1;
+			false
+		}
+		// If the pattern never made it back, count every byte as bad
+		(_, Err(_)) =>
+		{
+			result.byteErrors += PATTERN.len() as u32;
+			false
+		}
+	}
+}
+
+fn setLoopback(enabled: bool)
+{
+	// HDSEL can only be changed with the UART disabled
+	let usart = TARGET_UART_REGISTERS;
+	usart.cr1().modify(|reg| reg.set_ue(false));
+	usart.cr3().modify(|reg| reg.set_hdsel(enabled));
+	usart.cr1().modify(|reg| reg.set_ue(true));
+}
@@ -0,0 +1,320 @@
+// SPDX-License-Identifier: BSD-3-Clause
+
+/// Most steps a sequence can have
+pub const MAX_STEPS: usize = 8;
+/// Each step is sent and stored as the reset and boot pin drives followed by the hold time as a u16
+pub const STEP_SIZE: usize = 4;
+/// Largest a sequence can be as data
+pub const MAX_SEQUENCE_SIZE: usize = MAX_STEPS * STEP_SIZE;
+
+/// How long a reset pulse lasts when nobody says otherwise, in milliseconds
+pub const DEFAULT_RESET_PULSE: u16 = 100;
+// How long the boot mode select line stays held after reset is released, so the target has sampled it
+const BOOT_HOLD_TIME: u16 = 50;
+
+/// What a step does to one of the target's pins. Levels are as the target sees them, so driving reset low
+/// puts it in reset whatever the board's wiring in between
+#[repr(u8)]
+#[derive(Clone, Copy, PartialEq)]
+pub enum Drive
+{
+	/// Leave the pin as the last step left it
+	Keep = 0,
+	Low = 1,
+	High = 2,
+}
+
+impl TryFrom<u8> for Drive
+{
+	type Error = ();
+
+	fn try_from(value: u8) -> Result<Self, Self::Error>
+	{
+		match value
+		{
+			0 => Ok(Self::Keep),
+			1 => Ok(Self::Low),
+			2 => Ok(Self::High),
+			_ => Err(()),
+		}
+	}
+}
+
+/// Set the target's reset and boot pins, then wait for the given number of milliseconds
+#[derive(Clone, Copy, PartialEq)]
+pub struct Step
+{
+	pub reset: Drive,
+	pub boot: Drive,
+	pub holdTime: u16,
+}
+
+/// Work out whether a line should be driven high for what a step wants of the target's pin on the other end,
+/// given whether the line being active drives that pin high and whether the line is active high. Returns None
+/// if the line is to be left as it is
+pub fn pinLevel(drive: Drive, activePinHigh: bool, activeHigh: bool) -> Option<bool>
+{
+	let pinHigh = match drive
+	{
+		Drive::Keep => return None,
+		Drive::Low => false,
+		Drive::High => true,
+	};
+	Some((pinHigh == activePinHigh) == activeHigh)
+}
+
+const fn step(reset: Drive, boot: Drive, holdTime: u16) -> Step
+{
+	Step { reset, boot, holdTime }
+}
+
+/// A timed series of steps on the target's reset and boot pins, such as the dance that gets a particular
+/// family of parts into its bootloader. Whatever the last step leaves the pins at, they stay at
+#[derive(Clone, Copy)]
+pub struct Sequence
+{
+	steps: [Step; MAX_STEPS],
+	length: usize,
+}
+
+impl Sequence
+{
+	/// Make a sequence out of the given steps, of which there can be at most MAX_STEPS
+	pub const fn new(steps: &[Step]) -> Self
+	{
+		assert!(steps.len() <= MAX_STEPS, "Too many steps for a sequence");
+		let mut sequence = Self { steps: [step(Drive::Keep, Drive::Keep, 0); MAX_STEPS], length: steps.len() };
+		let mut index = 0;
+		while index < steps.len()
+		{
+			sequence.steps[index] = steps[index];
+			index += 1;
+		}
+		sequence
+	}
+
+	/// Hold the target in reset for the given number of milliseconds, optionally with its boot pin held high
+	/// across it (as for an STM32's BOOT0) so it comes back up in its bootloader
+	pub const fn resetPulse(holdTime: u16, intoBootloader: bool) -> Self
+	{
+		if intoBootloader
+		{
+			Self::new(&[
+				step(Drive::Low, Drive::High, holdTime),
+				step(Drive::High, Drive::Keep, BOOT_HOLD_TIME),
+				step(Drive::Keep, Drive::Low, 0),
+			])
+		}
+		else
+		{
+			Self::new(&[step(Drive::Low, Drive::Keep, holdTime), step(Drive::High, Drive::Keep, 0)])
+		}
+	}
+
+	pub fn steps(&self) -> &[Step]
+	{
+		&self.steps[0..self.length]
+	}
+
+	/// Read a sequence in from data, which must be a whole number of steps. Returns None if the data is empty,
+	/// holds too many steps or has a drive we don't know
+	pub fn fromData(data: &[u8]) -> Option<Self>
+	{
+		if data.is_empty() || data.len() > MAX_SEQUENCE_SIZE || !data.len().is_multiple_of(STEP_SIZE)
+		{
+			return None;
+		}
+
+		let mut sequence = Self::new(&[]);
+		for (index, step) in data.as_chunks::<STEP_SIZE>().0.iter().enumerate()
+		{
+			sequence.steps[index] = Step
+			{
+				reset: Drive::try_from(step[0]).ok()?,
+				boot: Drive::try_from(step[1]).ok()?,
+				holdTime: u16::from_le_bytes([step[2], step[3]]),
+			};
+		}
+		sequence.length = data.len() / STEP_SIZE;
+		Some(sequence)
+	}
+
+	pub fn toData(&self, data: &mut [u8]) -> Option<usize>
+	{
+		let length = self.length * STEP_SIZE;
+		if data.len() < length
+		{
+			return None;
+		}
+
+		for (step, data) in self.steps().iter().zip(data.as_chunks_mut::<STEP_SIZE>().0)
+		{
+			data[0] = step.reset as u8;
+			data[1] = step.boot as u8;
+			data[2..4].copy_from_slice(&step.holdTime.to_le_bytes());
+		}
+		Some(length)
+	}
+}
+
+/// The sequences that can be asked for by number, which apart from the custom one are built in
+#[repr(u8)]
+#[derive(Clone, Copy, PartialEq)]
+pub enum Preset
+{
+	/// Just reset the target
+	Reset = 1,
+	/// Hold BOOT0 high across a pulse on NRST
+	Stm32 = 2,
+	/// esptool's classic reset, with EN on the reset pin and GPIO0 on the boot pin
+	Esp32 = 3,
+	/// Hold the bootloader's DFU button pin low across a pulse on nRESET, and long enough after for the
+	/// bootloader to see it
+	Nrf52 = 4,
+	/// Pulse RESET, after which an Arduino style bootloader listens briefly before starting the application
+	Avr = 5,
+	/// Whatever the host has stored in the settings
+	Custom = 6,
+}
+
+impl Preset
+{
+	/// Get the sequence for a built in preset, or None for the custom one
+	pub const fn sequence(&self) -> Option<Sequence>
+	{
+		match self
+		{
+			Self::Reset => Some(Sequence::resetPulse(DEFAULT_RESET_PULSE, false)),
+			Self::Stm32 => Some(Sequence::resetPulse(DEFAULT_RESET_PULSE, true)),
+			Self::Esp32 => Some(Sequence::new(&[
+				step(Drive::Low, Drive::High, 100),
+				step(Drive::High, Drive::Low, 50),
+				step(Drive::Keep, Drive::High, 0),
+			])),
+			Self::Nrf52 => Some(Sequence::new(&[
+				step(Drive::Low, Drive::Low, 50),
+				step(Drive::High, Drive::Keep, 500),
+				step(Drive::Keep, Drive::High, 0),
+			])),
+			Self::Avr => Some(Sequence::new(&[step(Drive::Low, Drive::Keep, 10), step(Drive::High, Drive::Keep, 0)])),
+			Self::Custom => None,
+		}
+	}
+}
+
+impl TryFrom<u16> for Preset
+{
+	type Error = ();
+
+	fn try_from(value: u16) -> Result<Self, Self::Error>
+	{
+		match value
+		{
+			1 => Ok(Self::Reset),
+			2 => Ok(Self::Stm32),
+			3 => Ok(Self::Esp32),
+			4 => Ok(Self::Nrf52),
+			5 => Ok(Self::Avr),
+			6 => Ok(Self::Custom),
+			_ => Err(()),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+
+	fn steps(preset: Preset) -> Vec<(u8, u8, u16)>
+	{
+		let sequence = preset.sequence().unwrap();
+		sequence.steps().iter().map(|step| (step.reset as u8, step.boot as u8, step.holdTime)).collect()
+	}
+
+	#[test]
+	fn presetSteps()
+	{
+		// 0 leaves the pin be, 1 drives it low and 2 drives it high
+		assert_eq!(steps(Preset::Reset), [(1, 0, 100), (2, 0, 0)]);
+		assert_eq!(steps(Preset::Stm32), [(1, 2, 100), (2, 0, 50), (0, 1, 0)]);
+		assert_eq!(steps(Preset::Esp32), [(1, 2, 100), (2, 1, 50), (0, 2, 0)]);
+		assert_eq!(steps(Preset::Nrf52), [(1, 1, 50), (2, 0, 500), (0, 2, 0)]);
+		assert_eq!(steps(Preset::Avr), [(1, 0, 10), (2, 0, 0)]);
+		assert!(Preset::Custom.sequence().is_none());
+	}
+
+	#[test]
+	fn presetNumbers()
+	{
+		for preset in [Preset::Reset, Preset::Stm32, Preset::Esp32, Preset::Nrf52, Preset::Avr, Preset::Custom]
+		{
+			assert!(Preset::try_from(preset as u16) == Ok(preset));
+		}
+		assert!(Preset::try_from(0).is_err());
+		assert!(Preset::try_from(7).is_err());
+		assert!(Preset::try_from(0x101).is_err());
+	}
+
+	#[test]
+	fn dataRoundTrips()
+	{
+		let data = [1, 2, 0x34, 0x12, 2, 0, 0xff, 0xff, 0, 1, 0, 0];
+		let sequence = Sequence::fromData(&data).unwrap();
+		assert!(sequence.steps() == [
+			step(Drive::Low, Drive::High, 0x1234),
+			step(Drive::High, Drive::Keep, u16::MAX),
+			step(Drive::Keep, Drive::Low, 0),
+		]);
+
+		let mut output = [0xaa; MAX_SEQUENCE_SIZE];
+		assert_eq!(sequence.toData(&mut output), Some(data.len()));
+		assert_eq!(output[0..data.len()], data);
+		assert!(output[data.len()..].iter().all(|byte| *byte == 0xaa));
+		assert_eq!(sequence.toData(&mut output[0..data.len() - 1]), None);
+	}
+
+	#[test]
+	fn fullSequenceRoundTrips()
+	{
+		let data: Vec<u8> = (0..MAX_STEPS as u8).flat_map(|index| [index % 3, 2 - index % 3, index, 0]).collect();
+		let sequence = Sequence::fromData(&data).unwrap();
+		assert_eq!(sequence.steps().len(), MAX_STEPS);
+		let mut output = [0; MAX_SEQUENCE_SIZE];
+		assert_eq!(sequence.toData(&mut output), Some(MAX_SEQUENCE_SIZE));
+		assert_eq!(output[..], data[..]);
+	}
+
+	#[test]
+	fn rejectsBadData()
+	{
+		assert!(Sequence::fromData(&[]).is_none());
+		assert!(Sequence::fromData(&[0; MAX_SEQUENCE_SIZE + STEP_SIZE]).is_none());
+		assert!(Sequence::fromData(&[1, 2, 0]).is_none());
+		assert!(Sequence::fromData(&[1, 2, 0, 0, 1]).is_none());
+		assert!(Sequence::fromData(&[3, 0, 0, 0]).is_none());
+		assert!(Sequence::fromData(&[1, 2, 0, 0, 0, 0xff, 0, 0]).is_none());
+	}
+
+	#[test]
+	fn pinLevels()
+	{
+		// Nothing to do for a pin being left alone, however it's wired
+		for (activePinHigh, activeHigh) in [(false, false), (false, true), (true, false), (true, true)]
+		{
+			assert_eq!(pinLevel(Drive::Keep, activePinHigh, activeHigh), None);
+		}
+
+		// A reset pin that's active low, on a line that's active low or inverted to be active high
+		assert_eq!(pinLevel(Drive::Low, false, false), Some(false));
+		assert_eq!(pinLevel(Drive::High, false, false), Some(true));
+		assert_eq!(pinLevel(Drive::Low, false, true), Some(true));
+		assert_eq!(pinLevel(Drive::High, false, true), Some(false));
+
+		// A boot pin that's active high, on a line that's active high or inverted to be active low
+		assert_eq!(pinLevel(Drive::High, true, true), Some(true));
+		assert_eq!(pinLevel(Drive::Low, true, true), Some(false));
+		assert_eq!(pinLevel(Drive::High, true, false), Some(false));
+		assert_eq!(pinLevel(Drive::Low, true, false), Some(true));
+	}
+}
@@ -1,32 +1,85 @@
 // SPDX-License-Identifier: BSD-3-Clause
 
-use alloc::boxed::Box;
+#[cfg(feature = "command-mode")]
+use core::fmt::{self, Display, Formatter};
 use defmt::error;
 use embassy_embedded_hal::SetConfig;
+#[cfg(feature = "command-mode")]
 use embassy_futures::select::{Either, select};
+use embassy_futures::select::{Either4, select4};
 use embassy_stm32::mode::Async;
-use embassy_stm32::{bind_interrupts, peripherals};
-use embassy_stm32::usart::{Config as UartConfig, InterruptHandler, OutputConfig, Uart};
+use embassy_stm32::usart::{Config as UartConfig, OutputConfig, Uart, UartRx};
 use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
 use embassy_sync::channel::{Receiver, Sender};
+use embassy_time::Timer;
 
-use crate::resources::DmaUartResources;
-use crate::types::{TransmitRequest, ReceiveRequest};
+use crate::bert;
+use crate::capture;
+#[cfg(feature = "command-mode")]
+use crate::command_line::{Command, DataMode};
+#[cfg(feature = "command-mode")]
+use crate::command_mode::{self, CommandMode};
+#[cfg(feature = "command-mode")]
+use crate::escape::{ESCAPE_CHARACTER, ESCAPE_LENGTH};
+use crate::leds;
+#[cfg(feature = "network")]
+use crate::network;
+use crate::framed;
+#[cfg(feature = "command-mode")]
+use crate::framing::FrameEncoding;
+use crate::framing::FramingConfig;
+use crate::frequency_scaling::{FrequencyScaler, IDLE_TIMEOUT};
+use crate::packet::{MAX_PACKET_SIZE, Packet};
+use crate::pcapng::PacketDirection;
+use crate::prbs::PrbsPattern;
+use crate::board::{ClockResources, DmaUartResources, SnifferUartResources, UartIrqs};
+use crate::self_test;
+#[cfg(feature = "command-mode")]
+use crate::sequence::{DEFAULT_RESET_PULSE, Sequence};
+use crate::sniffer;
+#[cfg(feature = "command-mode")]
+use crate::target;
+use crate::watchdog::{self, Watched};
+use crate::types::{SerialCommand, SerialEncoding, TransmitRequest, ReceiveRequest};
 
-bind_interrupts!
-(
-	struct UartIrqs
-	{
-    	USART2 => InterruptHandler<peripherals::USART2>;
-	}
-);
+/// What the serial task is currently using the UART for
+#[derive(Clone, Copy)]
+enum SerialMode
+{
+	/// Conduit data between the host and the target
+	Normal,
+	/// Run the bit error rate tester with the given pattern
+	Bert(PrbsPattern),
+	/// Passively capture both directions of a link using the main and sniffer receivers
+	Sniffer,
+	/// Conduit data as COBS or SLIP encoded frames
+	Framed(FramingConfig),
+	/// Carry IP datagrams between the network interface and the target as SLIP
+	#[cfg(feature = "network")]
+	Network,
+}
+
+/// Something that has come in while running a mode that the serial task needs to act on
+pub enum SerialEvent
+{
+	Request(ReceiveRequest),
+	Command(SerialCommand),
+	/// Neither direction has seen any traffic for a while
+	Idle,
+	/// The guard time after the last data from the host ran out, so any escape sequence is complete
+	#[cfg(feature = "command-mode")]
+	GuardTime,
+}
 
 #[embassy_executor::task]
 pub async fn serialTask
 (
 	uart: DmaUartResources,
+	sniffer: SnifferUartResources,
+	clocks: ClockResources,
 	transmitChannel: Sender<'static, CriticalSectionRawMutex, TransmitRequest, 1>,
 	receiveChannel: Receiver<'static, CriticalSectionRawMutex, ReceiveRequest, 1>,
+	serialCommands: Receiver<'static, CriticalSectionRawMutex, SerialCommand, 2>,
 )
 {
 	let mut config = UartConfig::default();
@@ -43,38 +96,333 @@ pub async fn serialTask
 		config.clone()
 	)
 	.expect("Failed to set up main serial interface");
+	// The sniffer receiver sits idle unless sniffer mode is in use
+	let mut snifferPort = UartRx::new
+	(
+		sniffer.peripheral,
+		UartIrqs,
+		sniffer.rx,
+		sniffer.rx_dma,
+		config.clone()
+	)
+	.expect("Failed to set up sniffer serial interface");
+	let mut scaler = FrequencyScaler::new(clocks);
+	#[cfg(feature = "command-mode")]
+	let mut commandMode = CommandMode::new();
+
+	let mut encoding = SerialEncoding::default();
+	let mut mode = SerialMode::Normal;
+	let mut auxSerialReceiveBuffer = [0u8; MAX_PACKET_SIZE];
+
+	loop
+	{
+		watchdog::heartbeat(Watched::Serial);
+		// Run the current mode until something needs our attention. Each mode only excuses itself from the
+		// watchdog while waiting on the host or target, and otherwise checks in every time round its own loop
+		let modeFuture = async
+		{
+			// While the host has escaped to command mode, the UART isn't used for anything
+			#[cfg(feature = "command-mode")]
+			if commandMode.isActive()
+			{
+				return command_mode::idle(&mut serialPort, &receiveChannel, &serialCommands).await;
+			}
+			match mode
+			{
+				SerialMode::Normal =>
+				{
+					let shouldScaleDown = scaler.shouldScaleDown(&encoding);
+					forwardData(
+						&mut serialPort, &mut auxSerialReceiveBuffer, shouldScaleDown, &transmitChannel, &receiveChannel,
+						&serialCommands
+					).await
+				}
+				SerialMode::Bert(pattern) =>
+					bert::run(&mut serialPort, &encoding, pattern, &receiveChannel, &serialCommands).await,
+				SerialMode::Sniffer =>
+					sniffer::run(&mut serialPort, &mut snifferPort, &transmitChannel, &receiveChannel, &serialCommands).await,
+				SerialMode::Framed(framingConfig) =>
+					framed::run(&mut serialPort, &framingConfig, &transmitChannel, &receiveChannel, &serialCommands).await,
+				#[cfg(feature = "network")]
+				SerialMode::Network =>
+					network::runLink(&mut serialPort, &receiveChannel, &serialCommands).await,
+			}
+		};
+		// Whatever the mode, an escape sequence from the host completes when the guard time after it runs out
+		#[cfg(feature = "command-mode")]
+		let modeFuture = async
+		{
+			match select(modeFuture, commandMode.guardTimeout()).await
+			{
+				Either::First(event) => event,
+				Either::Second(()) => SerialEvent::GuardTime,
+			}
+		};
+		let event = modeFuture.await;
+
+		match event
+		{
+			SerialEvent::Idle =>
+				scaler.scaleDown(&mut serialPort, &mut snifferPort, &config),
+			#[cfg(feature = "command-mode")]
+			SerialEvent::GuardTime =>
+			{
+				// Escape characters held back that turned out not to be an escape sequence are data after all
+				let released = commandMode.guardTimeElapsed(&transmitChannel).await;
+				if released != 0 && matches!(mode, SerialMode::Normal)
+				{
+					writeToTarget(&mut serialPort, &[ESCAPE_CHARACTER; ESCAPE_LENGTH][0..released]).await;
+				}
+			}
+			#[cfg(feature = "command-mode")]
+			SerialEvent::Request(ReceiveRequest::Data(data)) =>
+			{
+				scaler.scaleUp(&mut serialPort, &mut snifferPort, &config);
+				let mut data = &data[..];
+				// Take in command lines for as long as the host stays in command mode
+				while commandMode.isActive() && !data.is_empty()
+				{
+					let (used, command) = commandMode.input(data, &transmitChannel).await;
+					data = &data[used..];
+					let Some(command) = command else { continue };
+					let action = runCommand(command, &mut commandMode, mode, &encoding, &transmitChannel).await;
+					match action
+					{
+						CommandAction::Nothing => {}
+						CommandAction::ChangeEncoding(newEncoding) =>
+						{
+							let request = ReceiveRequest::ChangeEncoding(newEncoding);
+							handleReceiveRequest(request, &mut serialPort, &mut snifferPort, &mut config, &mut encoding).await
+						}
+						CommandAction::SwitchMode(newMode) =>
+							mode = switchMode(mode, newMode),
+					}
+				}
+				// Then anything left is data, though only data mode has anywhere to send it
+				let mut output = [0u8; MAX_PACKET_SIZE + ESCAPE_LENGTH];
+				let output = commandMode.fromHost(data, &mut output);
+				if !output.is_empty() && matches!(mode, SerialMode::Normal)
+				{
+					writeToTarget(&mut serialPort, output).await;
+				}
+			}
+			// While the BERT or sniffer owns the line, data from the host has nowhere to go so gets discarded
+			#[cfg(not(feature = "command-mode"))]
+			SerialEvent::Request(ReceiveRequest::Data(_)) if !matches!(mode, SerialMode::Normal) => {}
+			SerialEvent::Request(request) =>
+			{
+				// Data and line coding changes both need the full speed clocks, data for throughput and the line
+				// coding as it might be too fast for the low power clocks. If the link stays quiet and slow, the
+				// idle timeout brings us back down again
+				scaler.scaleUp(&mut serialPort, &mut snifferPort, &config);
+				handleReceiveRequest(request, &mut serialPort, &mut snifferPort, &mut config, &mut encoding).await
+			}
+			SerialEvent::Command(SerialCommand::SelfTest) =>
+			{
+				scaler.scaleUp(&mut serialPort, &mut snifferPort, &config);
+				self_test::run(&mut serialPort, &config).await
+			}
+			SerialEvent::Command(command) =>
+			{
+				// Every mode other than normal is there to work the link hard, so needs the full speed clocks
+				scaler.scaleUp(&mut serialPort, &mut snifferPort, &config);
+				// Stop commands only apply to the mode they're for
+				let newMode = match (command, mode)
+				{
+					(SerialCommand::StartBert(pattern), _) => SerialMode::Bert(pattern),
+					(SerialCommand::StartSniffer, _) => SerialMode::Sniffer,
+					(SerialCommand::StartFraming(framingConfig), _) => SerialMode::Framed(framingConfig),
+					#[cfg(feature = "network")]
+					(SerialCommand::StartNetwork, _) =>
+					{
+						network::start();
+						SerialMode::Network
+					}
+					#[cfg(feature = "network")]
+					(SerialCommand::StopNetwork, SerialMode::Network) => SerialMode::Normal,
+					(SerialCommand::StopBert, SerialMode::Bert(_)) |
+						(SerialCommand::StopSniffer, SerialMode::Sniffer) |
+						(SerialCommand::StopFraming, SerialMode::Framed(_)) => SerialMode::Normal,
+					(_, mode) => mode,
+				};
+				mode = switchMode(mode, newMode);
+			}
+		}
+	}
+}
 
-	let mut auxSerialReceiveBuffer = [0u8; 64];
+/// Move on from one mode to the next, taking care of anything leaving the old one involves
+fn switchMode(mode: SerialMode, newMode: SerialMode) -> SerialMode
+{
+	// Leaving BERT mode for any reason means it has stopped
+	if matches!(mode, SerialMode::Bert(_)) && !matches!(newMode, SerialMode::Bert(_))
+	{
+		bert::stopped();
+	}
+	newMode
+}
+
+/// What the serial task has to do to finish off a command from command mode
+#[cfg(feature = "command-mode")]
+enum CommandAction
+{
+	Nothing,
+	ChangeEncoding(SerialEncoding),
+	SwitchMode(SerialMode),
+}
 
+/// Carry out a command from command mode and answer it, leaving anything that needs more of the serial task's
+/// state to it
+#[cfg(feature = "command-mode")]
+async fn runCommand(
+	command: Command,
+	commandMode: &mut CommandMode,
+	mode: SerialMode,
+	encoding: &SerialEncoding,
+	transmitChannel: &Sender<'static, CriticalSectionRawMutex, TransmitRequest, 1>,
+) -> CommandAction
+{
+	match command
+	{
+		Command::Blank => {}
+		Command::Attention => command_mode::ok(transmitChannel).await,
+		Command::Identify =>
+		{
+			let identity = format_args!("BMD USB serial conduit {}", env!("CARGO_PKG_VERSION"));
+			command_mode::respond(transmitChannel, identity).await
+		}
+		Command::Online =>
+		{
+			commandMode.leave();
+			command_mode::result(transmitChannel, "CONNECT").await
+		}
+		Command::QueryCoding => command_mode::reportCoding(encoding, transmitChannel).await,
+		Command::SetCoding(coding) =>
+		{
+			command_mode::ok(transmitChannel).await;
+			return CommandAction::ChangeEncoding(command_mode::encodingFor(&coding));
+		}
+		Command::QueryMode => command_mode::respond(transmitChannel, format_args!("+MODE: {}", mode)).await,
+		Command::SetMode(dataMode) =>
+		{
+			command_mode::ok(transmitChannel).await;
+			return CommandAction::SwitchMode(match dataMode
+			{
+				DataMode::Data => SerialMode::Normal,
+				DataMode::Sniffer => SerialMode::Sniffer,
+				DataMode::Bert(pattern) => SerialMode::Bert(pattern),
+				DataMode::Framed(framingConfig) => SerialMode::Framed(framingConfig),
+			});
+		}
+		Command::Reset(duration) | Command::Bootloader(duration) =>
+		{
+			let intoBootloader = matches!(command, Command::Bootloader(_));
+			// The pulse has to fit in a sequence step, and any sequence already playing has to have finished
+			let played = match duration.map_or(Ok(DEFAULT_RESET_PULSE), u16::try_from)
+			{
+				Ok(duration) => target::play(Sequence::resetPulse(duration, intoBootloader)),
+				Err(_) => false,
+			};
+			if played
+			{
+				command_mode::ok(transmitChannel).await
+			}
+			else
+			{
+				command_mode::error(transmitChannel).await
+			}
+		}
+	}
+	CommandAction::Nothing
+}
+
+/// Describes the current mode in the same form `AT+MODE=` takes it
+#[cfg(feature = "command-mode")]
+impl Display for SerialMode
+{
+	fn fmt(&self, fmt: &mut Formatter<'_>) -> fmt::Result
+	{
+		match self
+		{
+			Self::Normal => write!(fmt, "DATA"),
+			Self::Bert(pattern) => write!(fmt, "BERT,{}", *pattern as u8),
+			Self::Sniffer => write!(fmt, "SNIFFER"),
+			Self::Framed(framingConfig) =>
+			{
+				let encoding = match framingConfig.encoding
+				{
+					FrameEncoding::Cobs => "COBS",
+					FrameEncoding::Slip => "SLIP",
+				};
+				write!(fmt, "{}{}", encoding, if framingConfig.crc { ",CRC" } else { "" })
+			}
+			#[cfg(feature = "network")]
+			Self::Network => write!(fmt, "NETWORK"),
+		}
+	}
+}
+
+// Forward data from the UART to the host until a request or command comes in for us to handle, or the link
+// has been idle long enough to drop to the low power clocks if shouldScaleDown is set. Data from the target
+// alone never brings the clocks back up, as the low power clocks are only used at baud rates they can receive
+async fn forwardData(
+	serialPort: &mut Uart<'static, Async>,
+	auxSerialReceiveBuffer: &mut [u8],
+	shouldScaleDown: bool,
+	transmitChannel: &Sender<'static, CriticalSectionRawMutex, TransmitRequest, 1>,
+	receiveChannel: &Receiver<'static, CriticalSectionRawMutex, ReceiveRequest, 1>,
+	serialCommands: &Receiver<'static, CriticalSectionRawMutex, SerialCommand, 2>,
+) -> SerialEvent
+{
 	loop
 	{
+		watchdog::heartbeat(Watched::Serial);
 		let receiveFuture = receiveChannel.receive();
 		let auxSerialReceiveFuture =
-			serialPort.read_until_idle(&mut auxSerialReceiveBuffer);
-		match select(receiveFuture, auxSerialReceiveFuture).await
+			serialPort.read_until_idle(auxSerialReceiveBuffer);
+		let commandFuture = serialCommands.receive();
+		// Each time round is a fresh period of quiet, as anything else happening gets us out of this loop
+		let idleFuture = async
 		{
-			Either::First(request) =>
-				handleReceiveRequest(request, &mut serialPort, &mut config).await,
-			Either::Second(result) =>
+			if shouldScaleDown
+			{
+				Timer::after(IDLE_TIMEOUT).await
+			}
+			else
+			{
+				core::future::pending().await
+			}
+		};
+		let event = select4(receiveFuture, auxSerialReceiveFuture, commandFuture, idleFuture);
+		match watchdog::excused(Watched::Serial, event).await
+		{
+			Either4::First(request) =>
+				return SerialEvent::Request(request),
+			Either4::Second(result) =>
 			{
 				match result
 				{
 					Ok(byteCount) =>
 					{
-						let mut buffer = unsafe
+						capture::data(PacketDirection::Inbound, &auxSerialReceiveBuffer[0..byteCount]);
+						leds::activity();
+						// Getting the data to the host waits on it reading out what it already has
+						watchdog::excused(Watched::Serial, async
 						{
-							Box::new_zeroed_slice(byteCount)
-								.assume_init()
-						};
-						buffer.copy_from_slice(&auxSerialReceiveBuffer[0..byteCount]);
-						transmitChannel
-							.send(TransmitRequest::Data(buffer))
-							.await;
+							let packet = Packet::copyFrom(&auxSerialReceiveBuffer[0..byteCount]).await;
+							transmitChannel
+								.send(TransmitRequest::Data(packet))
+								.await;
+						}).await;
 					}
 					Err(error) =>
 						error!("Serial interface read failed, {}", error)
 				}
 			}
+			Either4::Third(command) =>
+				return SerialEvent::Command(command),
+			Either4::Fourth(()) =>
+				return SerialEvent::Idle,
 		}
 	}
 }
@@ -82,22 +430,38 @@ pub async fn serialTask
 async fn handleReceiveRequest(
 	request: ReceiveRequest,
 	serialPort: &mut Uart<'static, Async>,
+	snifferPort: &mut UartRx<'static, Async>,
 	config: &mut UartConfig,
+	currentEncoding: &mut SerialEncoding,
 )
 {
 	match request
 	{
 		ReceiveRequest::ChangeEncoding(encoding) =>
 		{
-			config.baudrate = encoding.baudRate;
-			config.stop_bits = encoding.stopBits();
-			config.parity = encoding.parityType();
-			config.data_bits = encoding.dataBits();
-
+			// Keep the sniffer receiver in step with the main UART so both sides of a link can be captured at once
+			applyEncoding(config, &encoding);
 			serialPort.set_config(config)
 				.expect("Unable to set desired encoding state");
+			snifferPort.set_config(config)
+				.expect("Unable to set desired encoding state");
+			*currentEncoding = encoding;
 		}
 		ReceiveRequest::Data(data) =>
-			serialPort.write(&data).await.expect("Serial interface writes never fail")
+			writeToTarget(serialPort, &data).await,
 	}
 }
+
+async fn writeToTarget(serialPort: &mut Uart<'static, Async>, data: &[u8])
+{
+	leds::activity();
+	serialPort.write(data).await.expect("Serial interface writes never fail")
+}
+
+pub fn applyEncoding(config: &mut UartConfig, encoding: &SerialEncoding)
+{
+	config.baudrate = encoding.baudRate;
+	config.stop_bits = encoding.stopBits();
+	config.parity = encoding.parityType();
+	config.data_bits = encoding.dataBits();
+}
@@ -3,49 +3,83 @@
 use embassy_stm32::uid::uid;
 use embassy_sync::once_lock::OnceLock;
 
+use crate::settings;
+
+/// The longest serial number we will present, whether derived from the UID or user-assigned
+pub const SERIAL_NUMBER_LENGTH: usize = 32;
+// The full 96-bit UID takes 24 hex digits to represent
+const UID_DIGITS: usize = 24;
+
 // Provide space for the serial number to be written into at runtime
-static SERIAL_NUMBER: OnceLock<SerialNumber<8>> = OnceLock::new();
+static SERIAL_NUMBER: OnceLock<SerialNumber<SERIAL_NUMBER_LENGTH>> = OnceLock::new();
 
-struct SerialNumber<const N: usize>
+#[derive(Clone, Copy)]
+pub struct SerialNumber<const N: usize>
 {
-	value: [u8; N]
+	value: [u8; N],
+	length: usize,
 }
 
 impl<const N: usize> SerialNumber<N>
 {
-	pub fn from_bytes(serialNumber: [u8; N]) -> Self
+	/// Build a serial number from a user-supplied string, which must be non-empty printable ASCII that fits in N bytes
+	pub fn from_bytes(serialNumber: &[u8]) -> Option<Self>
+	{
+		if serialNumber.is_empty() || serialNumber.len() > N ||
+			!serialNumber.iter().all(|byte| byte.is_ascii_graphic())
+		{
+			return None;
+		}
+
+		let mut value = [0u8; N];
+		value[0..serialNumber.len()].copy_from_slice(serialNumber);
+		Some(Self { value, length: serialNumber.len() })
+	}
+
+	/// Build a serial number from the device's 96-bit UID.
+	///
+	/// The UID is rendered as upper-case hex, most significant byte first, so it reads the same as
+	/// the UID registers do in a debugger. When N is less than 24, only the least significant digits
+	/// are kept as those encode the wafer position and wafer number, which vary the most between parts.
+	pub fn from_uid(uniqueID: &[u8; 12]) -> Self
 	{
-		Self
+		let length = N.min(UID_DIGITS);
+		let mut value = [0u8; N];
+		for (idx, byte) in value[0..length].iter_mut().rev().enumerate()
 		{
-			value: serialNumber
+			// Walk the UID from its least significant nibble up, filling the string from the right
+			let nibble = (uniqueID[idx / 2] >> ((idx % 2) * 4)) & 0x0f;
+			let mut digit = nibble + b'0';
+			if digit > b'9'
+			{
+				digit += 7;
+			}
+			*byte = digit;
 		}
+
+		Self { value, length }
+	}
+
+	pub fn as_bytes(&self) -> &[u8]
+	{
+		&self.value[0..self.length]
 	}
 
-	pub const fn as_str(&self) -> &str
+	pub fn as_str(&self) -> &str
 	{
-		unsafe { str::from_utf8_unchecked(&self.value) }
+		// Both constructors guarantee the value is ASCII
+		unsafe { str::from_utf8_unchecked(self.as_bytes()) }
 	}
 }
 
 pub fn readSerialNumber()
 {
-	let uniqueIDBytes = uid();
-	let uniqueID1 = u32::from_ne_bytes(uniqueIDBytes[0..4].try_into().unwrap());
-	let uniqueID2 = u32::from_ne_bytes(uniqueIDBytes[4..8].try_into().unwrap());
-	let uniqueID3 = u32::from_ne_bytes(uniqueIDBytes[8..12].try_into().unwrap());
-	let uniqueID = uniqueID1 + uniqueID2 + uniqueID3;
-	let mut serialNumber = [0u8; 8];
-	for (idx, byte) in serialNumber.iter_mut().enumerate()
-	{
-		let mut value = (((uniqueID >> (idx * 4)) & 0x0f) as u8) + ('0' as u8);
-		if value > ('9' as u8)
-		{
-			value += 7;
-		}
-		*byte = value;
-	}
+	// A user-assigned serial number from the settings store takes priority over the UID-derived one
+	let serialNumber = settings::current()
+		.serialNumber
+		.unwrap_or_else(|| SerialNumber::from_uid(uid()));
 
-	let _ = SERIAL_NUMBER.init(SerialNumber::from_bytes(serialNumber));
+	let _ = SERIAL_NUMBER.init(serialNumber);
 }
 
 pub async fn serialNumber() -> &'static str
@@ -0,0 +1,207 @@
+// SPDX-License-Identifier: BSD-3-Clause
+
+use core::cell::RefCell;
+use defmt::error;
+use embassy_stm32::flash::{Blocking, Flash, FLASH_SIZE};
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::signal::Signal;
+
+use crate::board::FlashResources;
+use crate::sequence::{MAX_SEQUENCE_SIZE, Preset, Sequence};
+use crate::serial_number::{SERIAL_NUMBER_LENGTH, SerialNumber};
+use crate::types::{INTERFACE_NAME_LENGTH, InterfaceName, PortInterface};
+
+// The settings block lives in the last 8KiB page of flash, well clear of the firmware image
+const SETTINGS_PAGE_SIZE: u32 = 8192;
+const SETTINGS_OFFSET: u32 = FLASH_SIZE as u32 - SETTINGS_PAGE_SIZE;
+// Magic number identifying a valid settings block ("CNDT" in memory)
+const SETTINGS_MAGIC: u32 = 0x54444e43;
+const SETTINGS_VERSION: u16 = 3;
+// Size of the settings block, which must be a multiple of the 16 byte flash write quantum
+const SETTINGS_SIZE: usize = 144;
+
+// Header layout: magic, version, and 2 reserved bytes
+const HEADER_LENGTH: usize = 8;
+// Serial number layout: length byte followed by the string padded out with 0's
+const SERIAL_NUMBER_OFFSET: usize = HEADER_LENGTH;
+// Interface names (from version 2) follow, each as a length byte and the name padded out with 0's
+const INTERFACE_NAMES_OFFSET: usize = 48;
+const INTERFACE_NAME_SIZE: usize = INTERFACE_NAME_LENGTH + 1;
+// Target sequences (from version 3) follow, as the preset played on port open (0 for none) then the custom
+// sequence as a length byte and its steps padded out with 0's
+const OPEN_SEQUENCE_OFFSET: usize = INTERFACE_NAMES_OFFSET + 2 * INTERFACE_NAME_SIZE;
+const CUSTOM_SEQUENCE_OFFSET: usize = OPEN_SEQUENCE_OFFSET + 1;
+
+// The live copy of the settings, and a signal to have them written back to flash
+static SETTINGS: Mutex<CriticalSectionRawMutex, RefCell<Settings>> =
+	Mutex::new(RefCell::new(Settings::new()));
+static SETTINGS_UPDATE: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// User configuration that persists across resets
+#[derive(Clone, Copy)]
+pub struct Settings
+{
+	pub serialNumber: Option<SerialNumber<SERIAL_NUMBER_LENGTH>>,
+	interfaceNames: [Option<InterfaceName>; 2],
+	/// Sequence played on the target's control lines when the host opens the port
+	pub openSequence: Option<Preset>,
+	/// Sequence played for the custom preset
+	pub customSequence: Option<Sequence>,
+}
+
+impl Settings
+{
+	const fn new() -> Self
+	{
+		Self
+		{
+			serialNumber: None,
+			interfaceNames: [None; 2],
+			openSequence: None,
+			customSequence: None,
+		}
+	}
+
+	pub fn interfaceName(&self, interface: PortInterface) -> Option<InterfaceName>
+	{
+		self.interfaceNames[interface as usize]
+	}
+
+	pub fn setInterfaceName(&mut self, interface: PortInterface, name: Option<InterfaceName>)
+	{
+		self.interfaceNames[interface as usize] = name;
+	}
+
+	fn fromData(data: &[u8; SETTINGS_SIZE]) -> Option<Self>
+	{
+		// Check the block is one of ours, and of a version we understand. Newer versions only ever
+		// append fields, so older blocks are still usable with the new fields left at their defaults
+		let magic = u32::from_le_bytes(data[0..4].try_into().unwrap());
+		let version = u16::from_le_bytes(data[4..6].try_into().unwrap());
+		if magic != SETTINGS_MAGIC || version == 0 || version > SETTINGS_VERSION
+		{
+			return None;
+		}
+
+		let mut settings = Self::new();
+		let serialNumberLength = data[SERIAL_NUMBER_OFFSET] as usize;
+		if serialNumberLength != 0
+		{
+			let serialNumber = data.get(SERIAL_NUMBER_OFFSET + 1..SERIAL_NUMBER_OFFSET + 1 + serialNumberLength)?;
+			settings.serialNumber = Some(SerialNumber::from_bytes(serialNumber)?);
+		}
+
+		if version >= 2
+		{
+			for (idx, name) in settings.interfaceNames.iter_mut().enumerate()
+			{
+				let offset = INTERFACE_NAMES_OFFSET + (idx * INTERFACE_NAME_SIZE);
+				let length = data[offset] as usize;
+				if length != 0
+				{
+					*name = Some(InterfaceName::fromBytes(data.get(offset + 1..offset + 1 + length)?)?);
+				}
+			}
+		}
+
+		if version >= 3
+		{
+			// A sequence we can't make sense of is just dropped, rather than losing the rest of the settings with it.
+			// 0 for no open sequence and a length of 0 for no custom one get dropped the same way
+			settings.openSequence = Preset::try_from(data[OPEN_SEQUENCE_OFFSET] as u16).ok();
+			let length = data[CUSTOM_SEQUENCE_OFFSET] as usize;
+			settings.customSequence = data.get(CUSTOM_SEQUENCE_OFFSET + 1..CUSTOM_SEQUENCE_OFFSET + 1 + length)
+				.and_then(Sequence::fromData);
+		}
+		Some(settings)
+	}
+
+	fn toData(&self, data: &mut [u8; SETTINGS_SIZE])
+	{
+		data.fill(0);
+		data[0..4].copy_from_slice(&SETTINGS_MAGIC.to_le_bytes());
+		data[4..6].copy_from_slice(&SETTINGS_VERSION.to_le_bytes());
+
+		if let Some(serialNumber) = &self.serialNumber
+		{
+			let serialNumber = serialNumber.as_bytes();
+			data[SERIAL_NUMBER_OFFSET] = serialNumber.len() as u8;
+			data[SERIAL_NUMBER_OFFSET + 1..SERIAL_NUMBER_OFFSET + 1 + serialNumber.len()]
+				.copy_from_slice(serialNumber);
+		}
+
+		for (idx, name) in self.interfaceNames.iter().enumerate()
+		{
+			if let Some(name) = name
+			{
+				let offset = INTERFACE_NAMES_OFFSET + (idx * INTERFACE_NAME_SIZE);
+				let name = name.asBytes();
+				data[offset] = name.len() as u8;
+				data[offset + 1..offset + 1 + name.len()].copy_from_slice(name);
+			}
+		}
+
+		data[OPEN_SEQUENCE_OFFSET] = self.openSequence.map_or(0, |preset| preset as u8);
+		if let Some(sequence) = &self.customSequence
+		{
+			let slot = &mut data[CUSTOM_SEQUENCE_OFFSET + 1..CUSTOM_SEQUENCE_OFFSET + 1 + MAX_SEQUENCE_SIZE];
+			let length = sequence.toData(slot).expect("Sequences always fit their settings slot");
+			data[CUSTOM_SEQUENCE_OFFSET] = length as u8;
+		}
+	}
+}
+
+/// Bring up the flash controller and load the settings block, falling back to defaults if there is none
+pub fn init(resources: FlashResources) -> Flash<'static, Blocking>
+{
+	let mut flash = Flash::new_blocking(resources.peripheral);
+	let mut data = [0u8; SETTINGS_SIZE];
+	match flash.blocking_read(SETTINGS_OFFSET, &mut data)
+	{
+		Ok(()) =>
+		{
+			if let Some(settings) = Settings::fromData(&data)
+			{
+				SETTINGS.lock(|current| current.replace(settings));
+			}
+		}
+		Err(error) =>
+			error!("Failed to read settings from flash, {}", error)
+	}
+	flash
+}
+
+/// Get a copy of the current settings
+pub fn current() -> Settings
+{
+	SETTINGS.lock(|settings| *settings.borrow())
+}
+
+/// Modify the current settings and have the result written back to flash
+pub fn update(modify: impl FnOnce(&mut Settings))
+{
+	SETTINGS.lock(|settings| modify(&mut settings.borrow_mut()));
+	SETTINGS_UPDATE.signal(());
+}
+
+#[embassy_executor::task]
+pub async fn settingsTask(mut flash: Flash<'static, Blocking>)
+{
+	let mut data = [0u8; SETTINGS_SIZE];
+
+	loop
+	{
+		SETTINGS_UPDATE.wait().await;
+		current().toData(&mut data);
+
+		// Erase the settings page and write the new block back in
+		let result = flash
+			.blocking_erase(SETTINGS_OFFSET, SETTINGS_OFFSET + SETTINGS_PAGE_SIZE)
+			.and_then(|()| flash.blocking_write(SETTINGS_OFFSET, &data));
+		if let Err(error) = result
+		{
+			error!("Failed to write settings to flash, {}", error)
+		}
+	}
+}
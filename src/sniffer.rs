@@ -0,0 +1,100 @@
+// SPDX-License-Identifier: BSD-3-Clause
+
+use embassy_futures::join::join;
+use embassy_futures::select::{Either3, select3};
+use embassy_stm32::mode::Async;
+use embassy_stm32::usart::{Uart, UartRx};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::channel::{Receiver, Sender};
+use embassy_time::Instant;
+
+use crate::packet::{MAX_PACKET_SIZE, Packet};
+use crate::serial::SerialEvent;
+use crate::types::{ReceiveRequest, SerialCommand, TransmitRequest};
+use crate::watchdog::{self, Watched};
+
+// Every record starts with this byte so the host can resynchronise if it drops part of the stream
+const RECORD_SYNC: u8 = 0xa5;
+// Sync byte, direction, 16-bit length and 64-bit timestamp
+const RECORD_HEADER_LENGTH: usize = 12;
+// Set in the direction byte when the record reports a receive error rather than carrying data
+const RECORD_ERROR: u8 = 0x80;
+
+/// Which side of the observed link a record was captured from
+#[repr(u8)]
+#[derive(Clone, Copy)]
+enum Direction
+{
+	/// Captured by the main UART's receiver
+	Primary = 0,
+	/// Captured by the sniffer UART's receiver
+	Secondary = 1,
+}
+
+/// Passively capture both directions of a link until a request or command comes in that needs the serial
+/// task's attention.
+///
+/// Each chunk of data is sent to the host as a record made of a 0xa5 sync byte, the direction (with bit 7 set
+/// for receive errors), the data length as a u16 and the capture time in microseconds since boot as a u64,
+/// all little endian, followed by the data itself. The timestamp is taken when the chunk completes, which is
+/// when the line goes idle or the receive buffer fills.
+pub async fn run(
+	serialPort: &mut Uart<'static, Async>,
+	snifferPort: &mut UartRx<'static, Async>,
+	transmitChannel: &Sender<'static, CriticalSectionRawMutex, TransmitRequest, 1>,
+	receiveChannel: &Receiver<'static, CriticalSectionRawMutex, ReceiveRequest, 1>,
+	serialCommands: &Receiver<'static, CriticalSectionRawMutex, SerialCommand, 2>,
+) -> SerialEvent
+{
+	let (_, primaryPort) = serialPort.split_ref();
+	// Both receivers have to run independently so that neither one's transfer gets cancelled by the other
+	let captureFuture = join
+	(
+		capture(primaryPort, Direction::Primary, transmitChannel),
+		capture(snifferPort, Direction::Secondary, transmitChannel),
+	);
+
+	match select3(captureFuture, receiveChannel.receive(), serialCommands.receive()).await
+	{
+		Either3::First(_) => unreachable!("Capture futures never complete"),
+		Either3::Second(request) => SerialEvent::Request(request),
+		Either3::Third(command) => SerialEvent::Command(command),
+	}
+}
+
+async fn capture(
+	port: &mut UartRx<'static, Async>,
+	direction: Direction,
+	transmitChannel: &Sender<'static, CriticalSectionRawMutex, TransmitRequest, 1>,
+)
+{
+	// Keep records small enough that header and data always fit in a single packet
+	let mut receiveBuffer = [0u8; MAX_PACKET_SIZE - RECORD_HEADER_LENGTH];
+
+	loop
+	{
+		watchdog::heartbeat(Watched::Serial);
+		let result = watchdog::excused(Watched::Serial, port.read_until_idle(&mut receiveBuffer)).await;
+		let timestamp = Instant::now().as_micros();
+		let (direction, data) = match result
+		{
+			Ok(byteCount) => (direction as u8, &receiveBuffer[0..byteCount]),
+			Err(_) => (direction as u8 | RECORD_ERROR, &receiveBuffer[0..0]),
+		};
+
+		// Packets only free up and the channel only drains as the host reads records out
+		watchdog::excused(Watched::Serial, async
+		{
+			let mut record = Packet::new(RECORD_HEADER_LENGTH + data.len()).await;
+			record[0] = RECORD_SYNC;
+			record[1] = direction;
+			record[2..4].copy_from_slice(&(data.len() as u16).to_le_bytes());
+			record[4..12].copy_from_slice(&timestamp.to_le_bytes());
+			record[RECORD_HEADER_LENGTH..].copy_from_slice(data);
+
+			transmitChannel
+				.send(TransmitRequest::Data(record))
+				.await;
+		}).await;
+	}
+}
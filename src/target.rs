@@ -0,0 +1,85 @@
+// SPDX-License-Identifier: BSD-3-Clause
+
+use defmt::info;
+use embassy_stm32::gpio::{Level, Output, Speed};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::channel::Channel;
+use embassy_time::Timer;
+
+use crate::board::{self, ControlResources, TARGET_BOOT_ACTIVE, TARGET_RESET_ACTIVE};
+use crate::capture;
+use crate::sequence::{Preset, Sequence, pinLevel};
+use crate::settings;
+
+static SEQUENCE_REQUESTS: Channel<CriticalSectionRawMutex, Sequence, 1> = Channel::new();
+
+/// The lines used to reset the target and select how it boots
+struct TargetControl
+{
+	reset: Output<'static>,
+	boot: Output<'static>,
+}
+
+impl TargetControl
+{
+	/// Take over the control lines, leaving the target out of reset and set to boot normally
+	fn new(control: ControlResources) -> Self
+	{
+		Self
+		{
+			reset: Output::new(control.reset, board::inactive(TARGET_RESET_ACTIVE), Speed::Low),
+			boot: Output::new(control.boot, board::inactive(TARGET_BOOT_ACTIVE), Speed::Low),
+		}
+	}
+
+	/// Step through a sequence on the lines. The target's reset pin is active low, so it's driven low by
+	/// the reset line being active, while the boot line being active drives the boot pin high
+	async fn play(&mut self, sequence: &Sequence)
+	{
+		for step in sequence.steps()
+		{
+			if let Some(level) = pinLevel(step.reset, false, TARGET_RESET_ACTIVE == Level::High).map(Level::from)
+			{
+				self.reset.set_level(level);
+			}
+			if let Some(level) = pinLevel(step.boot, true, TARGET_BOOT_ACTIVE == Level::High).map(Level::from)
+			{
+				self.boot.set_level(level);
+			}
+			Timer::after_millis(step.holdTime as u64).await;
+		}
+	}
+}
+
+/// Ask for a sequence to be played on the target's control lines. Returns false if one is still playing
+pub fn play(sequence: Sequence) -> bool
+{
+	SEQUENCE_REQUESTS.try_send(sequence).is_ok()
+}
+
+/// Ask for a preset sequence to be played. Returns false if it's the custom one and none has been stored, or
+/// if a sequence is still playing
+pub fn playPreset(preset: Preset) -> bool
+{
+	let sequence = match preset.sequence()
+	{
+		Some(sequence) => Some(sequence),
+		None => settings::current().customSequence,
+	};
+	sequence.is_some_and(play)
+}
+
+/// Hold the target's control lines released, playing sequences on them as they're asked for
+#[embassy_executor::task]
+pub async fn targetTask(control: ControlResources)
+{
+	let mut target = TargetControl::new(control);
+
+	loop
+	{
+		let sequence = SEQUENCE_REQUESTS.receive().await;
+		info!("Playing {} step target sequence", sequence.steps().len());
+		capture::event(format_args!("Target sequence of {} steps", sequence.steps().len()));
+		target.play(&sequence).await;
+	}
+}
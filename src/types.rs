@@ -2,18 +2,40 @@
 
 use core::fmt::{Display, Formatter, Result};
 
-use alloc::boxed::Box;
 use embassy_stm32::usart;
 
+use crate::framing::FramingConfig;
+use crate::packet::Packet;
+use crate::prbs::PrbsPattern;
+
+/// The longest name that can be assigned to a USB interface
+pub const INTERFACE_NAME_LENGTH: usize = 24;
+
 pub enum TransmitRequest
 {
-	Data(Box<[u8]>),
+	Data(Packet),
 }
 
 pub enum ReceiveRequest
 {
 	ChangeEncoding(SerialEncoding),
-	Data(Box<[u8]>),
+	Data(Packet),
+}
+
+/// Requests from the USB control plane that change what the serial task is doing
+pub enum SerialCommand
+{
+	SelfTest,
+	StartBert(PrbsPattern),
+	StopBert,
+	StartSniffer,
+	StopSniffer,
+	StartFraming(FramingConfig),
+	StopFraming,
+	#[cfg(feature = "network")]
+	StartNetwork,
+	#[cfg(feature = "network")]
+	StopNetwork,
 }
 
 #[repr(u8)]
@@ -52,6 +74,19 @@ impl Into<usart::StopBits> for StopBits
 	}
 }
 
+impl Display for StopBits
+{
+	fn fmt(&self, fmt: &mut Formatter<'_>) -> Result
+	{
+		match self
+		{
+			Self::One => write!(fmt, "1 stop bit"),
+			Self::OneAndHalf => write!(fmt, "1.5 stop bits"),
+			Self::Two => write!(fmt, "2 stop bits"),
+		}
+	}
+}
+
 #[repr(u8)]
 #[derive(Clone, Copy)]
 pub enum ParityType
@@ -131,8 +166,21 @@ impl Default for SerialEncoding
 	}
 }
 
+impl Display for SerialEncoding
+{
+	fn fmt(&self, fmt: &mut Formatter<'_>) -> Result
+	{
+		write!(fmt, "{} baud, {} data bits, {}, {}", self.baudRate, self.dataBits, self.parityType, self.stopBits)
+	}
+}
+
 impl SerialEncoding
 {
+	pub const fn new(baudRate: u32, stopBits: StopBits, parityType: ParityType, dataBits: u8) -> Self
+	{
+		Self { baudRate, stopBits, parityType, dataBits }
+	}
+
 	pub fn fromData(data: &[u8]) -> Option<Self>
 	{
 		// There need to be at least 7 bytes to consume as a serial encoding
@@ -180,6 +228,25 @@ impl SerialEncoding
 		self.parityType.into()
 	}
 
+	/// Number of bits of each byte that make it onto the wire with this encoding
+	pub fn characterBits(&self) -> u8
+	{
+		self.dataBits.min(8)
+	}
+
+	/// Mask of the bits of each byte that make it onto the wire with this encoding
+	pub fn characterMask(&self) -> u8
+	{
+		if self.dataBits < 8
+		{
+			(1u8 << self.dataBits) - 1
+		}
+		else
+		{
+			0xff
+		}
+	}
+
 	pub fn dataBits(&self) -> usart::DataBits
 	{
 		match self.dataBits
@@ -191,3 +258,64 @@ impl SerialEncoding
 		}
 	}
 }
+
+/// The interfaces making up a serial port, which can each be given a name
+#[repr(u8)]
+#[derive(Clone, Copy)]
+pub enum PortInterface
+{
+	Control = 0,
+	Data = 1,
+}
+
+impl TryFrom<u16> for PortInterface
+{
+	type Error = ();
+
+	fn try_from(value: u16) -> core::result::Result<Self, Self::Error>
+	{
+		match value
+		{
+			0 => Ok(Self::Control),
+			1 => Ok(Self::Data),
+			_ => Err(()),
+		}
+	}
+}
+
+/// A user-assigned interface name, held inline as printable ASCII
+#[derive(Clone, Copy)]
+pub struct InterfaceName
+{
+	value: [u8; INTERFACE_NAME_LENGTH],
+	length: usize,
+}
+
+impl InterfaceName
+{
+	pub fn fromBytes(name: &[u8]) -> Option<Self>
+	{
+		// Names must be non-empty printable ASCII, and not start or end with a space
+		if name.is_empty() || name.len() > INTERFACE_NAME_LENGTH ||
+			!name.iter().all(|byte| *byte == b' ' || byte.is_ascii_graphic()) ||
+			name[0] == b' ' || name[name.len() - 1] == b' '
+		{
+			return None;
+		}
+
+		let mut value = [0u8; INTERFACE_NAME_LENGTH];
+		value[0..name.len()].copy_from_slice(name);
+		Some(Self { value, length: name.len() })
+	}
+
+	pub fn asBytes(&self) -> &[u8]
+	{
+		&self.value[0..self.length]
+	}
+
+	pub fn asStr(&self) -> &str
+	{
+		// fromBytes() guarantees the value is ASCII
+		unsafe { str::from_utf8_unchecked(self.asBytes()) }
+	}
+}
@@ -1,26 +1,42 @@
 // SPDX-License-Identifier: BSD-3-Clause
 
 use core::cell::{OnceCell, RefCell};
-use alloc::boxed::Box;
 use defmt::error;
-use embassy_futures::select::{Either4, select4};
-use embassy_stm32::{bind_interrupts, peripherals};
-use embassy_stm32::usb::{Config as OtgConfig, Driver, InterruptHandler};
+use embassy_executor::Spawner;
+use embassy_futures::select::Either4;
+use embassy_stm32::usb::{Config as OtgConfig, Driver};
 use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
 use embassy_sync::channel::{Receiver, Sender};
 use embassy_sync::signal::Signal;
 use embassy_usb::control::{self, Request};
-use embassy_usb::driver::{Direction, EndpointAddress, EndpointIn, EndpointOut};
-use embassy_usb::types::InterfaceNumber;
+use embassy_usb::driver::{Direction, Endpoint as _, EndpointAddress, EndpointError, EndpointIn, EndpointOut};
+use embassy_usb::types::{InterfaceNumber, StringIndex};
+#[cfg(feature = "network")]
+use embassy_usb::class::cdc_ncm::{CdcNcmClass, State as NcmState};
 use embassy_usb::{Builder, Config as DeviceConfig, Handler, UsbVersion};
 use embassy_usb_synopsys_otg::{Endpoint, In, Out};
 use static_cell::{ConstStaticCell, StaticCell};
-use crate::resources::UsbResources;
-use crate::run_multiple::RunTwo;
+use crate::capture;
+#[cfg(feature = "modem-inputs")]
+use crate::modem;
+use crate::packet::Packet;
+use crate::pcapng::PacketDirection;
+#[cfg(feature = "target-power")]
+use crate::power;
+use crate::board::{self, DEFAULT_DATA_INTERFACE_NAME, USB_VBUS_DETECTION, UsbPeripheral, UsbResources};
+use crate::run_multiple::{RoundRobin, SelectFour, runAll};
 use crate::serial_number::serialNumber;
-use crate::types::{ReceiveRequest, SerialEncoding, TransmitRequest};
+use crate::settings;
+use crate::target;
+#[cfg(feature = "network")]
+use crate::network;
+#[cfg(feature = "log-usb")]
+use crate::usb_log;
+use crate::types::{InterfaceName, PortInterface, ReceiveRequest, SerialCommand, SerialEncoding, TransmitRequest};
 use crate::ref_counted::{Rc, RcPool};
-use crate::usb_types::{UsbCdcAcmCapabilities, UsbCdcAcmDescriptor, UsbCdcCallManagementCapabilities, UsbCdcCallManagementDescriptor, UsbCdcHeaderDescriptor, UsbCdcUnionDescriptor, UsbCdcVersion};
+use crate::vendor::VendorHandler;
+use crate::watchdog::{self, Watched};
+use crate::usb_types::{UsbCdcAcmCapabilities, UsbCdcAcmDescriptor, UsbCdcCallManagementCapabilities, UsbCdcCallManagementDescriptor, UsbCdcHeaderDescriptor, UsbCdcSerialState, UsbCdcUnionDescriptor, UsbCdcVersion};
 
 const VID: u16 = 0x1209;
 const PID: u16 = 0xbadb;
@@ -31,6 +47,8 @@ const USB_CLASS_CDC: u8 = 0x02;
 const USB_CLASS_DATA: u8 = 0x0a;
 /// Miscellaneous Device
 const USB_CLASS_MISC: u8 = 0xef;
+/// Vendor Specific
+const USB_CLASS_VENDOR: u8 = 0xff;
 
 /// CDC ACM subclass device
 const CDC_SUBCLASS_ACM: u8 = 2;
@@ -47,52 +65,90 @@ const MISC_SUBCLASS_COMMON: u8 = 2;
 // Interface Association
 const MISC_PROTOCOL_IAD: u8 = 1;
 
-bind_interrupts!
-(
-	struct UsbIrqs
-	{
-    	OTG_FS => InterruptHandler<peripherals::USB_OTG_FS>;
-	}
-);
-
-// Buffer that must be large enough to receive any possible packet we can dequeue
-static RX_BUFFER: ConstStaticCell<[u8; 192]> = ConstStaticCell::new([0u8; 192]);
-// Buffer that must be large enough to hold any possible control packet (in or out) that might be generated
-static CONTROL_BUFFER: ConstStaticCell<[u8; 64]> = ConstStaticCell::new([0u8; 64]);
+/// Non-specific vendor subclass
+const VENDOR_SUBCLASS_NONE: u8 = 0;
+/// Non-specific vendor protocol
+const VENDOR_PROTOCOL_NONE: u8 = 0;
+
+// Name given to the serial port's control interface when the user hasn't assigned their own (the data
+// interface's default comes from the board, as it names the UART)
+const DEFAULT_CONTROL_INTERFACE_NAME: &str = "Target Console";
+const CAPTURE_INTERFACE_NAME: &str = "Traffic Capture";
+const LOG_INTERFACE_NAME: &str = "Debug Log";
+
+/// Largest packet the bulk endpoints take, which is the most the bus allows at the speed we run at
+#[cfg(not(feature = "usb-hs"))]
+pub const BULK_PACKET_SIZE: u16 = 64;
+#[cfg(feature = "usb-hs")]
+pub const BULK_PACKET_SIZE: u16 = 512;
+// How often the host polls for notifications, in ms at full speed and as a power of 2 of 125µs microframes at
+// high speed (2^10 microframes being 128ms)
+#[cfg(not(feature = "usb-hs"))]
+const NOTIFICATION_INTERVAL: u8 = 100;
+#[cfg(feature = "usb-hs")]
+const NOTIFICATION_INTERVAL: u8 = 11;
+
+// Buffer that must be large enough to receive any possible packet we can dequeue. The driver sizes the core's
+// receive FIFO from this, and the transmit FIFOs from each IN endpoint's max packet size
+#[cfg(not(feature = "usb-hs"))]
+const RX_BUFFER_SIZE: usize = 192;
+#[cfg(feature = "usb-hs")]
+const RX_BUFFER_SIZE: usize = 1024;
+static RX_BUFFER: ConstStaticCell<[u8; RX_BUFFER_SIZE]> = ConstStaticCell::new([0u8; RX_BUFFER_SIZE]);
+// Buffer that must be large enough to hold any possible control packet (in or out) that might be generated,
+// the largest of which is the crash record
+static CONTROL_BUFFER: ConstStaticCell<[u8; 256]> = ConstStaticCell::new([0u8; 256]);
 // Buffer that must be large enough to hold the completed configuration descriptor
-static CONFIGURATION_DESCRIPTOR: ConstStaticCell<[u8; 75]> = ConstStaticCell::new([0u8; 75]);
+// (the capture and log functions each add another IAD, interface and endpoint when enabled, and the network
+// function an IAD, two interfaces, the CDC functional descriptors and three endpoints)
+const CONFIGURATION_DESCRIPTOR_LENGTH: usize = 75 + if cfg!(feature = "capture") { 24 } else { 0 } +
+	if cfg!(feature = "log-usb") { 24 } else { 0 } + if cfg!(feature = "network") { 85 } else { 0 };
+static CONFIGURATION_DESCRIPTOR: ConstStaticCell<[u8; CONFIGURATION_DESCRIPTOR_LENGTH]> =
+	ConstStaticCell::new([0u8; CONFIGURATION_DESCRIPTOR_LENGTH]);
 
 // Create a container for our serial handler to be created from
 static SERIAL_HANDLER_POOL: ConstStaticCell<RcPool<SerialHandlerInner, 1>> =
 	ConstStaticCell::new(RcPool::new());
 static SERIAL_HANDLER: StaticCell<SerialHandler> = StaticCell::new();
+static VENDOR_HANDLER: StaticCell<VendorHandler> = StaticCell::new();
+#[cfg(feature = "network")]
+static NETWORK_STATE: StaticCell<NcmState<'static>> = StaticCell::new();
 
 const USB_CDC_HEADER_DESCRIPTOR: UsbCdcHeaderDescriptor =
 	UsbCdcHeaderDescriptor::new(UsbCdcVersion::OneDotOne);
 const USB_CDC_ACM_DESCRIPTOR: UsbCdcAcmDescriptor =
 	UsbCdcAcmDescriptor::new(UsbCdcAcmCapabilities::SupportsLineCoding);
 
+/// Entry point for USB handling on the high priority executor. Tasks spawned onto an interrupt executor from
+/// outside it have to be Send, which the USB stack's state is not, so this only carries the resources over and
+/// then spawns the real USB task from inside that executor
 #[embassy_executor::task]
 pub async fn usbTask
 (
 	usb: UsbResources,
 	transmitChannel: Receiver<'static, CriticalSectionRawMutex, TransmitRequest, 1>,
 	receiveChannel: Sender<'static, CriticalSectionRawMutex, ReceiveRequest, 1>,
+	serialCommands: Sender<'static, CriticalSectionRawMutex, SerialCommand, 2>,
+)
+{
+	Spawner::for_current_executor().await
+		.spawn(usbDeviceTask(usb, transmitChannel, receiveChannel, serialCommands).unwrap());
+}
+
+#[embassy_executor::task]
+async fn usbDeviceTask
+(
+	usb: UsbResources,
+	transmitChannel: Receiver<'static, CriticalSectionRawMutex, TransmitRequest, 1>,
+	receiveChannel: Sender<'static, CriticalSectionRawMutex, ReceiveRequest, 1>,
+	serialCommands: Sender<'static, CriticalSectionRawMutex, SerialCommand, 2>,
 )
 {
 	let mut config = OtgConfig::default();
-	// We have VBus hooked up on this hardware, so do this.
-	config.vbus_detection = true;
-	// Create an instance of the USB driver for our peripheral
-	let driver: Driver<'static, peripherals::USB_OTG_FS> = Driver::new_fs
-	(
-		usb.peripheral,
-		UsbIrqs,
-		usb.dp,
-		usb.dm,
-		RX_BUFFER.take(),
-		config
-	);
+	// Use VBus detection if the board has it hooked up
+	config.vbus_detection = USB_VBUS_DETECTION;
+	// Create an instance of the USB driver for the board's peripheral
+	let driver: Driver<'static, UsbPeripheral> = board::usbDriver(usb, RX_BUFFER.take(), config);
 
 	// Build the device configuration state we intend to use
 	let deviceConfig = deviceConfig().await;
@@ -119,6 +175,11 @@ pub async fn usbTask
 		CONTROL_BUFFER.take(),
 	);
 
+	// Allocate the strings naming the serial port's interfaces so udev can tell ports apart by role
+	let controlInterfaceString = builder.string();
+	let dataInterfaceString = builder.string();
+	serialHandler.interfaceStrings(controlInterfaceString, dataInterfaceString);
+
 	// Define a new "function" to be the root of the CDC-ACM support
 	let mut serialFunction = builder.function
 	(
@@ -133,7 +194,7 @@ pub async fn usbTask
 		USB_CLASS_CDC,
 		CDC_SUBCLASS_ACM,
 		CDC_PROTOCOL_NONE,
-		None
+		Some(controlInterfaceString)
 	);
 	serialHandler.controlInterface(serialControlInterface.interface_number());
 	// Extract the endpoint for sending notifications for this control interface
@@ -141,7 +202,7 @@ pub async fn usbTask
 	(
 		Some(EndpointAddress::from_parts(2, Direction::In)),
 		16,
-		100
+		NOTIFICATION_INTERVAL
 	);
 
 	// Pair in the CDC descriptors needed to describe this interface as USB CDC ACM and how
@@ -171,43 +232,126 @@ pub async fn usbTask
 		USB_CLASS_DATA,
 		DATA_SUBCLASS_NONE,
 		DATA_PROTOCOL_NONE,
-		None
+		Some(dataInterfaceString)
 	);
 	// Extract the endpoints for communicating on the data interface
 	let serialDataTx: Endpoint<'static, In> = serialDataInterface.endpoint_bulk_in
 	(
 		Some(EndpointAddress::from_parts(1, Direction::In)),
-		64
+		BULK_PACKET_SIZE
 	);
 	let serialDataRx: Endpoint<'static, Out> = serialDataInterface.endpoint_bulk_out
 	(
 		Some(EndpointAddress::from_parts(1, Direction::Out)),
-		64
+		BULK_PACKET_SIZE
 	);
 
 	// Set up the endpoints against our serial handler
 	serialHandler.endpoints(serialNotification, serialDataTx, serialDataRx);
 	// Drop our reference to the function so the builder can work
 	drop(serialFunction);
+
+	// Define a vendor-specific function for streaming pcapng traffic captures to the host, if in use
+	#[cfg(feature = "capture")]
+	let mut captureEndpoint: Endpoint<'static, In> =
+	{
+		let captureInterfaceString = builder.string();
+		serialHandler.captureInterfaceString(captureInterfaceString);
+		let mut captureFunction = builder.function
+		(
+			USB_CLASS_VENDOR,
+			VENDOR_SUBCLASS_NONE,
+			VENDOR_PROTOCOL_NONE
+		);
+		let mut captureInterface = captureFunction.interface();
+		let mut captureInterface = captureInterface.alt_setting
+		(
+			USB_CLASS_VENDOR,
+			VENDOR_SUBCLASS_NONE,
+			VENDOR_PROTOCOL_NONE,
+			Some(captureInterfaceString)
+		);
+		captureInterface.endpoint_bulk_in
+		(
+			Some(EndpointAddress::from_parts(3, Direction::In)),
+			BULK_PACKET_SIZE
+		)
+	};
+	#[cfg(feature = "capture")]
+	let captureFuture = capture::run(&mut captureEndpoint);
+	#[cfg(not(feature = "capture"))]
+	let captureFuture = core::future::pending::<()>();
+
+	// Define another vendor-specific function for streaming defmt logs to the host, if in use
+	#[cfg(feature = "log-usb")]
+	let mut logEndpoint: Endpoint<'static, In> =
+	{
+		let logInterfaceString = builder.string();
+		serialHandler.logInterfaceString(logInterfaceString);
+		let mut logFunction = builder.function
+		(
+			USB_CLASS_VENDOR,
+			VENDOR_SUBCLASS_NONE,
+			VENDOR_PROTOCOL_NONE
+		);
+		let mut logInterface = logFunction.interface();
+		let mut logInterface = logInterface.alt_setting
+		(
+			USB_CLASS_VENDOR,
+			VENDOR_SUBCLASS_NONE,
+			VENDOR_PROTOCOL_NONE,
+			Some(logInterfaceString)
+		);
+		logInterface.endpoint_bulk_in
+		(
+			Some(EndpointAddress::from_parts(4, Direction::In)),
+			BULK_PACKET_SIZE
+		)
+	};
+	#[cfg(feature = "log-usb")]
+	let logFuture = usb_log::run(&mut logEndpoint);
+	#[cfg(not(feature = "log-usb"))]
+	let logFuture = core::future::pending::<()>();
+
+	// Define a CDC-NCM function for carrying IP to and from the target, if in use
+	#[cfg(feature = "network")]
+	let networkFuture = network::run
+	(
+		CdcNcmClass::new(&mut builder, NETWORK_STATE.init(NcmState::new()), network::hostMac(), BULK_PACKET_SIZE)
+	);
+	#[cfg(not(feature = "network"))]
+	let networkFuture = core::future::pending::<()>();
+
 	// Register the serial handler so we can deal with CDC ACM state requests
 	builder.handler(serialHandler);
+	// And the vendor request handler for device configuration
+	builder.handler(VENDOR_HANDLER.init(VendorHandler::new(serialCommands)));
 
 	// Turn the completed builder into a USB device and run it
 	let mut usbDevice = builder.build();
-	RunTwo::new(usbDevice.run(), serialHandlerInner.borrow().run()).await;
+	runAll!
+	(
+		usbDevice.run(),
+		serialHandlerInner.borrow().run(),
+		captureFuture,
+		watchdog::usbDeviceHeartbeat(),
+		logFuture,
+		networkFuture,
+	).await;
 }
 
 // Compile-time set up the device descriptor for this
 async fn deviceConfig() -> DeviceConfig<'static>
 {
 	let mut config = DeviceConfig::new(VID, PID);
-	// We're a USB 2.0 device
+	// We're a USB 2.0 device, which also has embassy-usb answer for the device qualifier descriptor built from
+	// this config that hosts ask high speed devices for
 	config.bcd_usb = UsbVersion::Two;
 	// Device is a misc IAD-based device
 	config.device_class = USB_CLASS_MISC;
 	config.device_sub_class = MISC_SUBCLASS_COMMON;
 	config.device_protocol = MISC_PROTOCOL_IAD;
-	// Use a 64 byte max packet size for EP0 (max for FS)
+	// Use a 64 byte max packet size for EP0 (max for FS, and the only option for HS)
 	config.max_packet_size_0 = 64;
 	// BCD encoded device version
 	config.device_release = 0x0001;
@@ -252,7 +396,7 @@ enum CdcNotification
 
 impl CdcNotification
 {
-	fn asMessage<'a>(&self, notification: &'a mut [u8; 16], interface: u16) -> &'a [u8]
+	fn asMessage<'a>(&self, notification: &'a mut [u8; 16], interface: u16, state: UsbCdcSerialState) -> &'a [u8]
 	{
 		match self
 		{
@@ -271,8 +415,8 @@ impl CdcNotification
 				message[4..6].copy_from_slice(&interface.to_le_bytes());
 				// 2 bytes after the header
 				message[6..8].copy_from_slice(&u16::to_le_bytes(2));
-				// Said 2 bytes representing the state, which is RTS & DTR
-				message[8..10].copy_from_slice(&u16::to_le_bytes(3));
+				// Said 2 bytes representing the state of the modem control inputs
+				message[8..10].copy_from_slice(&state.bits().to_le_bytes());
 
 				notification[0..10].copy_from_slice(&message);
 				&notification[0..10]
@@ -292,68 +436,176 @@ struct SerialHandlerInner
 	receiveEndpoint: OnceCell<RefCell<Endpoint<'static, Out>>>,
 	encodingUpdate: Signal<CriticalSectionRawMutex, SerialEncoding>,
 	stateUpdate: Signal<CriticalSectionRawMutex, u16>,
+	serialStateUpdate: Signal<CriticalSectionRawMutex, UsbCdcSerialState>,
 }
 
 impl SerialHandlerInner
 {
 	pub async fn run(&self) -> !
 	{
-		let mut usbSerialReceiveBuffer = [0u8; 64];
-		let mut receiveEndpoint = self.receiveEndpoint
-				.get()
-				.expect("Receive endpoint should be valid at this point")
-				.borrow_mut();
+		// Each endpoint gets its own loop so a transfer in flight is never cancelled by another completing first
+		let (never, _, _, _) = runAll!
+		(
+			self.handleControlEvents(),
+			self.forwardTransmitRequests(),
+			self.forwardReceivedData(),
+			self.forwardSerialState(),
+		).await;
+		never
+	}
+
+	async fn handleControlEvents(&self) -> !
+	{
+		let mut rotation = RoundRobin::new();
+		// Without the modem control inputs to go by, always claim carrier and that the target is ready
+		let mut serialState = UsbCdcSerialState::RxCarrier | UsbCdcSerialState::TxCarrier;
+		// While the target's power is tripped, it can't be said to have carrier or be ready whatever the inputs say
+		let mut tripped = false;
+		let mut portOpen = false;
 
 		loop
 		{
 			let encodingFuture = self.encodingUpdate.wait();
 			let stateFuture = self.stateUpdate.wait();
-			let transmitFuture = self.transmitChannel.receive();
-			let usbSerialReceiveFuture = receiveEndpoint
-				.read(&mut usbSerialReceiveBuffer);
-			match select4(encodingFuture, stateFuture, transmitFuture, usbSerialReceiveFuture).await
+			#[cfg(feature = "modem-inputs")]
+			let modemFuture = modem::changed();
+			#[cfg(not(feature = "modem-inputs"))]
+			let modemFuture = core::future::pending::<UsbCdcSerialState>();
+			#[cfg(feature = "target-power")]
+			let powerFuture = power::trippedChanged();
+			#[cfg(not(feature = "target-power"))]
+			let powerFuture = core::future::pending::<bool>();
+			let event = watchdog::excused
+			(
+				Watched::UsbControl,
+				SelectFour::new(&mut rotation, encodingFuture, stateFuture, modemFuture, powerFuture)
+			).await;
+			match event
 			{
 				Either4::First(encoding) =>
 				{
+					capture::event(format_args!("Line coding {}", encoding));
 					self.encoding.replace(encoding);
-					self.receiveChannel.send(ReceiveRequest::ChangeEncoding(encoding)).await;
+					// That waits its turn behind any data from the host the serial task is still getting through
+					watchdog::excused(Watched::UsbControl, self.receiveChannel.send(ReceiveRequest::ChangeEncoding(encoding)))
+						.await;
 				},
-				Either4::Second(_) =>
+				Either4::Second(state) =>
 				{
-					let mut notification = [0; 16];
-					let notification = CdcNotification::SerialState.asMessage(
-						&mut notification, self.controlInterface
-					);
-
-					self.notificationEndpoint.get()
-						.expect("Notification endpoint should be valid at this point")
-						.borrow_mut()
-						.write(notification).await
-						.expect("Endpoint in strange state");
+					capture::event(format_args!("Control lines DTR {} RTS {}", state & 1, (state >> 1) & 1));
+					// The host raising DTR is it opening the port, which can be set to kick the target
+					let dtr = state & 1 != 0;
+					let openSequence = settings::current().openSequence.filter(|_| dtr && !portOpen);
+					if openSequence.map(target::playPreset) == Some(false)
+					{
+						error!("Target sequence for port open could not be played");
+					}
+					portOpen = dtr;
+					self.notifySerialState(reportedState(serialState, tripped));
 				}
-				Either4::Third(request) =>
-					self.handleTransmitRequest(request).await,
-				Either4::Fourth(result) =>
+				Either4::Third(state) =>
+				{
+					capture::event(format_args!("Modem inputs DCD {} DSR {} RI {}",
+						state.contains(UsbCdcSerialState::RxCarrier) as u8,
+						state.contains(UsbCdcSerialState::TxCarrier) as u8,
+						state.contains(UsbCdcSerialState::RingSignal) as u8));
+					serialState = state;
+					self.notifySerialState(reportedState(serialState, tripped));
+				}
+				Either4::Fourth(nowTripped) =>
+				{
+					tripped = nowTripped;
+					self.notifySerialState(reportedState(serialState, tripped));
+				}
+			}
+			watchdog::heartbeat(Watched::UsbControl);
+		}
+	}
+
+	// Changes can come along well before the host has configured us or while it's away, so only the latest state
+	// is kept for forwardSerialState to send when it can
+	fn notifySerialState(&self, state: UsbCdcSerialState)
+	{
+		self.serialStateUpdate.signal(state);
+	}
+
+	async fn forwardSerialState(&self) -> !
+	{
+		let mut notificationEndpoint = self.notificationEndpoint
+			.get()
+			.expect("Notification endpoint should be valid at this point")
+			.borrow_mut();
+
+		let mut state = watchdog::excused(Watched::UsbNotify, self.serialStateUpdate.wait()).await;
+		loop
+		{
+			// Nothing can be sent until the host has configured us, and a newer state supersedes the one held
+			watchdog::excused(Watched::UsbNotify, notificationEndpoint.wait_enabled()).await;
+			if let Some(newerState) = self.serialStateUpdate.try_take()
+			{
+				state = newerState;
+			}
+
+			let mut notification = [0; 16];
+			let notification = CdcNotification::SerialState.asMessage(&mut notification, self.controlInterface, state);
+			// The host polls for notifications in its own time
+			match watchdog::excused(Watched::UsbNotify, notificationEndpoint.write(notification)).await
+			{
+				// The host went away or reset the bus before reading it, so it gets sent again once we're configured
+				Err(EndpointError::Disabled) => {},
+				result =>
 				{
-					match result
+					if let Err(error) = result
 					{
-						Ok(byteCount) =>
-						{
-							let mut buffer = unsafe
-							{
-								Box::new_zeroed_slice(byteCount)
-									.assume_init()
-							};
-							buffer.copy_from_slice(&usbSerialReceiveBuffer[0..byteCount]);
-							self.receiveChannel
-								.send(ReceiveRequest::Data(buffer))
-								.await;
-						},
-						Err(error) =>
-							error!("USB serial interface read failed, {}", error)
+						error!("Serial state notification failed, {}", error);
 					}
+					state = watchdog::excused(Watched::UsbNotify, self.serialStateUpdate.wait()).await;
 				}
 			}
+			watchdog::heartbeat(Watched::UsbNotify);
+		}
+	}
+
+	async fn forwardTransmitRequests(&self) -> !
+	{
+		loop
+		{
+			// Both waiting for data from the target and waiting for the host to read it out are out of our hands
+			let request = watchdog::excused(Watched::UsbTransmit, self.transmitChannel.receive()).await;
+			watchdog::excused(Watched::UsbTransmit, self.handleTransmitRequest(request)).await;
+		}
+	}
+
+	async fn forwardReceivedData(&self) -> !
+	{
+		let mut usbSerialReceiveBuffer = [0u8; BULK_PACKET_SIZE as usize];
+		let mut receiveEndpoint = self.receiveEndpoint
+				.get()
+				.expect("Receive endpoint should be valid at this point")
+				.borrow_mut();
+
+		loop
+		{
+			let result = watchdog::excused(Watched::UsbReceive, receiveEndpoint.read(&mut usbSerialReceiveBuffer)).await;
+			match result
+			{
+				Ok(byteCount) =>
+				{
+					capture::data(PacketDirection::Outbound, &usbSerialReceiveBuffer[0..byteCount]);
+					// The serial task can be a long time getting to the data, such as while running the self test
+					// or writing it out at a low baud rate
+					watchdog::excused(Watched::UsbReceive, async
+					{
+						let packet = Packet::copyFrom(&usbSerialReceiveBuffer[0..byteCount]).await;
+						self.receiveChannel
+							.send(ReceiveRequest::Data(packet))
+							.await;
+					}).await;
+				},
+				Err(error) =>
+					error!("USB serial interface read failed, {}", error)
+			}
+			watchdog::heartbeat(Watched::UsbReceive);
 		}
 	}
 
@@ -376,7 +628,7 @@ impl SerialHandlerInner
 			.expect("Endpoints already initialised")
 	}
 
-	fn controlLineState(&mut self, state: u16)
+	fn controlLineState(&self, state: u16)
 	{
 		self.stateUpdate.signal(state);
 	}
@@ -386,7 +638,7 @@ impl SerialHandlerInner
 		self.encoding.borrow().toData(data)
 	}
 
-	fn encodingFromData(&mut self, data: &[u8]) -> Option<()>
+	fn encodingFromData(&self, data: &[u8]) -> Option<()>
 	{
 		SerialEncoding::fromData(data)
 			.map(|encoding| self.encodingUpdate.signal(encoding))
@@ -403,9 +655,14 @@ impl SerialHandlerInner
 					.expect("Transmit endpoint should be valid at this point")
 					.borrow_mut();
 
-				transmitEndpoint
-					.write(&data).await
-					.expect("Endpoint in strange state")
+				// Requests can be larger than a packet, so split them up to fit the endpoint
+				let packetSize = transmitEndpoint.info().max_packet_size as usize;
+				for packet in data.chunks(packetSize)
+				{
+					transmitEndpoint
+						.write(packet).await
+						.expect("Endpoint in strange state")
+				}
 			}
 		};
 	}
@@ -414,16 +671,25 @@ impl SerialHandlerInner
 struct SerialHandler
 {
 	inner: Rc<SerialHandlerInner>,
+	controlInterfaceString: Option<StringIndex>,
+	dataInterfaceString: Option<StringIndex>,
+	controlInterfaceName: Option<InterfaceName>,
+	dataInterfaceName: Option<InterfaceName>,
+	captureInterfaceString: Option<StringIndex>,
+	logInterfaceString: Option<StringIndex>,
 }
 
 impl SerialHandler
 {
 	pub fn new(
-		serialHandlerPool: &mut RcPool<SerialHandlerInner, 1>,
+		serialHandlerPool: &'static RcPool<SerialHandlerInner, 1>,
 		transmitChannel: Receiver<'static, CriticalSectionRawMutex, TransmitRequest, 1>,
 		receiveChannel: Sender<'static, CriticalSectionRawMutex, ReceiveRequest, 1>,
 	) -> Self
 	{
+		// Pick up any names the user has assigned to the interfaces
+		let settings = settings::current();
+
 		// Bring up a new serial events handler in idle state
 		Self
 		{
@@ -438,10 +704,35 @@ impl SerialHandler
 				receiveEndpoint: OnceCell::new(),
 				encodingUpdate: Signal::new(),
 				stateUpdate: Signal::new(),
+				serialStateUpdate: Signal::new(),
 			}).expect("Rc pool should not be exhausted"),
+			controlInterfaceString: None,
+			dataInterfaceString: None,
+			controlInterfaceName: settings.interfaceName(PortInterface::Control),
+			dataInterfaceName: settings.interfaceName(PortInterface::Data),
+			captureInterfaceString: None,
+			logInterfaceString: None,
 		}
 	}
 
+	#[cfg(feature = "capture")]
+	pub fn captureInterfaceString(&mut self, captureInterfaceString: StringIndex)
+	{
+		self.captureInterfaceString = Some(captureInterfaceString);
+	}
+
+	#[cfg(feature = "log-usb")]
+	pub fn logInterfaceString(&mut self, logInterfaceString: StringIndex)
+	{
+		self.logInterfaceString = Some(logInterfaceString);
+	}
+
+	pub fn interfaceStrings(&mut self, controlInterfaceString: StringIndex, dataInterfaceString: StringIndex)
+	{
+		self.controlInterfaceString = Some(controlInterfaceString);
+		self.dataInterfaceString = Some(dataInterfaceString);
+	}
+
 	pub fn controlInterface(&self, controlInterface: InterfaceNumber)
 	{
 		self.inner.borrowMut().controlInterface(controlInterface);
@@ -465,6 +756,32 @@ impl SerialHandler
 
 impl Handler for SerialHandler
 {
+	fn get_string(&mut self, index: StringIndex, _langID: u16) -> Option<&str>
+	{
+		if Some(index) == self.controlInterfaceString
+		{
+			Some(self.controlInterfaceName.as_ref()
+				.map_or(DEFAULT_CONTROL_INTERFACE_NAME, InterfaceName::asStr))
+		}
+		else if Some(index) == self.dataInterfaceString
+		{
+			Some(self.dataInterfaceName.as_ref()
+				.map_or(DEFAULT_DATA_INTERFACE_NAME, InterfaceName::asStr))
+		}
+		else if Some(index) == self.captureInterfaceString
+		{
+			Some(CAPTURE_INTERFACE_NAME)
+		}
+		else if Some(index) == self.logInterfaceString
+		{
+			Some(LOG_INTERFACE_NAME)
+		}
+		else
+		{
+			None
+		}
+	}
+
 	fn control_in<'a>(&'a mut self, packet: Request, data: &'a mut [u8]) -> Option<control::InResponse<'a>>
 	{
 		if packet.recipient != control::Recipient::Interface ||
@@ -498,15 +815,23 @@ impl Handler for SerialHandler
 		{
 			CdcRequest::SetControlLineState =>
 			{
-				self.inner.borrowMut().controlLineState(packet.value);
+				self.inner.borrow().controlLineState(packet.value);
 				Some(control::OutResponse::Accepted)
 			}
 			CdcRequest::SetLineCoding =>
 			{
-				self.inner.borrowMut().encodingFromData(data)
+				self.inner.borrow().encodingFromData(data)
 					.map(|()| control::OutResponse::Accepted)
 			}
 			_ => None
 		}
 	}
 }
+
+// What the host gets told about the modem inputs, which all read as inactive while the target's power is tripped
+// (as documented for GetTargetPower). That goes out through notifySerialState like any other change, so it waits
+// for the host to have configured us
+fn reportedState(serialState: UsbCdcSerialState, tripped: bool) -> UsbCdcSerialState
+{
+	if tripped { UsbCdcSerialState::none() } else { serialState }
+}
@@ -0,0 +1,238 @@
+// SPDX-License-Identifier: BSD-3-Clause
+
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use critical_section::RestoreState;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::pipe::Pipe;
+use embassy_usb::driver::EndpointIn;
+use embassy_usb_synopsys_otg::{Endpoint, In};
+
+use crate::usb::BULK_PACKET_SIZE;
+
+// How much log data can be waiting to go to the host before we start dropping frames
+const LOG_BUFFER_SIZE: usize = 1024;
+// Largest encoded log frame we'll keep, anything longer gets dropped
+const MAX_FRAME_SIZE: usize = 256;
+
+static LOG_PIPE: Pipe<CriticalSectionRawMutex, LOG_BUFFER_SIZE> = Pipe::new();
+static LOG_LEVEL: AtomicU8 = AtomicU8::new(LogLevel::Info as u8);
+
+/// Levels log frames can be filtered at, least severe first
+#[repr(u8)]
+#[derive(Clone, Copy, PartialEq, PartialOrd)]
+pub enum LogLevel
+{
+	Trace = 0,
+	Debug = 1,
+	Info = 2,
+	Warn = 3,
+	Error = 4,
+	/// Only used as a filter, to turn off all logging
+	Off = 5,
+}
+
+impl TryFrom<u16> for LogLevel
+{
+	type Error = ();
+
+	fn try_from(value: u16) -> Result<Self, Self::Error>
+	{
+		match value
+		{
+			0 => Ok(Self::Trace),
+			1 => Ok(Self::Debug),
+			2 => Ok(Self::Info),
+			3 => Ok(Self::Warn),
+			4 => Ok(Self::Error),
+			5 => Ok(Self::Off),
+			_ => Err(()),
+		}
+	}
+}
+
+/// Only send frames at this level or more severe to the host from now on
+pub fn setLogLevel(level: LogLevel)
+{
+	LOG_LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+// The linker script groups each log level's interned strings together between these markers, so a frame's
+// string index tells us its level
+unsafe extern "C"
+{
+	static __DEFMT_MARKER_TRACE_START: u8;
+	static __DEFMT_MARKER_TRACE_END: u8;
+	static __DEFMT_MARKER_DEBUG_START: u8;
+	static __DEFMT_MARKER_DEBUG_END: u8;
+	static __DEFMT_MARKER_INFO_START: u8;
+	static __DEFMT_MARKER_INFO_END: u8;
+	static __DEFMT_MARKER_WARN_START: u8;
+	static __DEFMT_MARKER_WARN_END: u8;
+	static __DEFMT_MARKER_ERROR_START: u8;
+	static __DEFMT_MARKER_ERROR_END: u8;
+}
+
+fn frameLevel(index: u16) -> Option<LogLevel>
+{
+	let index = index as usize;
+	let inRange = |start: *const u8, end: *const u8| (start as usize..end as usize).contains(&index);
+	unsafe
+	{
+		if inRange(&raw const __DEFMT_MARKER_TRACE_START, &raw const __DEFMT_MARKER_TRACE_END)
+		{
+			Some(LogLevel::Trace)
+		}
+		else if inRange(&raw const __DEFMT_MARKER_DEBUG_START, &raw const __DEFMT_MARKER_DEBUG_END)
+		{
+			Some(LogLevel::Debug)
+		}
+		else if inRange(&raw const __DEFMT_MARKER_INFO_START, &raw const __DEFMT_MARKER_INFO_END)
+		{
+			Some(LogLevel::Info)
+		}
+		else if inRange(&raw const __DEFMT_MARKER_WARN_START, &raw const __DEFMT_MARKER_WARN_END)
+		{
+			Some(LogLevel::Warn)
+		}
+		else if inRange(&raw const __DEFMT_MARKER_ERROR_START, &raw const __DEFMT_MARKER_ERROR_END)
+		{
+			Some(LogLevel::Error)
+		}
+		else
+		{
+			None
+		}
+	}
+}
+
+/// Collects a whole encoded frame so it either goes into the pipe complete or not at all
+struct FrameBuffer
+{
+	data: [u8; MAX_FRAME_SIZE],
+	length: usize,
+	overflowed: bool,
+	// The frame's string index, which is always the first thing written to it
+	index: [u8; 2],
+	indexLength: usize,
+}
+
+impl FrameBuffer
+{
+	fn push(&mut self, bytes: &[u8])
+	{
+		match self.data.get_mut(self.length..self.length + bytes.len())
+		{
+			Some(target) =>
+			{
+				target.copy_from_slice(bytes);
+				self.length += bytes.len();
+			}
+			None => self.overflowed = true,
+		}
+	}
+}
+
+struct LoggerState
+{
+	restore: RestoreState,
+	encoder: defmt::Encoder,
+	frame: FrameBuffer,
+}
+
+struct LoggerStorage(UnsafeCell<LoggerState>);
+
+// Only ever accessed between acquire() and release(), which hold a critical section throughout
+unsafe impl Sync for LoggerStorage {}
+
+static LOGGER_TAKEN: AtomicBool = AtomicBool::new(false);
+static LOGGER_STATE: LoggerStorage = LoggerStorage(UnsafeCell::new(LoggerState
+{
+	restore: RestoreState::invalid(),
+	encoder: defmt::Encoder::new(),
+	frame: FrameBuffer { data: [0; MAX_FRAME_SIZE], length: 0, overflowed: false, index: [0; 2], indexLength: 0 },
+}));
+
+/// Sends defmt frames to the host over the log interface. When the host isn't reading, frames are dropped
+/// rather than holding anything up
+#[defmt::global_logger]
+struct UsbLogger;
+
+unsafe impl defmt::Logger for UsbLogger
+{
+	fn acquire()
+	{
+		let restore = unsafe { critical_section::acquire() };
+		if LOGGER_TAKEN.swap(true, Ordering::Relaxed)
+		{
+			panic!("defmt logger taken reentrantly")
+		}
+
+		let state = unsafe { &mut *LOGGER_STATE.0.get() };
+		state.restore = restore;
+		state.frame.length = 0;
+		state.frame.overflowed = false;
+		state.frame.indexLength = 0;
+		let LoggerState { encoder, frame, .. } = state;
+		encoder.start_frame(|bytes| frame.push(bytes));
+	}
+
+	unsafe fn flush() {}
+
+	unsafe fn release()
+	{
+		let state = unsafe { &mut *LOGGER_STATE.0.get() };
+		let LoggerState { encoder, frame, .. } = state;
+		encoder.end_frame(|bytes| frame.push(bytes));
+
+		// Frames that don't belong to a log level (such as println!()'s) always go through
+		let level = frameLevel(u16::from_le_bytes(frame.index));
+		let wanted = level.is_none_or(|level| level as u8 >= LOG_LEVEL.load(Ordering::Relaxed));
+		if wanted && !frame.overflowed && LOG_PIPE.free_capacity() >= frame.length
+		{
+			// Each write only fills up to the end of the pipe's ring buffer, so a frame that wraps around takes
+			// two. Nothing can read the pipe while we hold the critical section, so the room is there for it all
+			let mut data = &frame.data[0..frame.length];
+			while let Ok(written) = LOG_PIPE.try_write(data)
+			{
+				data = &data[written..];
+				if data.is_empty()
+				{
+					break;
+				}
+			}
+		}
+
+		LOGGER_TAKEN.store(false, Ordering::Relaxed);
+		unsafe { critical_section::release(state.restore) };
+	}
+
+	unsafe fn write(bytes: &[u8])
+	{
+		let state = unsafe { &mut *LOGGER_STATE.0.get() };
+		let LoggerState { encoder, frame, .. } = state;
+		for byte in bytes
+		{
+			if frame.indexLength == frame.index.len()
+			{
+				break;
+			}
+			frame.index[frame.indexLength] = *byte;
+			frame.indexLength += 1;
+		}
+		encoder.write(bytes, |bytes| frame.push(bytes));
+	}
+}
+
+/// Pump the log stream out to the host over the log endpoint
+pub async fn run(endpoint: &mut Endpoint<'static, In>) -> !
+{
+	let mut buffer = [0u8; BULK_PACKET_SIZE as usize];
+
+	loop
+	{
+		let byteCount = LOG_PIPE.read(&mut buffer).await;
+		// Logging a failure here would only feed straight back into the pipe, so failed writes are just dropped
+		let _ = endpoint.write(&buffer[0..byteCount]).await;
+	}
+}
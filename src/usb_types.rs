@@ -51,6 +51,18 @@ pub enum UsbCdcAcmCapabilities
 	SupportsNetworkConnection = 3,
 }
 
+/// The state bits of a CDC SERIAL_STATE notification, from the PSTN subclass specification
+#[bitmask(u16)]
+pub enum UsbCdcSerialState
+{
+	/// DCD
+	RxCarrier = 0x0001,
+	/// DSR
+	TxCarrier = 0x0002,
+	/// RI
+	RingSignal = 0x0008,
+}
+
 pub struct UsbCdcUnionDescriptor
 {
 	controlInterface: u8,
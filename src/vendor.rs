@@ -0,0 +1,362 @@
+// SPDX-License-Identifier: BSD-3-Clause
+
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::channel::Sender;
+use embassy_usb::Handler;
+use embassy_usb::control::{self, Request};
+
+use crate::bert;
+#[cfg(feature = "capture")]
+use crate::capture;
+use crate::crash;
+use crate::framing::FramingConfig;
+#[cfg(feature = "target-power")]
+use crate::power::{self, PowerCommand};
+use crate::prbs::PrbsPattern;
+use crate::self_test;
+use crate::sequence::{Preset, Sequence};
+use crate::serial_number::SerialNumber;
+use crate::settings;
+use crate::target;
+#[cfg(feature = "log-usb")]
+use crate::usb_log::{self, LogLevel};
+use crate::types::{InterfaceName, PortInterface, SerialCommand};
+use crate::watchdog;
+
+#[repr(u8)]
+#[derive(Clone, Copy)]
+enum VendorRequest
+{
+	/// Set (or with no data, clear) the user-assigned serial number, taking effect from the next reset
+	SetSerialNumber = 0x01,
+	/// Set (or with no data, reset to default) the name of the port interface given in wValue, from the next reset
+	SetInterfaceName = 0x02,
+	/// Run the loopback self-test over every supported baud rate and framing
+	StartSelfTest = 0x03,
+	/// Read back the outcome of the most recent self-test
+	GetSelfTestResult = 0x04,
+	/// Start the bit error rate tester, with the PRBS polynomial order (7, 15, 23 or 31) given in wValue
+	StartBert = 0x05,
+	/// Stop the bit error rate tester and go back to conduiting data
+	StopBert = 0x06,
+	/// Read back the statistics for the current or most recent BERT run
+	GetBertStatistics = 0x07,
+	/// Switch to passively capturing both directions of a link, streamed as tagged records on the data interface
+	StartSniffer = 0x08,
+	/// Stop sniffing and go back to conduiting data
+	StopSniffer = 0x09,
+	/// Start a fresh pcapng capture of all forwarded traffic on the capture interface, if it's in use
+	StartCapture = 0x0a,
+	/// Stop capturing traffic
+	StopCapture = 0x0b,
+	/// Switch to conduiting COBS or SLIP encoded frames. wValue selects the encoding (1 = COBS, 2 = SLIP) in its
+	/// low byte, with bit 8 adding a CRC-16 to each frame and bit 9 splitting UART data on the delimiter in wIndex
+	/// rather than when the line goes idle
+	StartFraming = 0x0c,
+	/// Stop framing and go back to conduiting a plain byte stream
+	StopFraming = 0x0d,
+	/// Read back why the device last reset, as a single byte
+	GetResetReason = 0x0e,
+	/// Read back the panic or HardFault record left by the last crash, along with the reset cause
+	GetCrashRecord = 0x0f,
+	/// Set the least severe level of log message sent to the host over the log interface, with wValue
+	/// from 0 (trace) to 4 (error), or 5 to turn logging off
+	SetLogLevel = 0x10,
+	/// Switch to carrying IP between the network interface and the target as SLIP
+	StartNetwork = 0x11,
+	/// Stop carrying IP and go back to conduiting data
+	StopNetwork = 0x12,
+	/// Switch the target's power with wValue 0 for off, 1 for on, or 2 to power cycle it leaving it off for
+	/// the number of milliseconds in wIndex (or a second if that's 0)
+	SetTargetPower = 0x13,
+	/// Read back whether the target is powered (0 off, 1 on, 2 cut by the overcurrent trip), followed by its
+	/// supply voltage in mV and current in mA as u16's. The serial interface's SERIAL_STATE notifications also
+	/// report DCD, DSR and RI as all inactive from when the trip cuts the power until it's turned back on
+	GetTargetPower = 0x14,
+	/// Play the reset and boot mode sequence given in wValue on the target's control lines (1 = reset, 2 = STM32,
+	/// 3 = ESP32, 4 = nRF52, 5 = AVR, or 6 for the custom sequence)
+	PlayTargetSequence = 0x15,
+	/// Store (or with no data, clear) the custom sequence, as up to 8 steps of 4 bytes each giving the reset
+	/// and boot pin levels (0 = leave, 1 = low, 2 = high) and then how many ms to hold them for as a u16
+	SetTargetSequence = 0x16,
+	/// Read back the custom sequence
+	GetTargetSequence = 0x17,
+	/// Set which sequence is played when the host opens the port by raising DTR, numbered as for
+	/// PlayTargetSequence, or 0 for none
+	SetOpenSequence = 0x18,
+}
+
+impl TryFrom<u8> for VendorRequest
+{
+	type Error = ();
+
+	fn try_from(value: u8) -> Result<Self, Self::Error>
+	{
+		match value
+		{
+			0x01 => Ok(Self::SetSerialNumber),
+			0x02 => Ok(Self::SetInterfaceName),
+			0x03 => Ok(Self::StartSelfTest),
+			0x04 => Ok(Self::GetSelfTestResult),
+			0x05 => Ok(Self::StartBert),
+			0x06 => Ok(Self::StopBert),
+			0x07 => Ok(Self::GetBertStatistics),
+			0x08 => Ok(Self::StartSniffer),
+			0x09 => Ok(Self::StopSniffer),
+			0x0a => Ok(Self::StartCapture),
+			0x0b => Ok(Self::StopCapture),
+			0x0c => Ok(Self::StartFraming),
+			0x0d => Ok(Self::StopFraming),
+			0x0e => Ok(Self::GetResetReason),
+			0x0f => Ok(Self::GetCrashRecord),
+			0x10 => Ok(Self::SetLogLevel),
+			0x11 => Ok(Self::StartNetwork),
+			0x12 => Ok(Self::StopNetwork),
+			0x13 => Ok(Self::SetTargetPower),
+			0x14 => Ok(Self::GetTargetPower),
+			0x15 => Ok(Self::PlayTargetSequence),
+			0x16 => Ok(Self::SetTargetSequence),
+			0x17 => Ok(Self::GetTargetSequence),
+			0x18 => Ok(Self::SetOpenSequence),
+			_ => Err(()),
+		}
+	}
+}
+
+/// Handles the device-level vendor requests used to configure and query the conduit
+pub struct VendorHandler
+{
+	serialCommands: Sender<'static, CriticalSectionRawMutex, SerialCommand, 2>,
+}
+
+impl VendorHandler
+{
+	pub fn new(serialCommands: Sender<'static, CriticalSectionRawMutex, SerialCommand, 2>) -> Self
+	{
+		Self { serialCommands }
+	}
+
+	fn sendCommand(&self, command: SerialCommand) -> control::OutResponse
+	{
+		// If the serial task is still busy with previous commands, the host will have to try again
+		match self.serialCommands.try_send(command)
+		{
+			Ok(()) => control::OutResponse::Accepted,
+			Err(_) => control::OutResponse::Rejected,
+		}
+	}
+}
+
+impl Handler for VendorHandler
+{
+	fn control_in<'a>(&'a mut self, packet: Request, data: &'a mut [u8]) -> Option<control::InResponse<'a>>
+	{
+		if packet.recipient != control::Recipient::Device ||
+			packet.request_type != control::RequestType::Vendor
+		{
+			return None
+		}
+
+		let Ok(request) = VendorRequest::try_from(packet.request)
+		else
+		{
+			return Some(control::InResponse::Rejected)
+		};
+
+		let length = match request
+		{
+			VendorRequest::GetSelfTestResult => self_test::result().toData(data),
+			VendorRequest::GetBertStatistics => bert::statistics().toData(data),
+			VendorRequest::GetResetReason => data.first_mut().map(|byte|
+			{
+				*byte = watchdog::resetReason();
+				1
+			}),
+			VendorRequest::GetCrashRecord => crash::lastCrash().toData(data),
+			#[cfg(feature = "target-power")]
+			VendorRequest::GetTargetPower => power::status().toData(data),
+			VendorRequest::GetTargetSequence =>
+				settings::current().customSequence.map_or(Some(0), |sequence| sequence.toData(data)),
+			_ => None,
+		};
+		Some(length.map_or(control::InResponse::Rejected, |length| control::InResponse::Accepted(&data[0..length])))
+	}
+
+	fn control_out(&mut self, packet: Request, data: &[u8]) -> Option<control::OutResponse>
+	{
+		if packet.recipient != control::Recipient::Device ||
+			packet.request_type != control::RequestType::Vendor
+		{
+			return None
+		}
+
+		// Anything addressed to us that we don't understand gets stalled
+		let Ok(request) = VendorRequest::try_from(packet.request)
+		else
+		{
+			return Some(control::OutResponse::Rejected)
+		};
+
+		match request
+		{
+			VendorRequest::SetSerialNumber =>
+			{
+				let serialNumber = if data.is_empty()
+				{
+					None
+				}
+				else
+				{
+					match SerialNumber::from_bytes(data)
+					{
+						Some(serialNumber) => Some(serialNumber),
+						None => return Some(control::OutResponse::Rejected),
+					}
+				};
+
+				settings::update(|settings| settings.serialNumber = serialNumber);
+				Some(control::OutResponse::Accepted)
+			}
+			VendorRequest::SetInterfaceName =>
+			{
+				let Ok(interface) = PortInterface::try_from(packet.value)
+				else
+				{
+					return Some(control::OutResponse::Rejected)
+				};
+				let name = if data.is_empty()
+				{
+					None
+				}
+				else
+				{
+					match InterfaceName::fromBytes(data)
+					{
+						Some(name) => Some(name),
+						None => return Some(control::OutResponse::Rejected),
+					}
+				};
+
+				settings::update(|settings| settings.setInterfaceName(interface, name));
+				Some(control::OutResponse::Accepted)
+			}
+			VendorRequest::StartSelfTest =>
+			{
+				let response = self.sendCommand(SerialCommand::SelfTest);
+				if matches!(response, control::OutResponse::Accepted)
+				{
+					self_test::pending();
+				}
+				Some(response)
+			}
+			VendorRequest::StartBert =>
+			{
+				match PrbsPattern::try_from(packet.value)
+				{
+					Ok(pattern) => Some(self.sendCommand(SerialCommand::StartBert(pattern))),
+					Err(()) => Some(control::OutResponse::Rejected),
+				}
+			}
+			VendorRequest::StopBert =>
+				Some(self.sendCommand(SerialCommand::StopBert)),
+			VendorRequest::StartSniffer =>
+				Some(self.sendCommand(SerialCommand::StartSniffer)),
+			VendorRequest::StopSniffer =>
+				Some(self.sendCommand(SerialCommand::StopSniffer)),
+			#[cfg(feature = "capture")]
+			VendorRequest::StartCapture =>
+			{
+				capture::start();
+				Some(control::OutResponse::Accepted)
+			}
+			#[cfg(feature = "capture")]
+			VendorRequest::StopCapture =>
+			{
+				capture::stop();
+				Some(control::OutResponse::Accepted)
+			}
+			VendorRequest::StartFraming =>
+			{
+				match FramingConfig::fromRequest(packet.value, packet.index)
+				{
+					Some(config) => Some(self.sendCommand(SerialCommand::StartFraming(config))),
+					None => Some(control::OutResponse::Rejected),
+				}
+			}
+			VendorRequest::StopFraming =>
+				Some(self.sendCommand(SerialCommand::StopFraming)),
+			#[cfg(feature = "log-usb")]
+			VendorRequest::SetLogLevel =>
+			{
+				match LogLevel::try_from(packet.value)
+				{
+					Ok(level) =>
+					{
+						usb_log::setLogLevel(level);
+						Some(control::OutResponse::Accepted)
+					}
+					Err(()) => Some(control::OutResponse::Rejected),
+				}
+			}
+			#[cfg(feature = "network")]
+			VendorRequest::StartNetwork =>
+				Some(self.sendCommand(SerialCommand::StartNetwork)),
+			#[cfg(feature = "network")]
+			VendorRequest::StopNetwork =>
+				Some(self.sendCommand(SerialCommand::StopNetwork)),
+			#[cfg(feature = "target-power")]
+			VendorRequest::SetTargetPower =>
+			{
+				// If the last power cycle is still going, the host will have to try again
+				match PowerCommand::fromRequest(packet.value, packet.index)
+				{
+					Some(command) if power::request(command) => Some(control::OutResponse::Accepted),
+					_ => Some(control::OutResponse::Rejected),
+				}
+			}
+			VendorRequest::PlayTargetSequence =>
+			{
+				// If the last sequence is still playing, the host will have to try again
+				match Preset::try_from(packet.value)
+				{
+					Ok(preset) if target::playPreset(preset) => Some(control::OutResponse::Accepted),
+					_ => Some(control::OutResponse::Rejected),
+				}
+			}
+			VendorRequest::SetTargetSequence =>
+			{
+				let sequence = if data.is_empty()
+				{
+					None
+				}
+				else
+				{
+					match Sequence::fromData(data)
+					{
+						Some(sequence) => Some(sequence),
+						None => return Some(control::OutResponse::Rejected),
+					}
+				};
+
+				settings::update(|settings| settings.customSequence = sequence);
+				Some(control::OutResponse::Accepted)
+			}
+			VendorRequest::SetOpenSequence =>
+			{
+				let preset = match packet.value
+				{
+					0 => None,
+					value => match Preset::try_from(value)
+					{
+						Ok(preset) => Some(preset),
+						Err(()) => return Some(control::OutResponse::Rejected),
+					}
+				};
+
+				settings::update(|settings| settings.openSequence = preset);
+				Some(control::OutResponse::Accepted)
+			}
+			_ => Some(control::OutResponse::Rejected),
+		}
+	}
+}
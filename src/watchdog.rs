@@ -0,0 +1,190 @@
+// SPDX-License-Identifier: BSD-3-Clause
+
+use core::sync::atomic::{AtomicU8, Ordering};
+use embassy_stm32::pac;
+use embassy_stm32::wdg::IndependentWatchdog;
+use embassy_time::{Duration, Ticker};
+
+use crate::board::WatchdogResources;
+
+// How long the watchdog gives us before resetting the device if it isn't kicked
+const WATCHDOG_TIMEOUT_US: u32 = 3_000_000;
+// How often every watched loop has to check in for the watchdog to be kicked
+const HEARTBEAT_WINDOW: Duration = Duration::from_secs(1);
+
+/// The loops that have to keep making progress for the device to be considered healthy
+#[repr(u8)]
+#[derive(Clone, Copy)]
+pub enum Watched
+{
+	/// The USB device's own run loop, which services the control endpoint
+	UsbDevice = 1 << 0,
+	/// SerialHandlerInner's line coding and control line state handling
+	UsbControl = 1 << 1,
+	/// SerialHandlerInner's forwarding of data from the serial task to the host
+	UsbTransmit = 1 << 2,
+	/// SerialHandlerInner's forwarding of data from the host to the serial task
+	UsbReceive = 1 << 3,
+	/// The serial task's main loop, and the loops of whichever mode it's running
+	Serial = 1 << 4,
+	/// SerialHandlerInner's sending of serial state notifications to the host
+	UsbNotify = 1 << 5,
+}
+
+const ALL_WATCHED: u8 = Watched::UsbDevice as u8 | Watched::UsbControl as u8 | Watched::UsbTransmit as u8 |
+	Watched::UsbReceive as u8 | Watched::Serial as u8 | Watched::UsbNotify as u8;
+const WATCHED_COUNT: usize = ALL_WATCHED.count_ones() as usize;
+
+// Loops that have checked in during the current window
+static HEARTBEATS: AtomicU8 = AtomicU8::new(0);
+// How many waits on the host or target, which can legitimately take forever, each loop has in progress. A loop
+// can be several futures running concurrently, so it's only held to account again once they're all done waiting
+static EXCUSED: [AtomicU8; WATCHED_COUNT] = [const { AtomicU8::new(0) }; WATCHED_COUNT];
+
+static RESET_REASON: AtomicU8 = AtomicU8::new(ResetReason::Unknown as u8);
+
+/// Why the device last reset, as reported to the host
+#[repr(u8)]
+#[derive(Clone, Copy)]
+pub enum ResetReason
+{
+	Unknown = 0,
+	BrownOut = 1,
+	Pin = 2,
+	Software = 3,
+	IndependentWatchdog = 4,
+	WindowWatchdog = 5,
+	LowPower = 6,
+	OptionByteLoad = 7,
+}
+
+/// Work out why we reset from the flags in RCC_CSR, then clear them ready for next time
+pub fn readResetReason()
+{
+	let flags = pac::RCC.csr().read();
+	// The pin reset flag gets set along with most of the others as the reset drives NRST, so check it last
+	let reason = if flags.iwdgrstf()
+	{
+		ResetReason::IndependentWatchdog
+	}
+	else if flags.wwdgrstf()
+	{
+		ResetReason::WindowWatchdog
+	}
+	else if flags.sftrstf()
+	{
+		ResetReason::Software
+	}
+	else if flags.lpwrrstf()
+	{
+		ResetReason::LowPower
+	}
+	else if flags.oblrstf()
+	{
+		ResetReason::OptionByteLoad
+	}
+	else if flags.borrstf()
+	{
+		ResetReason::BrownOut
+	}
+	else if flags.pinrstf()
+	{
+		ResetReason::Pin
+	}
+	else
+	{
+		ResetReason::Unknown
+	};
+
+	pac::RCC.csr().modify(|csr| csr.set_rmvf(true));
+	RESET_REASON.store(reason as u8, Ordering::Relaxed);
+}
+
+pub fn resetReason() -> u8
+{
+	RESET_REASON.load(Ordering::Relaxed)
+}
+
+/// Record that a watched loop has made progress
+pub fn heartbeat(watched: Watched)
+{
+	HEARTBEATS.fetch_or(watched as u8, Ordering::Relaxed);
+}
+
+/// Await something the host or target controls the timing of, such as new data arriving or the host reading
+/// data out, without the loop counting as stalled however long that takes
+pub async fn excused<F: Future>(watched: Watched, future: F) -> F::Output
+{
+	let _excuse = Excuse::new(watched);
+	future.await
+}
+
+struct Excuse(Watched);
+
+impl Excuse
+{
+	fn new(watched: Watched) -> Self
+	{
+		EXCUSED[watched.index()].fetch_add(1, Ordering::Relaxed);
+		Self(watched)
+	}
+}
+
+impl Drop for Excuse
+{
+	fn drop(&mut self)
+	{
+		// However we stop waiting, the loop is alive again and can be held to account from here
+		EXCUSED[self.0.index()].fetch_sub(1, Ordering::Relaxed);
+		heartbeat(self.0);
+	}
+}
+
+impl Watched
+{
+	const fn index(self) -> usize
+	{
+		(self as u8).trailing_zeros() as usize
+	}
+}
+
+// The loops with any waits in progress, as a mask of Watched bits
+fn excusedLoops() -> u8
+{
+	EXCUSED.iter().enumerate()
+		.filter(|(_, count)| count.load(Ordering::Relaxed) != 0)
+		.fold(0, |mask, (index, _)| mask | 1u8 << index)
+}
+
+/// Beat on behalf of the USB device's run loop. That's a library loop we can't put heartbeats in, but as it
+/// runs in the same task this only keeps beating while the task keeps getting polled
+pub async fn usbDeviceHeartbeat() -> !
+{
+	let mut ticker = Ticker::every(HEARTBEAT_WINDOW / 2);
+	loop
+	{
+		ticker.next().await;
+		heartbeat(Watched::UsbDevice);
+	}
+}
+
+/// Enable the independent watchdog and only kick it when every watched loop has either checked in during
+/// the last window or is waiting on something outside the device. Once enabled the watchdog can't be stopped,
+/// so if any loop wedges the device resets
+#[embassy_executor::task]
+pub async fn watchdogTask(watchdog: WatchdogResources)
+{
+	let mut watchdog = IndependentWatchdog::new(watchdog.peripheral, WATCHDOG_TIMEOUT_US);
+	watchdog.unleash();
+
+	let mut ticker = Ticker::every(HEARTBEAT_WINDOW);
+	loop
+	{
+		ticker.next().await;
+		let alive = HEARTBEATS.swap(0, Ordering::Relaxed) | excusedLoops();
+		if alive & ALL_WATCHED == ALL_WATCHED
+		{
+			watchdog.pet();
+		}
+	}
+}